@@ -2,12 +2,23 @@
 # API script
 
 # commands to start p1, p2, p3
-# cargo run --release -- -vvv --p2p 127.0.0.1:6000 --api 127.0.0.1:7000 | tee p1.out
-# cargo run --release -- -vvv --p2p 127.0.0.1:6001 --api 127.0.0.1:7001 -c 127.0.0.1:6000 | tee p2.out
-# cargo run --release -- -vvv --p2p 127.0.0.1:6002 --api 127.0.0.1:7002 -c 127.0.0.1:6001 | tee p3.out
+# cargo run --release -- -vvv --p2p 127.0.0.1:6000 --api 127.0.0.1:7000 --event-log p1.events
+# cargo run --release -- -vvv --p2p 127.0.0.1:6001 --api 127.0.0.1:7001 -c 127.0.0.1:6000 --event-log p2.events
+# cargo run --release -- -vvv --p2p 127.0.0.1:6002 --api 127.0.0.1:7002 -c 127.0.0.1:6001 --event-log p3.events
+# analyze the experiment with: cargo run --release --bin analyze -- p1.events p2.events p3.events
 
-# command to start tx_generator and miner
-curl http://127.0.0.1:7000/miner/start?lambda=1000000 & \
-curl http://127.0.0.1:7001/miner/start?lambda=1000001 & \
-curl http://127.0.0.1:7002/miner/start?lambda=1000002
+# command to start tx_generator, each node spends from its own ICO address set
+curl "http://127.0.0.1:7000/txgen/start?interval=1000000&address_set=0" & \
+curl "http://127.0.0.1:7001/txgen/start?interval=1000001&address_set=1" & \
+curl "http://127.0.0.1:7002/txgen/start?interval=1000002&address_set=2"
+sleep 1
 
+# command to start miner
+curl "http://127.0.0.1:7000/miner/start?interval=1000000&miner_id=0" & \
+curl "http://127.0.0.1:7001/miner/start?interval=1000001&miner_id=1" & \
+curl "http://127.0.0.1:7002/miner/start?interval=1000002&miner_id=2"
+
+# status of a node
+# curl http://127.0.0.1:7000/miner/status
+# curl http://127.0.0.1:7000/txgen/status
+# curl http://127.0.0.1:7000/network/bans
@@ -1,4 +1,4 @@
-import re
+import json
 import sys
 from collections import defaultdict
 import numpy as np
@@ -17,29 +17,19 @@ class Node:
         print('Delay %d Num entries %d Average %0.2f' % (self.delay, self.num_entries, (self.delay/self.num_entries)))
 
 delay_map = defaultdict(Node)
-logfiles = ['p1.out', 'p2.out', 'p3.out']
+# event logs written by the nodes with --event-log, one JSON object per line
+logfiles = ['p1.events', 'p2.events', 'p3.events']
 for logfile in logfiles:
-    pattern = re.compile("^Received trans hash")
     recv_time_map = {}
-    with open(logfile, 'r') as f:
-        for line in f:
-            if pattern.match(line):
-                separated = line.split()
-                tx_hash = separated[3]
-                recv_time = int(separated[-1])
-                # extract the hash and time 
-                recv_time_map[tx_hash] = recv_time 
-
-    pattern = re.compile("^Confirmed trans hash")
     confirm_time_map = {}
     with open(logfile, 'r') as f:
         for line in f:
-            if pattern.match(line):
-                separated = line.split()
-                tx_hash = separated[3]
-                confirm_time = int(separated[-1])
-                # extract the hash and time 
-                confirm_time_map[tx_hash] = confirm_time
+            event = json.loads(line)
+            # the first insertion into the mempool and the first confirmation count
+            if event['event'] == 'TxReceived':
+                recv_time_map.setdefault(event['hash'], event['timestamp'])
+            elif event['event'] == 'TxConfirmed':
+                confirm_time_map.setdefault(event['hash'], event['timestamp'])
 
     for tx_hash in recv_time_map:
         if tx_hash in confirm_time_map:
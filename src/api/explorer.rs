@@ -0,0 +1,463 @@
+use serde::Serialize;
+use crate::blockchain::Blockchain;
+use crate::block::{Block, Content};
+use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::address::H160;
+use crate::mempool::TransactionMempool;
+use crate::transaction::{SignedTransaction, UtxoInput, UtxoOutput};
+use crate::utxo::UtxoState;
+
+// JSON views of the Prism DAG served by the explorer endpoints.
+// Hashes and addresses are hex encoded.
+
+// Voter blocks listed when the request does not set a limit
+pub const DEFAULT_VOTER_BLOCKS: usize = 100;
+// Largest limit accepted, chains are listed under the blockchain lock
+pub const MAX_VOTER_BLOCKS: usize = 1000;
+
+#[derive(Serialize)]
+pub struct ProposerSummary {
+    hash: String,
+    votes: u32,
+}
+
+#[derive(Serialize)]
+pub struct ProposerLevel {
+    level: u32,
+    // proposer with the most votes, not necessarily confirmed yet
+    top_proposer: Option<String>,
+    proposers: Vec<ProposerSummary>,
+}
+
+#[derive(Serialize)]
+pub struct VoterSummary {
+    hash: String,
+    level: u32,
+    votes: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct VoterChain {
+    chain: u32,
+    tip: String,
+    depth: u32,
+    // at most `limit` blocks, from the `from` block (the tip by default) down to genesis
+    blocks: Vec<VoterSummary>,
+    // parent of the last block listed, `from` of the next page if genesis was not reached
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ChainTip {
+    chain: u32,
+    tip: String,
+    depth: u32,
+}
+
+#[derive(Serialize)]
+pub struct Tips {
+    proposer_tip: String,
+    proposer_depth: u32,
+    num_voter_chains: u32,
+    voter_chains: Vec<ChainTip>,
+    unreferenced_proposers: usize,
+    orphans: usize,
+}
+
+#[derive(Serialize)]
+pub struct Leader {
+    level: Option<u32>,
+    hash: String,
+}
+
+#[derive(Serialize)]
+pub struct HeaderView {
+    parent: String,
+    nonce: u32,
+    difficulty: String,
+    timestamp: u128,
+    merkle_root: String,
+    miner_id: i32,
+}
+
+#[derive(Serialize)]
+pub struct InputView {
+    tx_hash: String,
+    idx: u8,
+}
+
+#[derive(Serialize)]
+pub struct OutputView {
+    address: String,
+    value: u32,
+    conditional: bool,
+}
+
+#[derive(Serialize)]
+pub struct TxView {
+    hash: String,
+    inputs: Vec<InputView>,
+    outputs: Vec<OutputView>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ContentView {
+    Proposer {
+        parent: String,
+        coinbase_level: u32,
+        coinbase: Vec<OutputView>,
+        transactions: Vec<TxView>,
+        proposer_refs: Vec<String>,
+    },
+    Voter {
+        parent: String,
+        chain: u32,
+        votes: Vec<String>,
+    },
+}
+
+#[derive(Serialize)]
+pub struct VoteView {
+    chain: u32,
+    voter: String,
+}
+
+#[derive(Serialize)]
+pub struct BlockView {
+    hash: String,
+    // None for blocks still waiting in the orphan buffer
+    level: Option<u32>,
+    header: HeaderView,
+    content: ContentView,
+    sortition_proof: Vec<String>,
+    // votes cast on this proposer block
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vote_count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    votes: Option<Vec<VoteView>>,
+}
+
+#[derive(Serialize)]
+pub struct UnspentOutput {
+    tx_hash: String,
+    idx: u8,
+    address: String,
+    value: u32,
+    conditional: bool,
+    // immature coinbase outputs can't be spent yet
+    mature: bool,
+    // spent by a transaction waiting in the mempool
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pending_spend: bool,
+}
+
+#[derive(Serialize)]
+pub struct AddressUtxos {
+    address: String,
+    balance: u64,
+    // value of the mature outputs without a spending condition
+    spendable_balance: u64,
+    outputs: Vec<UnspentOutput>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TxState {
+    Pending,
+    Confirmed,
+    Unknown,
+}
+
+#[derive(Serialize)]
+pub struct TxStatus {
+    tx_hash: String,
+    status: TxState,
+    // level of the leader which confirmed the tx
+    #[serde(skip_serializing_if = "Option::is_none")]
+    level: Option<u32>,
+    // fee of a pending tx
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fee: Option<u64>,
+}
+
+fn hashes(list: &[H256]) -> Vec<String> {
+    list.iter().map(|hash| hash.to_string()).collect()
+}
+
+pub fn input_view(input: &UtxoInput) -> InputView {
+    InputView { tx_hash: input.tx_hash.to_string(), idx: input.idx }
+}
+
+pub fn output_view(output: &UtxoOutput) -> OutputView {
+    OutputView {
+        address: hex::encode(output.receipient_addr),
+        value: output.value,
+        conditional: output.condition.is_some(),
+    }
+}
+
+fn tx_view(tx: &SignedTransaction) -> TxView {
+    TxView {
+        hash: tx.hash().to_string(),
+        inputs: tx.tx.tx_input.iter().map(input_view).collect(),
+        outputs: tx.tx.tx_output.iter().map(output_view).collect(),
+    }
+}
+
+fn content_view(content: &Content) -> ContentView {
+    match content {
+        Content::Proposer(c) => ContentView::Proposer {
+            parent: c.parent_hash.to_string(),
+            coinbase_level: c.coinbase.level,
+            coinbase: c.coinbase.tx_output.iter().map(output_view).collect(),
+            transactions: c.transactions.iter().map(tx_view).collect(),
+            proposer_refs: hashes(&c.proposer_refs),
+        },
+        Content::Voter(c) => ContentView::Voter {
+            parent: c.parent_hash.to_string(),
+            chain: c.chain_num,
+            votes: hashes(&c.votes),
+        },
+    }
+}
+
+fn block_level(blockchain: &Blockchain, hash: &H256, block: &Block) -> Option<u32> {
+    match &block.content {
+        Content::Proposer(_) => blockchain.proposer_chain.get(hash).map(|meta| meta.level),
+        Content::Voter(c) => blockchain.voter_chains
+            .get((c.chain_num as usize).wrapping_sub(1))
+            .and_then(|chain| chain.get(hash))
+            .map(|meta| meta.level),
+    }
+}
+
+pub fn proposers(blockchain: &Blockchain, level: u32) -> Option<ProposerLevel> {
+    let all = blockchain.level2allproposers.get(&level)?;
+    let proposers: Vec<ProposerSummary> = all.iter()
+        .map(|hash| ProposerSummary {
+            hash: hash.to_string(),
+            votes: blockchain.proposer2votecount.get(hash).copied().unwrap_or(0),
+        })
+        .collect();
+    let top_proposer = proposers.iter()
+        .filter(|p| p.votes > 0)
+        .max_by_key(|p| p.votes)
+        .map(|p| p.hash.clone());
+    Some(ProposerLevel { level, top_proposer, proposers })
+}
+
+// Voter chains are numbered from 1. Lists up to `limit` blocks starting at `from`, or at the
+// tip if not set, None if the chain doesn't exist or `from` is not one of its blocks.
+pub fn voter_chain(blockchain: &Blockchain, chain: u32, from: Option<H256>, limit: usize) -> Option<VoterChain> {
+    let idx = (chain as usize).checked_sub(1)?;
+    let blocks = blockchain.voter_chains.get(idx)?;
+    let tip = blockchain.voter_tips[idx];
+    let mut current = from.unwrap_or(tip);
+    if !blocks.contains_key(&current) {
+        return None;
+    }
+    let mut summaries = Vec::new();
+    let mut next = None;
+    while let Some(meta) = blocks.get(&current) {
+        if summaries.len() >= limit {
+            next = Some(current.to_string());
+            break;
+        }
+        let content = match &meta.block.content {
+            Content::Voter(c) => c,
+            Content::Proposer(_) => break,
+        };
+        summaries.push(VoterSummary {
+            hash: current.to_string(),
+            level: meta.level,
+            votes: hashes(&content.votes),
+        });
+        // the parent of genesis is not part of the chain
+        current = content.parent_hash;
+    }
+    Some(VoterChain {
+        chain,
+        tip: tip.to_string(),
+        depth: blockchain.voter_depths[idx],
+        blocks: summaries,
+        next,
+    })
+}
+
+pub fn block(blockchain: &Blockchain, hash: &H256) -> Option<BlockView> {
+    let block = blockchain.get_block(*hash)?;
+    let is_proposer = matches!(block.content, Content::Proposer(_));
+    let votes = if is_proposer {
+        let info = blockchain.proposer2voterinfo.get(hash).map(|v| v.as_slice()).unwrap_or(&[]);
+        Some(info.iter().map(|(chain, voter)| VoteView { chain: *chain, voter: voter.to_string() }).collect())
+    } else {
+        None
+    };
+    Some(BlockView {
+        hash: hash.to_string(),
+        level: block_level(blockchain, hash, block),
+        header: HeaderView {
+            parent: block.header.parent.to_string(),
+            nonce: block.header.nonce,
+            difficulty: block.header.difficulty.to_string(),
+            timestamp: block.header.timestamp,
+            merkle_root: block.header.merkle_root.to_string(),
+            miner_id: block.header.miner_id,
+        },
+        content: content_view(&block.content),
+        sortition_proof: hashes(&block.sortition_proof),
+        vote_count: if is_proposer {
+            Some(blockchain.proposer2votecount.get(hash).copied().unwrap_or(0))
+        } else {
+            None
+        },
+        votes,
+    })
+}
+
+pub fn leaders(blockchain: &Blockchain, leader_sequence: &[H256]) -> Vec<Leader> {
+    leader_sequence.iter()
+        .map(|hash| Leader {
+            level: blockchain.proposer_chain.get(hash).map(|meta| meta.level),
+            hash: hash.to_string(),
+        })
+        .collect()
+}
+
+pub fn tips(blockchain: &Blockchain) -> Tips {
+    Tips {
+        proposer_tip: blockchain.proposer_tip.to_string(),
+        proposer_depth: blockchain.proposer_depth,
+        num_voter_chains: blockchain.num_voter_chains,
+        voter_chains: blockchain.voter_tips.iter().zip(&blockchain.voter_depths).enumerate()
+            .map(|(idx, (tip, depth))| ChainTip { chain: idx as u32 + 1, tip: tip.to_string(), depth: *depth })
+            .collect(),
+        unreferenced_proposers: blockchain.unref_proposers.len(),
+        orphans: blockchain.orphan_buffer.values().map(|blocks| blocks.len()).sum(),
+    }
+}
+
+fn unspent_output(state: &UtxoState, mempool: &TransactionMempool, input: &UtxoInput, output: &UtxoOutput) -> UnspentOutput {
+    UnspentOutput {
+        tx_hash: input.tx_hash.to_string(),
+        idx: input.idx,
+        address: hex::encode(output.receipient_addr),
+        value: output.value,
+        conditional: output.condition.is_some(),
+        mature: state.is_mature(input),
+        pending_spend: mempool.contains_utxoinput(&input.hash()),
+    }
+}
+
+// Unspent outputs paying `address`, largest first. Outputs with a spending condition
+// are listed under the address they nominally pay to.
+pub fn address_utxos(state: &UtxoState, mempool: &TransactionMempool, address: &H160) -> AddressUtxos {
+    let mut coins: Vec<(&UtxoInput, &UtxoOutput)> = state.state_map.iter()
+        .filter(|(_, output)| output.receipient_addr == *address)
+        .collect();
+    coins.sort_by(|(a_input, a), (b_input, b)| {
+        b.value.cmp(&a.value)
+            .then(a_input.tx_hash.cmp(&b_input.tx_hash))
+            .then(a_input.idx.cmp(&b_input.idx))
+    });
+    let outputs: Vec<UnspentOutput> = coins.into_iter()
+        .map(|(input, output)| unspent_output(state, mempool, input, output))
+        .collect();
+    AddressUtxos {
+        address: hex::encode(address),
+        balance: outputs.iter().map(|o| o.value as u64).sum(),
+        spendable_balance: outputs.iter().filter(|o| o.mature && !o.conditional).map(|o| o.value as u64).sum(),
+        outputs,
+    }
+}
+
+pub fn outpoint(state: &UtxoState, mempool: &TransactionMempool, input: &UtxoInput) -> Option<UnspentOutput> {
+    let output = state.state_map.get(input)?;
+    Some(unspent_output(state, mempool, input, output))
+}
+
+// Confirmed transactions are looked up first, a confirmed tx may still linger in the mempool
+pub fn tx_status(state: &UtxoState, mempool: &TransactionMempool, hash: &H256) -> TxStatus {
+    let (status, level, fee) = if let Some(level) = state.confirmed_txs.get(hash) {
+        (TxState::Confirmed, Some(*level), None)
+    } else if let Some(txstore) = mempool.get(hash) {
+        (TxState::Pending, None, Some(txstore.fee))
+    } else {
+        (TxState::Unknown, None, None)
+    };
+    TxStatus { tx_hash: hash.to_string(), status, level, fee }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mempool::TransactionMempool;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn views_of_genesis() {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let blockchain = Blockchain::new(2, &mempool);
+
+        let view = voter_chain(&blockchain, 2, None, DEFAULT_VOTER_BLOCKS).unwrap();
+        assert_eq!(view.depth, blockchain.voter_depths[1]);
+        assert_eq!(view.blocks.len(), 1);
+        assert_eq!(view.blocks[0].hash, blockchain.voter_tips[1].to_string());
+        assert!(view.next.is_none());
+        let page = voter_chain(&blockchain, 2, None, 0).unwrap();
+        assert!(page.blocks.is_empty());
+        assert_eq!(page.next, Some(blockchain.voter_tips[1].to_string()));
+        assert!(voter_chain(&blockchain, 0, None, DEFAULT_VOTER_BLOCKS).is_none());
+        assert!(voter_chain(&blockchain, 3, None, DEFAULT_VOTER_BLOCKS).is_none());
+        assert!(voter_chain(&blockchain, 2, Some([7; 32].into()), DEFAULT_VOTER_BLOCKS).is_none());
+
+        let tips = tips(&blockchain);
+        assert_eq!(tips.voter_chains.len(), 2);
+        assert_eq!(tips.voter_chains[0].chain, 1);
+        assert_eq!(tips.proposer_tip, blockchain.proposer_tip.to_string());
+
+        let genesis = blockchain.proposer_tip;
+        let view = block(&blockchain, &genesis).unwrap();
+        assert_eq!(view.level, Some(1));
+        assert!(view.votes.is_some());
+        let level = proposers(&blockchain, 1).unwrap();
+        assert_eq!(level.proposers[0].hash, genesis.to_string());
+        assert!(proposers(&blockchain, 2).is_none());
+        assert_eq!(leaders(&blockchain, &[genesis])[0].level, Some(1));
+    }
+
+    #[test]
+    fn balances_and_tx_status() {
+        let mut wallet = crate::wallet::Wallet::new();
+        let owner = wallet.generate_key().unwrap();
+        let mut state = UtxoState::default();
+        for (byte, value) in [(1u8, 10), (2, 30)].iter() {
+            state.state_map.insert(UtxoInput { tx_hash: H256::from([*byte; 32]), idx: 0 }, UtxoOutput::new(owner, *value));
+        }
+        let mut mempool = TransactionMempool::new();
+        let coins = wallet.spendable_coins(&state);
+        let tx = wallet.create_transaction(&coins[1..], vec![UtxoOutput::new(owner, 9)], 1).unwrap();
+        let tx_hash = tx.hash();
+        assert!(matches!(tx_status(&state, &mempool, &tx_hash).status, TxState::Unknown));
+        mempool.insert(tx.clone(), 1);
+
+        let utxos = address_utxos(&state, &mempool, &owner);
+        assert_eq!(utxos.balance, 40);
+        assert_eq!(utxos.spendable_balance, 40);
+        assert_eq!(utxos.outputs[0].value, 30);
+        assert!(utxos.outputs[1].pending_spend && !utxos.outputs[0].pending_spend);
+        let status = tx_status(&state, &mempool, &tx_hash);
+        assert!(matches!(status.status, TxState::Pending));
+        assert_eq!(status.fee, Some(1));
+
+        state.confirmed_level = 7;
+        state.update_state(&tx);
+        let status = tx_status(&state, &mempool, &tx_hash);
+        assert!(matches!(status.status, TxState::Confirmed));
+        assert_eq!(status.level, Some(7));
+        assert_eq!(outpoint(&state, &mempool, &UtxoInput { tx_hash, idx: 0 }).unwrap().value, 9);
+        assert!(outpoint(&state, &mempool, &coins[1].0).is_none());
+    }
+}
@@ -1,23 +1,44 @@
+pub mod explorer;
+
 use serde::Serialize;
 use crate::miner::Handle as MinerHandle;
 use crate::tx_generator::Handle as TxGenHandle;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
+use crate::network::misbehavior::BanList;
+use crate::network::addrbook;
+use crate::mempool::TransactionMempool;
+use crate::blockchain::Blockchain;
+use crate::utxo::UtxoState;
+use crate::transaction::{SignedTransaction, UtxoInput};
+use crate::crypto::address::H160;
+use crate::wallet::ICO_NODES;
+use crate::metrics::metrics;
+use crate::crypto::hash::{H256, Hashable};
+use crate::validation::transaction::{check_tx, TxError};
 
 use log::debug;
 use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use tiny_http::Header;
+use tiny_http::Method;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
 use url::Url;
-use std::time;
 use log::info;
 pub struct Server {
     handle: HTTPServer,
     miner: MinerHandle,
     network: NetworkServerHandle,
     txgen: TxGenHandle,
+    mempool: Arc<Mutex<TransactionMempool>>,
+    utxo_state: Arc<Mutex<UtxoState>>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    leaders: Arc<Mutex<Vec<H256>>>,
+    ban_list: Arc<Mutex<BanList>>,
 }
 
 #[derive(Serialize)]
@@ -26,17 +47,137 @@ struct ApiResponse {
     message: String,
 }
 
-macro_rules! respond_result {
-    ( $req:expr, $success:expr, $message:expr ) => {{
-        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
-        let payload = ApiResponse {
-            success: $success,
-            message: $message.to_string(),
-        };
-        let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
-            .with_header(content_type);
-        $req.respond(resp).unwrap();
-    }};
+// Submitted transactions larger than this are rejected without decoding them
+const MAX_TX_BODY: u64 = 1 << 20;
+
+#[derive(Serialize)]
+struct TxSubmitResponse {
+    success: bool,
+    message: String,
+    // machine readable rejection reason
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tx_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fee: Option<u64>,
+}
+
+// Reason a transaction submitted through the API is not accepted
+#[derive(Debug)]
+pub enum SubmitError {
+    WrongMethod,
+    // the body is larger than MAX_TX_BODY
+    TooLarge,
+    Decode(String),
+    AlreadyKnown(H256),
+    // an input is already spent by a transaction waiting in the mempool
+    MempoolConflict(UtxoInput),
+    Invalid(TxError),
+}
+
+impl SubmitError {
+    fn reason(&self) -> &'static str {
+        match self {
+            SubmitError::WrongMethod => "wrong_method",
+            SubmitError::TooLarge => "too_large",
+            SubmitError::Decode(_) => "decode_error",
+            SubmitError::AlreadyKnown(_) => "already_known",
+            SubmitError::MempoolConflict(_) => "mempool_conflict",
+            SubmitError::Invalid(e) => e.code(),
+        }
+    }
+
+    fn status_code(&self) -> u16 {
+        match self {
+            SubmitError::WrongMethod => 405,
+            SubmitError::TooLarge => 413,
+            SubmitError::Decode(_) => 400,
+            SubmitError::AlreadyKnown(_) | SubmitError::MempoolConflict(_) => 409,
+            SubmitError::Invalid(_) => 422,
+        }
+    }
+}
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubmitError::WrongMethod => write!(f, "transactions must be submitted with POST"),
+            SubmitError::TooLarge => write!(f, "transactions are limited to {} bytes", MAX_TX_BODY),
+            SubmitError::Decode(e) => write!(f, "error decoding transaction: {}", e),
+            SubmitError::AlreadyKnown(hash) => write!(f, "tx {} is already in the mempool", hash),
+            SubmitError::MempoolConflict(input) => {
+                write!(f, "input {:?} is already spent by a tx in the mempool", input)
+            }
+            SubmitError::Invalid(e) => write!(f, "invalid tx: {}", e),
+        }
+    }
+}
+
+// Decode a submitted transaction, either JSON or the hex encoded bincode wire format
+fn decode_transaction(body: &str) -> Result<SignedTransaction, SubmitError> {
+    let body = body.trim();
+    if body.starts_with('{') {
+        return serde_json::from_str(body).map_err(|e| SubmitError::Decode(e.to_string()));
+    }
+    let raw = hex::decode(body).map_err(|e| SubmitError::Decode(e.to_string()))?;
+    bincode::deserialize(&raw).map_err(|e| SubmitError::Decode(e.to_string()))
+}
+
+// Read and decode a submitted transaction, bodies over MAX_TX_BODY are not decoded
+fn read_transaction<R: Read>(body: R) -> Result<SignedTransaction, SubmitError> {
+    // one byte past the limit tells an oversized body from one at the limit
+    let mut raw = Vec::new();
+    body.take(MAX_TX_BODY + 1).read_to_end(&mut raw).map_err(|e| SubmitError::Decode(e.to_string()))?;
+    if raw.len() as u64 > MAX_TX_BODY {
+        return Err(SubmitError::TooLarge);
+    }
+    let body = String::from_utf8(raw).map_err(|e| SubmitError::Decode(e.to_string()))?;
+    decode_transaction(&body)
+}
+
+// Validate a transaction and add it to the mempool, returns its hash and fee
+pub fn submit_transaction(
+    tx: SignedTransaction,
+    mempool: &Mutex<TransactionMempool>,
+    utxo_state: &Mutex<UtxoState>,
+) -> Result<(H256, u64), SubmitError> {
+    // same lock order as the p2p worker, the mempool is never locked while holding the state
+    let fee = check_tx(&utxo_state.lock().unwrap(), &tx).map_err(SubmitError::Invalid)?;
+
+    let hash = tx.hash();
+    let mut locked_mempool = mempool.lock().unwrap();
+    if locked_mempool.contains(&hash) {
+        return Err(SubmitError::AlreadyKnown(hash));
+    }
+    if let Some(input) = locked_mempool.conflicting_input(&tx) {
+        return Err(SubmitError::MempoolConflict(input.clone()));
+    }
+    locked_mempool.insert(tx, fee);
+    Ok((hash, fee))
+}
+
+fn respond_json<T: Serialize>(req: tiny_http::Request, payload: &T, status_code: u16) {
+    let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+    let resp = Response::from_string(serde_json::to_string_pretty(payload).unwrap())
+        .with_header(content_type)
+        .with_status_code(status_code);
+    req.respond(resp).unwrap();
+}
+
+fn u64_param(params: &HashMap<String, String>, name: &str) -> Result<u64, String> {
+    match params.get(name) {
+        Some(v) => v.parse::<u64>().map_err(|e| format!("error parsing {}: {}", name, e)),
+        None => Err(format!("missing {}", name)),
+    }
+}
+
+fn respond_error(req: tiny_http::Request, message: String, status_code: u16) {
+    respond_json(req, &ApiResponse { success: false, message }, status_code);
+}
+
+fn respond_ok(req: tiny_http::Request) {
+    respond_json(req, &ApiResponse { success: true, message: "ok".to_string() }, 200);
 }
 
 impl Server {
@@ -45,95 +186,301 @@ impl Server {
         miner: &MinerHandle,
         network: &NetworkServerHandle,
         txgen: &TxGenHandle,
+        mempool: &Arc<Mutex<TransactionMempool>>,
+        utxo_state: &Arc<Mutex<UtxoState>>,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        leaders: &Arc<Mutex<Vec<H256>>>,
+        ban_list: &Arc<Mutex<BanList>>,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
         let server = Self {
             handle,
             miner: miner.clone(),
             network: network.clone(),
-            txgen: txgen.clone()
+            txgen: txgen.clone(),
+            mempool: Arc::clone(mempool),
+            utxo_state: Arc::clone(utxo_state),
+            blockchain: Arc::clone(blockchain),
+            leaders: Arc::clone(leaders),
+            ban_list: Arc::clone(ban_list),
         };
         thread::spawn(move || {
-            for req in server.handle.incoming_requests() {
+            for mut req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
                 let txgen = server.txgen.clone(); 
                 let network = server.network.clone();
+                let mempool = Arc::clone(&server.mempool);
+                let utxo_state = Arc::clone(&server.utxo_state);
+                let blockchain = Arc::clone(&server.blockchain);
+                let leaders = Arc::clone(&server.leaders);
+                let ban_list = Arc::clone(&server.ban_list);
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
                     let url = match base_url.join(req.url()) {
                         Ok(u) => u,
                         Err(e) => {
-                            respond_result!(req, false, format!("error parsing url: {}", e));
+                            respond_error(req, format!("error parsing url: {}", e), 400);
                             return;
                         }
                     };
                     match url.path() {
                         "/miner/start" => {
-                            let params = url.query_pairs();
-                        
-                            let params: HashMap<_, _> = params.into_owned().collect();
-                        
-                            let lambda = match params.get("lambda") {
-                                Some(v) => v,
-                                None => {
-                                    respond_result!(req, false, "missing lambda");
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let interval = match u64_param(&params, "interval") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_error(req, e, 400);
                                     return;
                                 }
                             };
-                            let lambda = match lambda.parse::<u64>() {
+                            let miner_id = match u64_param(&params, "miner_id") {
                                 Ok(v) => v,
                                 Err(e) => {
-                                    respond_result!(
-                                        req,
-                                        false,
-                                        format!("error parsing lambda: {}", e)
-                                    );
+                                    respond_error(req, e, 400);
                                     return;
                                 }
                             };
-                            /*let index = match params.get("index") {
-                                Some(v) => v,
+                            if miner.start(interval, miner_id) {
+                                respond_ok(req);
+                            } else {
+                                respond_error(req, "miner was stopped, restart the node to mine again".to_string(), 409);
+                            }
+                        }
+                        "/miner/pause" => {
+                            if miner.pause() {
+                                respond_ok(req);
+                            } else {
+                                respond_error(req, "miner was stopped, restart the node to mine again".to_string(), 409);
+                            }
+                        }
+                        "/miner/stop" => {
+                            if miner.exit() {
+                                respond_ok(req);
+                            } else {
+                                respond_error(req, "miner is already stopped".to_string(), 409);
+                            }
+                        }
+                        "/miner/status" => {
+                            respond_json(req, &miner.status(), 200);
+                        }
+                        "/txgen/start" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let interval = match u64_param(&params, "interval") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_error(req, e, 400);
+                                    return;
+                                }
+                            };
+                            // a generator spending from a keystore ignores the ICO address set
+                            let address_set = match params.get("address_set") {
+                                None if txgen.status().keystore_wallet => 0,
+                                _ => match u64_param(&params, "address_set") {
+                                    Ok(v) if (v as usize) < ICO_NODES => v,
+                                    Ok(v) => {
+                                        respond_error(req, format!("address set {} out of range, there are {}", v, ICO_NODES), 400);
+                                        return;
+                                    }
+                                    Err(e) => {
+                                        respond_error(req, e, 400);
+                                        return;
+                                    }
+                                },
+                            };
+                            if txgen.start(interval, address_set) {
+                                respond_ok(req);
+                            } else {
+                                respond_error(req, "generator was stopped, restart the node to generate transactions again".to_string(), 409);
+                            }
+                        }
+                        "/txgen/pause" => {
+                            if txgen.pause() {
+                                respond_ok(req);
+                            } else {
+                                respond_error(req, "generator was stopped, restart the node to generate transactions again".to_string(), 409);
+                            }
+                        }
+                        "/txgen/stop" => {
+                            if txgen.exit() {
+                                respond_ok(req);
+                            } else {
+                                respond_error(req, "generator is already stopped".to_string(), 409);
+                            }
+                        }
+                        "/txgen/status" => {
+                            respond_json(req, &txgen.status(), 200);
+                        }
+                        "/metrics" => {
+                            let content_type = "Content-Type: text/plain; version=0.0.4".parse::<Header>().unwrap();
+                            req.respond(Response::from_string(metrics().render()).with_header(content_type)).unwrap();
+                        }
+                        "/network/ping" => {
+                            network.broadcast(Message::Ping(String::from("Test ping")));
+                            respond_ok(req);
+                        }
+                        "/network/bans" => {
+                            let bans = ban_list.lock().unwrap().bans(addrbook::now());
+                            respond_json(req, &bans, 200);
+                        }
+                        "/tx/submit" => {
+                            let result = if *req.method() != Method::Post {
+                                Err(SubmitError::WrongMethod)
+                            } else {
+                                read_transaction(req.as_reader())
+                            }
+                            .and_then(|tx| submit_transaction(tx, &mempool, &utxo_state));
+                            match result {
+                                Ok((hash, fee)) => {
+                                    network.broadcast(Message::NewTransactionHashes(vec![hash]));
+                                    let payload = TxSubmitResponse {
+                                        success: true,
+                                        message: "ok".to_string(),
+                                        reason: None,
+                                        tx_hash: Some(hash.to_string()),
+                                        fee: Some(fee),
+                                    };
+                                    respond_json(req, &payload, 200);
+                                }
+                                Err(e) => {
+                                    debug!("Rejected submitted tx: {}", e);
+                                    let payload = TxSubmitResponse {
+                                        success: false,
+                                        message: e.to_string(),
+                                        reason: Some(e.reason().to_string()),
+                                        tx_hash: None,
+                                        fee: None,
+                                    };
+                                    respond_json(req, &payload, e.status_code());
+                                }
+                            }
+                        }
+                        "/blockchain/proposers" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let level = match params.get("level").map(|v| v.parse::<u32>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_error(req, format!("error parsing level: {}", e), 400);
+                                    return;
+                                }
                                 None => {
-                                    respond_result!(req, false, "missing index");
+                                    respond_error(req, "missing level".to_string(), 400);
                                     return;
                                 }
                             };
-                            let index = match index.parse::<u64>() {
+                            let view = explorer::proposers(&blockchain.lock().unwrap(), level);
+                            match view {
+                                Some(view) => respond_json(req, &view, 200),
+                                None => respond_error(req, format!("no proposer at level {}", level), 404),
+                            }
+                        }
+                        "/blockchain/tips" => {
+                            let view = explorer::tips(&blockchain.lock().unwrap());
+                            respond_json(req, &view, 200);
+                        }
+                        "/ledger/leaders" => {
+                            let leader_sequence = leaders.lock().unwrap().clone();
+                            let view = explorer::leaders(&blockchain.lock().unwrap(), &leader_sequence);
+                            respond_json(req, &view, 200);
+                        }
+                        // the mempool is locked before the state, same as the tx generator
+                        path if path.starts_with("/utxo/address/") => {
+                            let address = match path["/utxo/address/".len()..].parse::<H160>() {
                                 Ok(v) => v,
                                 Err(e) => {
-                                    respond_result!(
-                                        req,
-                                        false,
-                                        format!("error parsing index: {}", e)
-                                    );
+                                    respond_error(req, format!("error parsing address: {}", e), 400);
                                     return;
                                 }
-                            };*/
-                           
-                            txgen.start(lambda, lambda%3);
-                            let interval = time::Duration::from_micros(1000000);
-                            thread::sleep(interval);
-                            miner.start(lambda, lambda%3);
-                            respond_result!(req, true, "ok");
+                            };
+                            let locked_mempool = mempool.lock().unwrap();
+                            let view = explorer::address_utxos(&utxo_state.lock().unwrap(), &locked_mempool, &address);
+                            drop(locked_mempool);
+                            respond_json(req, &view, 200);
                         }
-                        "/network/ping" => {
-                            network.broadcast(Message::Ping(String::from("Test ping")));
-                            respond_result!(req, true, "ok");
+                        path if path.starts_with("/utxo/outpoint/") => {
+                            let mut parts = path["/utxo/outpoint/".len()..].splitn(2, '/');
+                            let tx_hash = parts.next().unwrap_or("").parse::<H256>();
+                            let idx = parts.next().unwrap_or("").parse::<u8>();
+                            let input = match (tx_hash, idx) {
+                                (Ok(tx_hash), Ok(idx)) => UtxoInput { tx_hash, idx },
+                                (Err(e), _) => {
+                                    respond_error(req, format!("error parsing tx hash: {}", e), 400);
+                                    return;
+                                }
+                                (_, Err(e)) => {
+                                    respond_error(req, format!("error parsing output index: {}", e), 400);
+                                    return;
+                                }
+                            };
+                            let locked_mempool = mempool.lock().unwrap();
+                            let view = explorer::outpoint(&utxo_state.lock().unwrap(), &locked_mempool, &input);
+                            drop(locked_mempool);
+                            match view {
+                                Some(view) => respond_json(req, &view, 200),
+                                None => respond_error(req, format!("output {:?} is spent or unknown", input), 404),
+                            }
                         }
-                        _ => {
-                            let content_type =
-                                "Content-Type: application/json".parse::<Header>().unwrap();
-                            let payload = ApiResponse {
-                                success: false,
-                                message: "endpoint not found".to_string(),
+                        path if path.starts_with("/tx/") && path.ends_with("/status") => {
+                            let hash = path.get("/tx/".len()..path.len() - "/status".len()).unwrap_or("");
+                            let hash = match hash.parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_error(req, format!("error parsing tx hash: {}", e), 400);
+                                    return;
+                                }
+                            };
+                            let locked_mempool = mempool.lock().unwrap();
+                            let view = explorer::tx_status(&utxo_state.lock().unwrap(), &locked_mempool, &hash);
+                            drop(locked_mempool);
+                            respond_json(req, &view, 200);
+                        }
+                        path if path.starts_with("/blockchain/voter-chain/") => {
+                            let chain = match path["/blockchain/voter-chain/".len()..].parse::<u32>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_error(req, format!("error parsing chain number: {}", e), 400);
+                                    return;
+                                }
+                            };
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let from = match params.get("from").map(|v| v.parse::<H256>()) {
+                                Some(Ok(v)) => Some(v),
+                                Some(Err(e)) => {
+                                    respond_error(req, format!("error parsing from: {}", e), 400);
+                                    return;
+                                }
+                                None => None,
                             };
-                            let resp = Response::from_string(
-                                serde_json::to_string_pretty(&payload).unwrap(),
-                            )
-                            .with_header(content_type)
-                            .with_status_code(404);
-                            req.respond(resp).unwrap();
+                            let limit = match u64_param(&params, "limit") {
+                                Ok(v) => std::cmp::min(v as usize, explorer::MAX_VOTER_BLOCKS),
+                                Err(_) if !params.contains_key("limit") => explorer::DEFAULT_VOTER_BLOCKS,
+                                Err(e) => {
+                                    respond_error(req, e, 400);
+                                    return;
+                                }
+                            };
+                            let view = explorer::voter_chain(&blockchain.lock().unwrap(), chain, from, limit);
+                            match view {
+                                Some(view) => respond_json(req, &view, 200),
+                                None => respond_error(req, format!("no voter chain {} or no such block in it", chain), 404),
+                            }
+                        }
+                        path if path.starts_with("/block/") => {
+                            let hash = match path["/block/".len()..].parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_error(req, format!("error parsing block hash: {}", e), 400);
+                                    return;
+                                }
+                            };
+                            let view = explorer::block(&blockchain.lock().unwrap(), &hash);
+                            match view {
+                                Some(view) => respond_json(req, &view, 200),
+                                None => respond_error(req, format!("block {} not found", hash), 404),
+                            }
+                        }
+                        _ => {
+                            respond_error(req, "endpoint not found".to_string(), 404);
                         }
                     }
                 });
@@ -142,3 +489,44 @@ impl Server {
         info!("API server listening at {}", &addr);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::UtxoOutput;
+    use crate::wallet::Wallet;
+
+    #[test]
+    fn submitted_transactions_are_checked() {
+        let mut wallet = Wallet::new();
+        let owner = wallet.generate_key().unwrap();
+        let mut state = UtxoState::default();
+        let coin = UtxoInput { tx_hash: H256::from([1u8; 32]), idx: 0 };
+        state.state_map.insert(coin.clone(), UtxoOutput::new(owner, 10));
+        let coins = wallet.spendable_coins(&state);
+        let utxo_state = Mutex::new(state);
+        let mempool = Mutex::new(TransactionMempool::new());
+
+        let tx = wallet.create_transaction(&coins, vec![UtxoOutput::new(owner, 8)], 2).unwrap();
+        let encoded = hex::encode(bincode::serialize(&tx).unwrap());
+        let decoded = decode_transaction(&encoded).unwrap();
+        assert_eq!(submit_transaction(decoded, &mempool, &utxo_state).unwrap(), (tx.hash(), 2));
+        let json = serde_json::to_string(&tx).unwrap();
+        let decoded = decode_transaction(&json).unwrap();
+        assert!(matches!(submit_transaction(decoded, &mempool, &utxo_state), Err(SubmitError::AlreadyKnown(_))));
+
+        // a different spend of the same coin
+        let tx = wallet.create_transaction(&coins, vec![UtxoOutput::new(owner, 9)], 1).unwrap();
+        let err = submit_transaction(tx, &mempool, &utxo_state).unwrap_err();
+        assert!(matches!(err, SubmitError::MempoolConflict(ref input) if *input == coin));
+
+        let mut tx = wallet.create_transaction(&coins, vec![UtxoOutput::new(owner, 7)], 3).unwrap();
+        tx.tx.tx_output[0].value = 11;
+        let err = submit_transaction(tx, &mempool, &utxo_state).unwrap_err();
+        assert_eq!(err.reason(), "bad_signature");
+        assert!(matches!(decode_transaction("zz"), Err(SubmitError::Decode(_))));
+        let oversized = vec![b' '; MAX_TX_BODY as usize + 1];
+        assert!(matches!(read_transaction(&oversized[..]), Err(SubmitError::TooLarge)));
+        assert!(matches!(read_transaction(&oversized[1..]), Err(SubmitError::Decode(_))));
+    }
+}
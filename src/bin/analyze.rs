@@ -0,0 +1,357 @@
+// Post-processes the event logs written by the nodes with --event-log.
+// Transactions are joined by hash across the logs to report confirmation latency,
+// throughput over time, block rates and the proposer orphan rate as CSV or JSON.
+
+use clap::clap_app;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::process;
+
+// The fields of the events used here, the others are ignored
+#[derive(Deserialize)]
+struct Record {
+    timestamp: u64,
+    event: String,
+    #[serde(default)]
+    hash: Option<String>,
+    #[serde(default)]
+    kind: Option<String>,
+    #[serde(default)]
+    level: Option<u32>,
+}
+
+// Events of one node
+#[derive(Default)]
+struct NodeLog {
+    // tx hash -> first time it entered the mempool
+    received: HashMap<String, u64>,
+    // tx hash -> first time it got confirmed
+    confirmed: HashMap<String, u64>,
+    // level -> leader confirmed last, rolled back leaders are removed
+    leaders: HashMap<u32, String>,
+}
+
+#[derive(Default)]
+struct Analysis {
+    nodes: Vec<(String, NodeLog)>,
+    first_timestamp: Option<u64>,
+    last_timestamp: Option<u64>,
+    // block hash -> kind, of the blocks mined by any of the nodes
+    mined: HashMap<String, String>,
+    // proposer block hash -> level, of the proposer blocks seen by any of the nodes
+    proposers: HashMap<String, u32>,
+    // leaders in force at the end of the log of any of the nodes
+    leaders: HashSet<String>,
+    max_leader_level: u32,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct Latency {
+    samples: usize,
+    mean: f64,
+    min: f64,
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    max: f64,
+}
+
+#[derive(Serialize, Debug)]
+struct NodeSummary {
+    log: String,
+    received_txs: usize,
+    confirmed_txs: usize,
+    latency: Option<Latency>,
+}
+
+#[derive(Serialize, Debug)]
+struct Window {
+    // seconds since the first event
+    start: u64,
+    confirmed_txs: usize,
+    txs_per_sec: f64,
+}
+
+#[derive(Serialize, Debug)]
+struct Report {
+    duration_secs: f64,
+    // latencies of all nodes, in seconds
+    latency: Option<Latency>,
+    nodes: Vec<NodeSummary>,
+    // distinct transactions confirmed by at least one node
+    confirmed_txs: usize,
+    txs_per_sec: f64,
+    throughput: Vec<Window>,
+    proposer_blocks_mined: usize,
+    voter_blocks_mined: usize,
+    proposer_blocks_per_sec: f64,
+    voter_blocks_per_sec: f64,
+    // proposer blocks at confirmed levels which did not become the leader
+    proposer_orphan_rate: Option<f64>,
+}
+
+impl Analysis {
+    fn add_log<R: BufRead>(&mut self, name: &str, reader: R) -> io::Result<()> {
+        let mut node = NodeLog::default();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: Record = serde_json::from_str(&line).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {}", name, i + 1, e))
+            })?;
+            self.first_timestamp = Some(self.first_timestamp.map_or(record.timestamp, |t| t.min(record.timestamp)));
+            self.last_timestamp = Some(self.last_timestamp.map_or(record.timestamp, |t| t.max(record.timestamp)));
+            let hash = match record.hash {
+                Some(hash) => hash,
+                None => continue,
+            };
+            match record.event.as_str() {
+                "TxReceived" => {
+                    node.received.entry(hash).or_insert(record.timestamp);
+                }
+                "TxConfirmed" => {
+                    node.confirmed.entry(hash).or_insert(record.timestamp);
+                }
+                "BlockMined" => {
+                    if let Some(kind) = &record.kind {
+                        if kind == "proposer" {
+                            self.proposers.insert(hash.clone(), record.level.unwrap_or(0));
+                        }
+                        self.mined.insert(hash, kind.clone());
+                    }
+                }
+                "BlockInserted" if record.kind.as_deref() == Some("proposer") => {
+                    self.proposers.insert(hash, record.level.unwrap_or(0));
+                }
+                "LeaderConfirmed" => {
+                    node.leaders.insert(record.level.unwrap_or(0), hash);
+                }
+                "LeaderRolledBack" => {
+                    let level = record.level.unwrap_or(0);
+                    if node.leaders.get(&level) == Some(&hash) {
+                        node.leaders.remove(&level);
+                    }
+                }
+                _ => {}
+            }
+        }
+        for (level, hash) in &node.leaders {
+            self.max_leader_level = self.max_leader_level.max(*level);
+            self.leaders.insert(hash.clone());
+        }
+        self.nodes.push((name.to_string(), node));
+        Ok(())
+    }
+
+    fn report(&self, interval: u64) -> Report {
+        let first = self.first_timestamp.unwrap_or(0);
+        let duration_secs = (self.last_timestamp.unwrap_or(0) - first) as f64 / 1e6;
+        let rate = |n: usize| if duration_secs > 0.0 { n as f64 / duration_secs } else { 0.0 };
+
+        let mut all_latencies = vec![];
+        let mut nodes = vec![];
+        // tx hash -> earliest confirmation on any node
+        let mut first_confirmed: HashMap<&str, u64> = HashMap::new();
+        for (name, node) in &self.nodes {
+            let mut latencies: Vec<f64> = node.received.iter()
+                .filter_map(|(hash, received)| node.confirmed.get(hash).map(|confirmed| *confirmed as f64 / 1e6 - *received as f64 / 1e6))
+                .collect();
+            for (hash, confirmed) in &node.confirmed {
+                let time = first_confirmed.entry(hash).or_insert(*confirmed);
+                *time = (*time).min(*confirmed);
+            }
+            nodes.push(NodeSummary {
+                log: name.clone(),
+                received_txs: node.received.len(),
+                confirmed_txs: node.confirmed.len(),
+                latency: latency(&mut latencies),
+            });
+            all_latencies.append(&mut latencies);
+        }
+
+        let mut throughput = vec![];
+        let interval = interval.max(1);
+        for confirmed in first_confirmed.values() {
+            let window = ((confirmed - first) / 1_000_000 / interval) as usize;
+            if throughput.len() <= window {
+                throughput.resize_with(window + 1, || 0);
+            }
+            throughput[window] += 1;
+        }
+        let throughput = throughput.into_iter().enumerate().map(|(i, count)| Window {
+            start: i as u64 * interval,
+            confirmed_txs: count,
+            txs_per_sec: count as f64 / interval as f64,
+        }).collect();
+
+        let proposer_blocks_mined = self.mined.values().filter(|kind| *kind == "proposer").count();
+        let voter_blocks_mined = self.mined.values().filter(|kind| *kind == "voter").count();
+
+        // only levels with a confirmed leader tell whether a proposer block got orphaned
+        let confirmed_proposers: Vec<&String> = self.proposers.iter()
+            .filter(|(_, level)| **level > 0 && **level <= self.max_leader_level)
+            .map(|(hash, _)| hash)
+            .collect();
+        let proposer_orphan_rate = if confirmed_proposers.is_empty() {
+            None
+        } else {
+            let orphans = confirmed_proposers.iter().filter(|hash| !self.leaders.contains(**hash)).count();
+            Some(orphans as f64 / confirmed_proposers.len() as f64)
+        };
+
+        Report {
+            duration_secs,
+            latency: latency(&mut all_latencies),
+            nodes,
+            confirmed_txs: first_confirmed.len(),
+            txs_per_sec: rate(first_confirmed.len()),
+            throughput,
+            proposer_blocks_mined,
+            voter_blocks_mined,
+            proposer_blocks_per_sec: rate(proposer_blocks_mined),
+            voter_blocks_per_sec: rate(voter_blocks_mined),
+            proposer_orphan_rate,
+        }
+    }
+}
+
+fn latency(samples: &mut [f64]) -> Option<Latency> {
+    if samples.is_empty() {
+        return None;
+    }
+    samples.sort_by(|a, b| a.total_cmp(b));
+    // nearest rank percentile
+    let percentile = |p: f64| samples[((p * samples.len() as f64).ceil() as usize).clamp(1, samples.len()) - 1];
+    Some(Latency {
+        samples: samples.len(),
+        mean: samples.iter().sum::<f64>() / samples.len() as f64,
+        min: samples[0],
+        p50: percentile(0.5),
+        p90: percentile(0.9),
+        p99: percentile(0.99),
+        max: samples[samples.len() - 1],
+    })
+}
+
+fn optional(value: Option<f64>) -> String {
+    value.map_or(String::new(), |v| format!("{:.6}", v))
+}
+
+// A metric,value section followed by the per node latencies and the throughput windows
+fn print_csv(report: &Report) {
+    println!("metric,value");
+    println!("duration_secs,{:.6}", report.duration_secs);
+    println!("confirmed_txs,{}", report.confirmed_txs);
+    println!("txs_per_sec,{:.6}", report.txs_per_sec);
+    println!("proposer_blocks_mined,{}", report.proposer_blocks_mined);
+    println!("voter_blocks_mined,{}", report.voter_blocks_mined);
+    println!("proposer_blocks_per_sec,{:.6}", report.proposer_blocks_per_sec);
+    println!("voter_blocks_per_sec,{:.6}", report.voter_blocks_per_sec);
+    println!("proposer_orphan_rate,{}", optional(report.proposer_orphan_rate));
+    println!();
+    println!("log,received_txs,confirmed_txs,samples,mean,min,p50,p90,p99,max");
+    let rows = report.nodes.iter()
+        .map(|node| (node.log.as_str(), Some((node.received_txs, node.confirmed_txs)), &node.latency))
+        .chain(std::iter::once(("all", None, &report.latency)));
+    for (log, counts, latency) in rows {
+        let counts = counts.map_or(",".to_string(), |(received, confirmed)| format!("{},{}", received, confirmed));
+        match latency {
+            Some(l) => println!("{},{},{},{:.6},{:.6},{:.6},{:.6},{:.6},{:.6}", log, counts, l.samples, l.mean, l.min, l.p50, l.p90, l.p99, l.max),
+            None => println!("{},{},0,,,,,,", log, counts),
+        }
+    }
+    println!();
+    println!("window_start_secs,confirmed_txs,txs_per_sec");
+    for window in &report.throughput {
+        println!("{},{},{:.6}", window.start, window.confirmed_txs, window.txs_per_sec);
+    }
+}
+
+fn main() {
+    let matches = clap_app!(Analyze =>
+     (version: "0.1")
+     (about: "Reports confirmation latency, throughput and block rates from node event logs")
+     (@arg format: -f --format [FORMAT] possible_values(&["csv", "json"]) default_value("csv") "Sets the output format")
+     (@arg interval: -i --interval [SECS] default_value("10") "Sets the length of the throughput windows")
+     (@arg logs: <EVENT_LOG> ... "Event logs of the nodes of one experiment")
+    )
+    .get_matches();
+
+    let interval = matches.value_of("interval").unwrap().parse::<u64>().unwrap_or_else(|e| {
+        eprintln!("Error parsing throughput interval: {}", e);
+        process::exit(1);
+    });
+
+    let mut analysis = Analysis::default();
+    for path in matches.values_of("logs").unwrap() {
+        let result = File::open(path).and_then(|file| analysis.add_log(path, BufReader::new(file)));
+        if let Err(e) = result {
+            eprintln!("Error reading event log {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+
+    let report = analysis.report(interval);
+    match matches.value_of("format").unwrap() {
+        "json" => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+        _ => print_csv(&report),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joins_transactions_across_logs() {
+        let p1 = r#"{"timestamp":0,"event":"TxReceived","hash":"aa"}
+{"timestamp":1000000,"event":"BlockMined","hash":"b1","kind":"proposer","level":1}
+{"timestamp":1000000,"event":"BlockInserted","hash":"b1","kind":"proposer","level":1}
+{"timestamp":3000000,"event":"TxConfirmed","hash":"aa","level":1}
+{"timestamp":3000000,"event":"LeaderConfirmed","level":1,"hash":"b1"}"#;
+        let p2 = r#"{"timestamp":500000,"event":"TxReceived","hash":"aa"}
+{"timestamp":1500000,"event":"BlockMined","hash":"b2","kind":"proposer","level":1}
+{"timestamp":2000000,"event":"BlockMined","hash":"v1","kind":"voter","level":1}
+{"timestamp":4500000,"event":"TxConfirmed","hash":"aa","level":1}
+{"timestamp":5000000,"event":"TxReceived","hash":"bb"}"#;
+        let mut analysis = Analysis::default();
+        analysis.add_log("p1", p1.as_bytes()).unwrap();
+        analysis.add_log("p2", p2.as_bytes()).unwrap();
+        let report = analysis.report(2);
+
+        assert_eq!(report.duration_secs, 5.0);
+        let latency = report.latency.unwrap();
+        assert_eq!((latency.samples, latency.min, latency.max, latency.p50), (2, 3.0, 4.0, 3.0));
+        assert_eq!(report.nodes[1].received_txs, 2);
+        // counted once, in the window of the earliest confirmation
+        assert_eq!(report.confirmed_txs, 1);
+        assert_eq!(report.throughput.iter().map(|w| w.confirmed_txs).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!((report.proposer_blocks_mined, report.voter_blocks_mined), (2, 1));
+        assert_eq!(report.proposer_orphan_rate, Some(0.5));
+    }
+
+    #[test]
+    fn rolled_back_leaders_are_orphans() {
+        let p1 = r#"{"timestamp":0,"event":"BlockInserted","hash":"b1","kind":"proposer","level":2}
+{"timestamp":0,"event":"BlockInserted","hash":"b2","kind":"proposer","level":2}
+{"timestamp":1000000,"event":"LeaderConfirmed","level":2,"hash":"b1"}
+{"timestamp":2000000,"event":"LeaderRolledBack","level":2,"hash":"b1"}
+{"timestamp":3000000,"event":"LeaderConfirmed","level":2,"hash":"b2"}"#;
+        let mut analysis = Analysis::default();
+        analysis.add_log("p1", p1.as_bytes()).unwrap();
+        assert_eq!(analysis.report(1).proposer_orphan_rate, Some(0.5));
+
+        // a rollback without a new leader leaves the level undecided
+        let p2 = r#"{"timestamp":0,"event":"BlockInserted","hash":"b3","kind":"proposer","level":1}
+{"timestamp":1000000,"event":"LeaderConfirmed","level":1,"hash":"b3"}
+{"timestamp":2000000,"event":"LeaderRolledBack","level":1,"hash":"b3"}"#;
+        let mut analysis = Analysis::default();
+        analysis.add_log("p2", p2.as_bytes()).unwrap();
+        assert_eq!(analysis.report(1).proposer_orphan_rate, None);
+    }
+}
@@ -4,7 +4,7 @@
 use rand::Rng;
 use serde::{Serialize, Deserialize};
 use crate::crypto::hash::{H256, Hashable};
-use crate::transaction::{self, SignedTransaction};
+use crate::transaction::{SignedTransaction, CoinbaseTransaction};
 use crate::crypto::merkle::MerkleTree;
 
 extern crate chrono;
@@ -12,6 +12,8 @@ use chrono::prelude::*;
 
 #[derive(Serialize, Deserialize, Debug,Clone)]
 pub struct Header {
+    // proposer block the superblock was mined on, decides the required difficulty
+    pub parent: H256,
     pub nonce: u32,
     pub difficulty: H256,
     pub timestamp: u128,
@@ -44,7 +46,7 @@ pub struct Block {
 
 impl Block {
     pub fn new(
-        //parent: H256,
+        parent: H256,
         ts: u128,
         n: u32,
         content_merkle_root: H256,
@@ -54,7 +56,7 @@ impl Block {
         diff: H256,
     ) -> Self {
         let header = Header{
-           // parenthash:parent,
+            parent,
             nonce:n,
             difficulty:diff,
             timestamp:ts,
@@ -72,6 +74,7 @@ impl Block {
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct ProposerContent {
     pub parent_hash:H256,
+    pub coinbase: CoinbaseTransaction,
     pub transactions: Vec<SignedTransaction>,
     pub proposer_refs: Vec<H256>,
 }
@@ -80,10 +83,11 @@ impl Hashable for ProposerContent {
     fn hash(&self) -> H256 {
         let txns_merkle_tree = MerkleTree::new(&self.transactions);
         let prop_refs_merkle_tree = MerkleTree::new(&self.proposer_refs);
-        let mut byte_array = [0u8; 96];
+        let mut byte_array = [0u8; 128];
         byte_array[..32].copy_from_slice(self.parent_hash.as_ref());
         byte_array[32..64].copy_from_slice(prop_refs_merkle_tree.root().as_ref());
         byte_array[64..96].copy_from_slice(txns_merkle_tree.root().as_ref());
+        byte_array[96..128].copy_from_slice(self.coinbase.hash().as_ref());
         ring::digest::digest(&ring::digest::SHA256, &byte_array).into()
     }
 }
@@ -156,13 +160,14 @@ pub fn genesis_proposer() -> Block {
 let zero_vec : [u8; 32] = [0; 32];
    let content = ProposerContent {
       parent_hash:zero_vec.into(),
+      coinbase:CoinbaseTransaction::default(),
       transactions:vec![],
       proposer_refs:vec![],
    };
    
    let raw: [u8; 32] = [255; 32];
    let default_diff:H256= raw.into();
-   Block::new(0,0,zero_vec.into(),vec![],Content::Proposer(content),0,default_diff,)
+   Block::new(zero_vec.into(),0,0,zero_vec.into(),vec![],Content::Proposer(content),0,default_diff,)
 }
 
 pub fn genesis_voter(chain_number:u32) -> Block {
@@ -175,6 +180,6 @@ pub fn genesis_voter(chain_number:u32) -> Block {
     let raw: [u8; 32] = [255; 32];
     let default_diff:H256= raw.into();
 
-    Block::new(0,0,zero_vec.into(),vec![],Content::Voter(content),0,default_diff,)
+    Block::new(zero_vec.into(),0,0,zero_vec.into(),vec![],Content::Voter(content),0,default_diff,)
 }
 
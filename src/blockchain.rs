@@ -2,10 +2,17 @@ use crate::block::{self, *};
 use crate::crypto::hash::{H256,Hashable};
 use log::debug;
 use log::info;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::collections::VecDeque;
 use crate::mempool::{TransactionMempool};
+use crate::storage::{BlockStore, ChainSnapshot, MemoryStore};
+use crate::metrics::metrics;
+use crate::events::{emit, Event, BlockKind};
+use crate::miner::{self, DIFFICULTY_EPOCH, TARGET_PROPOSER_INTERVAL};
+use crate::validation::{BlockResult, check_difficulty, check_timestamp, check_coinbase};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::{error, warn};
 
 // use crate::utils::{*};
 
@@ -27,9 +34,21 @@ pub fn remove_by_element<T>(list: &mut Vec<T>, element: T) where T: PartialEq {
     }
 }
 
+// Number of inserted blocks after which the derived indices are persisted again
+pub const SNAPSHOT_INTERVAL: usize = 64;
+// Snapshots serialize the indices of the whole chain, so the interval also grows to this
+// fraction of the stored blocks: their cost per inserted block stays bounded as the chain
+// grows, and so does the share of blocks replayed on startup
+pub const SNAPSHOT_FRACTION: usize = 8;
+// Orphan blocks kept at most, the oldest are dropped beyond it
+pub const MAX_ORPHAN_BLOCKS: usize = 1024;
+// Orphan blocks whose dependencies did not arrive within this time are dropped
+pub const ORPHAN_EXPIRY: Duration = Duration::from_secs(600);
+
 pub enum InsertStatus {
     Orphan,
     Valid,
+    Invalid,
 }
 
 #[derive(Debug,Clone)]
@@ -61,33 +80,51 @@ pub struct Blockchain {
     //that proposer
     pub proposer2voterinfo: HashMap<H256, Vec<(u32, H256)>>,
 
-    // Last voted level corresponding to each voter chain
-    // IMP TODO: need changes to handle forking in the voter chain
+    // Last level voted by the main (longest) chain of each voter chain
     pub num_voter_chains: u32,
     pub chain2level: HashMap<u32, u32>,
 
     // orphan buffer stores a mapping between missing reference and block
     // use Vec<Block> as many blocks could wait on a single reference.
-    pub orphan_buffer: HashMap<H256, Vec<Block>>,
+    pub orphan_buffer: HashMap<H256, Vec<(H256, Block)>>,
+    // orphan hash -> when it was first buffered and the reference it waits for. Orphans are
+    // only stored once they pass the checks of `insert` on joining the chain.
+    orphan_times: HashMap<H256, (Instant, H256)>,
+    // buffered orphans, oldest first
+    orphans_by_age: BTreeSet<(Instant, H256)>,
 
     // This is the store of all blocks ever received / mined.
-    pub blocksdb: HashMap<H256, Block>,
+    pub blocksdb: Box<dyn BlockStore>,
+    // blocks inserted since the last snapshot of the indices was saved
+    blocks_since_snapshot: usize,
+    // set while replaying stored blocks on startup
+    replaying: bool,
 
     // reference to mempool
     mempool:Arc<Mutex<TransactionMempool>>, 
 
-    new_proposer: bool,
+    // incremented whenever a block joins the chain, lets the miner detect a stale template
+    version: u64,
 }
 
 impl Blockchain {
+    // Blockchain backed by a volatile in-memory block store
     pub fn new(num_voter_chains: u32, mempool: &Arc<Mutex<TransactionMempool>>) -> Self {
-        // genesis for proposer and voter chains
-        let mut blocksdb = HashMap::new();
+        Self::with_store(num_voter_chains, mempool, Box::new(MemoryStore::new())).unwrap()
+    }
 
+    // Blockchain backed by `blocksdb`. Blocks and indices already present in the store
+    // are loaded: the latest snapshot is restored and newer blocks are replayed on top of it.
+    pub fn with_store(
+        num_voter_chains: u32,
+        mempool: &Arc<Mutex<TransactionMempool>>,
+        mut blocksdb: Box<dyn BlockStore>,
+    ) -> std::io::Result<Self> {
+        // genesis for proposer and voter chains
         let mut proposer_chain = HashMap::new();
         let proposer = genesis_proposer();
         let proposer_hash = proposer.hash();
-        blocksdb.insert(proposer_hash, proposer.clone());
+        blocksdb.insert_block(proposer_hash, &proposer)?;
 
         let metablock = Metablock {
             block: proposer,
@@ -104,7 +141,7 @@ impl Blockchain {
             let mut tmp_chain = HashMap::new();
             let voter = genesis_voter(chain_num);
             let voter_hash = voter.hash();
-            blocksdb.insert(voter_hash, voter.clone());
+            blocksdb.insert_block(voter_hash, &voter)?;
 
             let metablock = Metablock {
                 block: voter,
@@ -133,7 +170,7 @@ impl Blockchain {
         let mut proposer2voterinfo = HashMap::new();
         proposer2voterinfo.insert(proposer_hash, Vec::new());
 
-        Blockchain {
+        let mut blockchain = Blockchain {
             proposer_chain: proposer_chain,
             proposer_tip: proposer_hash,
             proposer_depth: 1,
@@ -152,29 +189,154 @@ impl Blockchain {
             chain2level: chain2level,
 
             orphan_buffer: HashMap::new(),
+            orphan_times: HashMap::new(),
+            orphans_by_age: BTreeSet::new(),
             blocksdb: blocksdb,
+            blocks_since_snapshot: 0,
+            replaying: false,
 
             mempool: Arc::clone(mempool),
-            new_proposer: true,
+            version: 0,
+        };
+        blockchain.load()?;
+        Ok(blockchain)
+    }
+
+    // Rebuild the in-memory chain from the block store
+    fn load(&mut self) -> std::io::Result<()> {
+        let mut replay_from = 0;
+        if let Some(snapshot) = self.blocksdb.snapshot().cloned() {
+            if snapshot.num_voter_chains != self.num_voter_chains {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "block store was created with {} voter chains, not {}",
+                        snapshot.num_voter_chains, self.num_voter_chains
+                    ),
+                ));
+            }
+            self.restore(&snapshot)?;
+            replay_from = snapshot.num_blocks;
+        }
+
+        let pending: Vec<Block> = self.blocksdb.block_hashes()[replay_from..].iter()
+            .map(|hash| self.blocksdb.get_block(hash).unwrap().clone())
+            .filter(|block| !self.is_inserted(block))
+            .collect();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        info!("Replaying {} stored blocks", pending.len());
+        self.replaying = true;
+        for block in &pending {
+            self.insert(block);
+        }
+        self.replaying = false;
+        self.save_snapshot();
+        info!("Restored blockchain with proposer depth {}", self.proposer_depth);
+        Ok(())
+    }
+
+    fn metablock_from_store(&self, hash: &H256, level: u32) -> std::io::Result<Metablock> {
+        match self.blocksdb.get_block(hash) {
+            Some(block) => Ok(Metablock { block: block.clone(), level }),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("snapshot references block {:?} missing from the store", hash),
+            )),
+        }
+    }
+
+    fn restore(&mut self, snapshot: &ChainSnapshot) -> std::io::Result<()> {
+        // genesis blocks of all chains share the same hash, so they are kept as built
+        // by the constructor instead of being looked up in the store
+        let mut proposer_chain = self.proposer_chain.clone();
+        for (hash, level) in &snapshot.proposer_levels {
+            if !proposer_chain.contains_key(hash) {
+                proposer_chain.insert(*hash, self.metablock_from_store(hash, *level)?);
+            }
+        }
+
+        let mut voter_chains = self.voter_chains.clone();
+        for (chain, levels) in voter_chains.iter_mut().zip(&snapshot.voter_levels) {
+            for (hash, level) in levels {
+                if !chain.contains_key(hash) {
+                    chain.insert(*hash, self.metablock_from_store(hash, *level)?);
+                }
+            }
+        }
+
+        self.proposer_chain = proposer_chain;
+        self.proposer_tip = snapshot.proposer_tip;
+        self.proposer_depth = snapshot.proposer_depth;
+        self.voter_chains = voter_chains;
+        self.voter_tips = snapshot.voter_tips.clone();
+        self.voter_depths = snapshot.voter_depths.clone();
+        self.unref_proposers = snapshot.unref_proposers.clone();
+        self.level2proposer = snapshot.level2proposer.clone();
+        self.level2allproposers = snapshot.level2allproposers.clone();
+        self.proposer2votecount = snapshot.proposer2votecount.clone();
+        self.proposer2voterinfo = snapshot.proposer2voterinfo.clone();
+        self.chain2level = snapshot.chain2level.clone();
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> ChainSnapshot {
+        ChainSnapshot {
+            num_blocks: self.blocksdb.block_hashes().len(),
+            num_voter_chains: self.num_voter_chains,
+            proposer_levels: self.proposer_chain.iter().map(|(h, m)| (*h, m.level)).collect(),
+            proposer_tip: self.proposer_tip,
+            proposer_depth: self.proposer_depth,
+            voter_levels: self.voter_chains.iter()
+                .map(|chain| chain.iter().map(|(h, m)| (*h, m.level)).collect())
+                .collect(),
+            voter_tips: self.voter_tips.clone(),
+            voter_depths: self.voter_depths.clone(),
+            unref_proposers: self.unref_proposers.clone(),
+            level2proposer: self.level2proposer.clone(),
+            level2allproposers: self.level2allproposers.clone(),
+            proposer2votecount: self.proposer2votecount.clone(),
+            proposer2voterinfo: self.proposer2voterinfo.clone(),
+            chain2level: self.chain2level.clone(),
+        }
+    }
+
+    // Persist the derived indices so that a restart does not need to replay every block
+    pub fn save_snapshot(&mut self) {
+        if !self.blocksdb.is_persistent() {
+            return;
+        }
+        let snapshot = self.snapshot();
+        match self.blocksdb.save_snapshot(&snapshot) {
+            Ok(()) => self.blocks_since_snapshot = 0,
+            Err(e) => error!("Failed to save blockchain snapshot: {}", e),
         }
     }
 
     pub fn is_orphan (&mut self, block: &Block) -> bool {
         // If there are missing references, it will add 
         // (first missing ref -> block) entry to orphan buffer map
+        if !self.proposer_chain.contains_key(&block.header.parent) {
+            // proposer block the block was mined on not found
+            self.buffer_orphan(block.header.parent, block);
+            debug!("Adding block with hash {:?} to buffer", block.hash());
+            return true;
+        }
+
         match &block.content {
             Content::Proposer(content) => {
                 if (!self.proposer_chain.contains_key(&content.parent_hash)) {
                     // parent proposer not found, add to orphan buffer
-                    self.orphan_buffer.entry(content.parent_hash).or_insert(Vec::new()).push(block.clone());
-                    println!("Adding proposer block with hash {:?} to buffer", block.hash());
+                    self.buffer_orphan(content.parent_hash, block);
+                    debug!("Adding proposer block with hash {:?} to buffer", block.hash());
                     return true;
                 }
 
                 for ref_proposer in content.proposer_refs.clone() {
                     if (!self.proposer_chain.contains_key(&ref_proposer)) {
-                        self.orphan_buffer.entry(ref_proposer).or_insert(Vec::new()).push(block.clone());
-                        println!("Adding proposer block with hash {:?} to buffer", block.hash());
+                        self.buffer_orphan(ref_proposer, block);
+                        debug!("Adding proposer block with hash {:?} to buffer", block.hash());
                         return true;
                     }
                 }
@@ -185,15 +347,15 @@ impl Blockchain {
 
                 if (!self.voter_chains[(chain_num-1) as usize].contains_key(&content.parent_hash)) {
                     // parent proposer not found, add to orphan buffer
-                    self.orphan_buffer.entry(content.parent_hash).or_insert(Vec::new()).push(block.clone());
-                    println!("Adding voter block with hash {:?} to buffer", block.hash());
+                    self.buffer_orphan(content.parent_hash, block);
+                    debug!("Adding voter block with hash {:?} to buffer", block.hash());
                     return true;
                 }
 
                 for vote in content.votes.clone() {
                     if (!self.proposer_chain.contains_key(&vote)) {
-                        self.orphan_buffer.entry(vote).or_insert(Vec::new()).push(block.clone());
-                        println!("Adding voter block with hash {:?} to buffer", block.hash());
+                        self.buffer_orphan(vote, block);
+                        debug!("Adding voter block with hash {:?} to buffer", block.hash());
                         return true;
                     }
                 }
@@ -202,13 +364,91 @@ impl Blockchain {
         }
     }
 
+    fn buffer_orphan(&mut self, missing: H256, block: &Block) {
+        let block_hash = block.hash();
+        // an orphan received again keeps its age, it moves only if it now waits for another block
+        let buffered = match self.orphan_times.get(&block_hash) {
+            Some((_, waiting_for)) if *waiting_for == missing => return,
+            Some((buffered, _)) => *buffered,
+            None => Instant::now(),
+        };
+        self.orphan_buffer.entry(missing).or_default().push((block_hash, block.clone()));
+        self.orphan_times.insert(block_hash, (buffered, missing));
+        self.orphans_by_age.insert((buffered, block_hash));
+        self.prune_orphans(Instant::now());
+    }
+
+    // Drop the orphans older than ORPHAN_EXPIRY and the oldest ones beyond MAX_ORPHAN_BLOCKS
+    pub fn prune_orphans(&mut self, now: Instant) {
+        let mut dropped = 0;
+        while let Some(&(buffered, block_hash)) = self.orphans_by_age.iter().next() {
+            if self.orphans_by_age.len() <= MAX_ORPHAN_BLOCKS && now.duration_since(buffered) < ORPHAN_EXPIRY {
+                break;
+            }
+            self.orphans_by_age.remove(&(buffered, block_hash));
+            let (_, missing) = self.orphan_times.remove(&block_hash).unwrap();
+            if let Some(blocks) = self.orphan_buffer.get_mut(&missing) {
+                blocks.retain(|(hash, _)| *hash != block_hash);
+                if blocks.is_empty() {
+                    self.orphan_buffer.remove(&missing);
+                }
+            }
+            dropped += 1;
+        }
+        if dropped > 0 {
+            debug!("Dropped {} orphan blocks", dropped);
+        }
+    }
+
+    // The orphan joined the chain or turned out invalid
+    fn unbuffer_orphan(&mut self, block_hash: &H256) {
+        if let Some((buffered, _)) = self.orphan_times.remove(block_hash) {
+            self.orphans_by_age.remove(&(buffered, *block_hash));
+        }
+    }
+
+    // Blocks the orphans are waiting for which we do not have at all
+    pub fn missing_blocks(&self) -> Vec<H256> {
+        self.orphan_buffer.keys().filter(|hash| !self.has_block(**hash)).cloned().collect()
+    }
+
+    fn persist(&mut self, block_hash: H256, block: &Block) {
+        if let Err(e) = self.blocksdb.insert_block(block_hash, block) {
+            error!("Failed to persist block {:?}: {}", block_hash, e);
+        }
+    }
+
+    fn update_metrics(&self) {
+        metrics().proposer_depth.set(self.proposer_depth as i64);
+        metrics().orphan_blocks.set(self.orphan_times.len() as i64);
+    }
+
     pub fn insert(&mut self, block: &Block) -> InsertStatus {
         let block_hash = block.hash();
-        self.blocksdb.insert(block_hash, block.clone());
 
+        if self.is_inserted(block) {
+            debug!("Block {:?} already in the chain", block_hash);
+            return InsertStatus::Valid;
+        }
+
+        self.prune_orphans(Instant::now());
         if self.is_orphan(block) {
+            self.update_metrics();
             return InsertStatus::Orphan;
         }
+        self.unbuffer_orphan(&block_hash);
+
+        // the difficulty, the timestamp and the coinbase can only be checked once the proposer parent is known
+        if let BlockResult::Fail = check_difficulty(block, self) {
+            return InsertStatus::Invalid;
+        }
+        if let BlockResult::Fail = check_timestamp(block, self) {
+            return InsertStatus::Invalid;
+        }
+        if let BlockResult::Fail = check_coinbase(block, self) {
+            return InsertStatus::Invalid;
+        }
+        self.persist(block_hash, block);
 
         // All references inside the block are guaranteed to be present
         match &block.content {
@@ -230,8 +470,7 @@ impl Blockchain {
                     level: block_level,
                 };
                 self.proposer_chain.insert(block_hash, metablock.clone());
-                self.new_proposer = true;
-                println!("Added proposer {:?} at level {}", block_hash, block_level);
+                emit(Event::BlockInserted { hash: block_hash, kind: BlockKind::Proposer, level: block_level });
 
                 if metablock.level > self.proposer_depth {
                     self.proposer_depth = metablock.level;
@@ -240,13 +479,15 @@ impl Blockchain {
 
                 // remove transactions from the mempool
                 // println!("trying to acquire mempool lock");
-                let mut locked_mempool = self.mempool.lock().unwrap();
-                // println!("acquired mempool lock");
+                if !self.replaying {
+                    let mut locked_mempool = self.mempool.lock().unwrap();
+                    // println!("acquired mempool lock");
 
-                for tx in &content.transactions {
-                    locked_mempool.delete(&tx.hash());
+                    for tx in &content.transactions {
+                        locked_mempool.delete(&tx.hash());
+                    }
+                    drop(locked_mempool);
                 }
-                drop(locked_mempool);
 
                 // Add selfhash to unref_proposers
                 self.unref_proposers.push(block_hash);
@@ -261,40 +502,24 @@ impl Blockchain {
 
             Content::Voter(content) => {
                 let chain_num = content.chain_num;
+                let chain_idx = (chain_num-1) as usize;
 
-                // BEHOLD
-                // The below code is inaccurate: votes aren't counted from every block, 
-                // only the blocks belonging to the longest chain. So this is a major TODO.
-                // Bhavana will work on this 4/25. 
-                
-                // go through all votes, update proposer2votecount and chain2level
-                let mut max_vote_level: u32 = self.chain2level[&chain_num];
-                let voter_info = (chain_num, block_hash);
-                for vote in content.votes.clone() {
-                    // update proposer2votecount
-                    let counter = self.proposer2votecount.entry(vote).or_insert(0);
-                    *counter += 1;
-                    
-                    let voters_info = self.proposer2voterinfo.entry(vote).or_insert(Vec::new());
-                    voters_info.push(voter_info);
-
-                    // update max vote level variable
-                    let block_level = self.proposer_chain[&vote].level;
-                    let max_vote_level = cmp::max(max_vote_level, block_level);
-                }
-                self.chain2level.insert(chain_num, max_vote_level);
-
-                // add to voter chain and update tip
-                let mut parent_meta = &self.voter_chains[(chain_num-1) as usize][&content.parent_hash];
+                // add to voter chain
+                let parent_meta = &self.voter_chains[chain_idx][&content.parent_hash];
                 let metablock = Metablock {
                     block: block.clone(),
                     level: parent_meta.level + 1
                 };
-                self.voter_chains[(chain_num-1) as usize].insert(block_hash, metablock.clone());
-                // println!("Added voter {:?} #{} at level {}", block_hash, chain_num, metablock.level);
-                if metablock.level > self.voter_depths[(chain_num-1) as usize] {
-                    self.voter_depths[(chain_num-1) as usize] = metablock.level;
-                    self.voter_tips[(chain_num-1) as usize] = block_hash;
+                self.voter_chains[chain_idx].insert(block_hash, metablock.clone());
+                emit(Event::BlockInserted { hash: block_hash, kind: BlockKind::Voter, level: metablock.level });
+
+                // Only votes of blocks on the longest chain are counted
+                if content.parent_hash == self.voter_tips[chain_idx] {
+                    self.add_votes(chain_num, block_hash, &content.votes);
+                    self.voter_depths[chain_idx] = metablock.level;
+                    self.voter_tips[chain_idx] = block_hash;
+                } else if metablock.level > self.voter_depths[chain_idx] {
+                    self.switch_voter_tip(chain_num, block_hash);
                 }
             }
         }
@@ -302,8 +527,8 @@ impl Blockchain {
         let result = self.orphan_buffer.remove(&block_hash);
         match result {
             Some(orphan_blocks) => {
-                for orphan_block in &orphan_blocks {
-                    let status = self.insert(&orphan_block);
+                for (orphan_hash, orphan_block) in &orphan_blocks {
+                    let status = self.insert(orphan_block);
                     match status {
                         InsertStatus::Valid => {
                             // remove orphan_block from the orphan buffer list
@@ -318,10 +543,13 @@ impl Blockchain {
                             // }
                             // if success {
                                 // self.orphan_buffer[&block_hash].remove(orphan_idx);
-                            println!("Orphan block {:?} processed", orphan_block.hash());
+                            debug!("Orphan block {:?} processed", orphan_hash);
                             // }
                         }
                         InsertStatus::Orphan => {},
+                        InsertStatus::Invalid => {
+                            warn!("Orphan block {:?} is invalid", orphan_hash);
+                        }
                     }
                 }
             },
@@ -330,9 +558,144 @@ impl Blockchain {
             },
         }
 
+        self.version += 1;
+        self.update_metrics();
+        if !self.replaying && self.blocksdb.is_persistent() {
+            self.blocks_since_snapshot += 1;
+            let interval = cmp::max(SNAPSHOT_INTERVAL, self.blocksdb.block_hashes().len() / SNAPSHOT_FRACTION);
+            if self.blocks_since_snapshot >= interval {
+                self.save_snapshot();
+            }
+        }
+
         InsertStatus::Valid
     }
 
+    fn voter_parent(&self, chain_num: u32, block_hash: H256) -> H256 {
+        match &self.voter_chains[(chain_num-1) as usize][&block_hash].block.content {
+            Content::Voter(content) => content.parent_hash,
+            Content::Proposer(_) => unreachable!("proposer block in voter chain {}", chain_num),
+        }
+    }
+
+    fn voter_votes(&self, chain_num: u32, block_hash: H256) -> Vec<H256> {
+        match &self.voter_chains[(chain_num-1) as usize][&block_hash].block.content {
+            Content::Voter(content) => content.votes.clone(),
+            Content::Proposer(_) => unreachable!("proposer block in voter chain {}", chain_num),
+        }
+    }
+
+    // Count the votes of a block which joined the main chain of `chain_num`
+    fn add_votes(&mut self, chain_num: u32, block_hash: H256, votes: &[H256]) {
+        let mut max_vote_level: u32 = self.chain2level[&chain_num];
+        let voter_info = (chain_num, block_hash);
+        for vote in votes {
+            *self.proposer2votecount.entry(*vote).or_insert(0) += 1;
+            self.proposer2voterinfo.entry(*vote).or_insert_with(Vec::new).push(voter_info);
+            max_vote_level = cmp::max(max_vote_level, self.proposer_chain[vote].level);
+        }
+        self.chain2level.insert(chain_num, max_vote_level);
+    }
+
+    // Retract the votes of a block which left the main chain of `chain_num`
+    fn remove_votes(&mut self, chain_num: u32, block_hash: H256, votes: &[H256]) {
+        for vote in votes {
+            if let Some(counter) = self.proposer2votecount.get_mut(vote) {
+                *counter = counter.saturating_sub(1);
+            }
+            if let Some(voters_info) = self.proposer2voterinfo.get_mut(vote) {
+                voters_info.retain(|info| *info != (chain_num, block_hash));
+            }
+        }
+    }
+
+    // Reorganize voter chain `chain_num` so that `new_tip` becomes its tip: votes of the blocks
+    // on the abandoned branch are retracted and votes of the new branch are counted
+    fn switch_voter_tip(&mut self, chain_num: u32, new_tip: H256) {
+        let chain_idx = (chain_num-1) as usize;
+        let level = |chain: &Self, hash: &H256| chain.voter_chains[chain_idx][hash].level;
+
+        let mut old_branch: Vec<H256> = Vec::new();
+        let mut new_branch: Vec<H256> = Vec::new();
+        let mut old_cursor = self.voter_tips[chain_idx];
+        let mut new_cursor = new_tip;
+        while level(self, &new_cursor) > level(self, &old_cursor) {
+            new_branch.push(new_cursor);
+            new_cursor = self.voter_parent(chain_num, new_cursor);
+        }
+        while old_cursor != new_cursor {
+            old_branch.push(old_cursor);
+            old_cursor = self.voter_parent(chain_num, old_cursor);
+            new_branch.push(new_cursor);
+            new_cursor = self.voter_parent(chain_num, new_cursor);
+        }
+        info!(
+            "Voter chain {} reorg at level {}: {} blocks orphaned, {} blocks adopted",
+            chain_num, level(self, &old_cursor), old_branch.len(), new_branch.len()
+        );
+
+        for hash in old_branch {
+            let votes = self.voter_votes(chain_num, hash);
+            self.remove_votes(chain_num, hash, &votes);
+        }
+
+        // last voted level of the common ancestor
+        let mut voted_level = 0;
+        let mut cursor = old_cursor;
+        while level(self, &cursor) > 1 {
+            let votes = self.voter_votes(chain_num, cursor);
+            if let Some(max_level) = votes.iter().map(|vote| self.proposer_chain[vote].level).max() {
+                voted_level = max_level;
+                break;
+            }
+            cursor = self.voter_parent(chain_num, cursor);
+        }
+        self.chain2level.insert(chain_num, voted_level);
+
+        for hash in new_branch.into_iter().rev() {
+            let votes = self.voter_votes(chain_num, hash);
+            self.add_votes(chain_num, hash, &votes);
+        }
+        self.voter_depths[chain_idx] = level(self, &new_tip);
+        self.voter_tips[chain_idx] = new_tip;
+    }
+
+    // Whether the block has already been added to the proposer chain or its voter chain
+    fn is_inserted(&self, block: &Block) -> bool {
+        let block_hash = block.hash();
+        match &block.content {
+            Content::Proposer(_) => self.proposer_chain.contains_key(&block_hash),
+            Content::Voter(content) => self.voter_chains[(content.chain_num-1) as usize].contains_key(&block_hash),
+        }
+    }
+
+    // Difficulty required for blocks mined on top of proposer block `parent`, retargeted
+    // every DIFFICULTY_EPOCH levels from the timestamps of the epoch's proposer blocks.
+    // Returns None if `parent` is not in the proposer chain.
+    pub fn get_difficulty(&self, parent: &H256) -> Option<H256> {
+        let parent_meta = self.proposer_chain.get(parent)?;
+        if parent_meta.level == 1 {
+            return Some(miner::get_difficulty(self.num_voter_chains));
+        }
+        let difficulty = parent_meta.block.header.difficulty;
+        if (parent_meta.level - 1) % DIFFICULTY_EPOCH != 0 {
+            return Some(difficulty);
+        }
+
+        // first proposer block of the epoch which ends with `parent`
+        let mut first_meta = parent_meta;
+        for _ in 1..DIFFICULTY_EPOCH {
+            let first_parent = match &first_meta.block.content {
+                Content::Proposer(content) => content.parent_hash,
+                Content::Voter(_) => unreachable!("voter block in proposer chain"),
+            };
+            first_meta = &self.proposer_chain[&first_parent];
+        }
+        let actual_span = parent_meta.block.header.timestamp.saturating_sub(first_meta.block.header.timestamp);
+        let expected_span = (DIFFICULTY_EPOCH - 1) as u128 * TARGET_PROPOSER_INTERVAL;
+        Some(miner::retarget_difficulty(difficulty, actual_span, expected_span))
+    }
+
     pub fn get_proposer_tip(&self) -> H256 {
         self.proposer_tip
     }
@@ -356,17 +719,15 @@ impl Blockchain {
     }
 
     pub fn has_block(&self, block_hash: H256) -> bool {
-        self.blocksdb.contains_key(&block_hash)
+        self.blocksdb.contains_block(&block_hash) || self.orphan_times.contains_key(&block_hash)
     }
 
     pub fn get_block(&self, block_hash: H256) -> Option<&Block> {
-        self.blocksdb.get(&block_hash)
+        self.blocksdb.get_block(&block_hash)
     }
 
-    pub fn has_new_proposer(&mut self) -> bool {
-        let ret_val = self.new_proposer;
-        self.new_proposer = false;
-        ret_val
+    pub fn version(&self) -> u64 {
+        self.version
     }
 
     pub fn print_chains(&self) {
@@ -407,10 +768,239 @@ mod tests {
     use super::*;
     // use crate::block::test::generate_random_block;
     use crate::crypto::hash::Hashable;
+    use crate::transaction::CoinbaseTransaction;
+    use bigint::uint::U256;
 
     #[test]
     fn blockchain_init() {
         // 10 voting chains
-        let mut blockchain = Blockchain::new(10);
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let blockchain = Blockchain::new(10, &mempool);
+        assert_eq!(blockchain.voter_chains.len(), 10);
+        assert_eq!(blockchain.proposer_depth, 1);
+    }
+
+    fn coinbase(blockchain: &Blockchain, parent: H256) -> CoinbaseTransaction {
+        let level = blockchain.proposer_chain[&parent].level + 1;
+        CoinbaseTransaction::new(level, parent, crate::utxo::miner_address(0), crate::utxo::BLOCK_REWARD)
+    }
+
+    fn proposer_block(blockchain: &Blockchain, parent: H256, nonce: u32) -> Block {
+        proposer_block_at(blockchain, parent, 0, nonce)
+    }
+
+    fn proposer_block_at(blockchain: &Blockchain, parent: H256, timestamp: u128, nonce: u32) -> Block {
+        let content = ProposerContent { parent_hash: parent, coinbase: coinbase(blockchain, parent), transactions: vec![], proposer_refs: vec![] };
+        let difficulty = blockchain.get_difficulty(&parent).unwrap();
+        Block::new(parent, timestamp, nonce, [0; 32].into(), vec![], Content::Proposer(content), 0, difficulty)
+    }
+
+    fn voter_block(blockchain: &Blockchain, chain_num: u32, parent: H256, votes: Vec<H256>, nonce: u32) -> Block {
+        let content = VoterContent { votes, parent_hash: parent, chain_num };
+        let proposer_parent = blockchain.get_proposer_tip();
+        let difficulty = blockchain.get_difficulty(&proposer_parent).unwrap();
+        Block::new(proposer_parent, 0, nonce, [0; 32].into(), vec![], Content::Voter(content), 0, difficulty)
+    }
+
+    #[test]
+    fn memory_store_is_not_snapshotted() {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let mut blockchain = Blockchain::new(2, &mempool);
+        let p1 = proposer_block(&blockchain, blockchain.get_proposer_tip(), 1);
+        blockchain.insert(&p1);
+        blockchain.save_snapshot();
+        assert!(blockchain.blocksdb.snapshot().is_none());
+    }
+
+    #[test]
+    fn restore_from_store() {
+        let dir = std::env::temp_dir().join(format!("prism-blockchain-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+
+        let store = Box::new(crate::storage::FileStore::open(&dir).unwrap());
+        let mut blockchain = Blockchain::with_store(2, &mempool, store).unwrap();
+        let p1 = proposer_block(&blockchain, blockchain.get_proposer_tip(), 1);
+        let v1 = voter_block(&blockchain, 1, blockchain.get_voter_tip(1), vec![p1.hash()], 2);
+        // v1 arrives before the proposer it votes for and waits in the orphan buffer
+        blockchain.insert(&v1);
+        blockchain.insert(&p1);
+        blockchain.save_snapshot();
+        let p2 = proposer_block(&blockchain, p1.hash(), 3);
+        blockchain.insert(&p2);
+        let expected = blockchain.snapshot();
+        drop(blockchain);
+
+        let store = Box::new(crate::storage::FileStore::open(&dir).unwrap());
+        let blockchain = Blockchain::with_store(2, &mempool, store).unwrap();
+        assert_eq!(blockchain.proposer_depth, 3);
+        assert_eq!(blockchain.get_proposer_tip(), p2.hash());
+        assert_eq!(blockchain.get_voter_tip(1), v1.hash());
+        assert_eq!(blockchain.proposer2voterinfo[&p1.hash()], vec![(1, v1.hash())]);
+        assert_eq!(blockchain.level2allproposers, expected.level2allproposers);
+        assert_eq!(blockchain.chain2level, expected.chain2level);
+        assert!(blockchain.orphan_buffer.is_empty());
+        drop(blockchain);
+
+        // a store created for a different number of voter chains is rejected
+        let store = Box::new(crate::storage::FileStore::open(&dir).unwrap());
+        assert!(Blockchain::with_store(3, &mempool, store).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn expired_orphans_are_dropped() {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let mut blockchain = Blockchain::new(1, &mempool);
+        let p1 = proposer_block(&blockchain, blockchain.get_proposer_tip(), 1);
+        let v1 = voter_block(&blockchain, 1, blockchain.get_voter_tip(1), vec![p1.hash()], 2);
+        blockchain.insert(&v1);
+        // receiving the orphan again doesn't buffer it twice
+        blockchain.insert(&v1);
+        assert_eq!(blockchain.orphan_buffer[&p1.hash()].len(), 1);
+        assert_eq!(blockchain.missing_blocks(), vec![p1.hash()]);
+
+        blockchain.prune_orphans(Instant::now() + ORPHAN_EXPIRY);
+        assert!(blockchain.orphan_buffer.is_empty());
+        assert!(!blockchain.has_block(v1.hash()));
+
+        // a dropped orphan is buffered again when it is received again
+        blockchain.insert(&v1);
+        assert!(blockchain.has_block(v1.hash()));
+        blockchain.insert(&p1);
+        assert_eq!(blockchain.get_voter_tip(1), v1.hash());
+        assert!(blockchain.missing_blocks().is_empty());
+
+        // beyond MAX_ORPHAN_BLOCKS the oldest orphans are dropped
+        let missing = proposer_block(&blockchain, blockchain.get_proposer_tip(), 3);
+        let orphans: Vec<Block> = (0..MAX_ORPHAN_BLOCKS as u32 + 1)
+            .map(|nonce| voter_block(&blockchain, 1, v1.hash(), vec![missing.hash()], nonce))
+            .collect();
+        for orphan in &orphans {
+            blockchain.insert(orphan);
+        }
+        assert_eq!(blockchain.orphan_buffer[&missing.hash()].len(), MAX_ORPHAN_BLOCKS);
+        assert_eq!(orphans.iter().filter(|orphan| blockchain.has_block(orphan.hash())).count(), MAX_ORPHAN_BLOCKS);
+    }
+
+    #[test]
+    fn invalid_orphans_are_not_stored() {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let mut blockchain = Blockchain::new(1, &mempool);
+        let p1 = proposer_block(&blockchain, blockchain.get_proposer_tip(), 1);
+        // child of p1 minting its coinbase at the wrong level
+        let coinbase = CoinbaseTransaction::new(5, p1.hash(), crate::utxo::miner_address(0), crate::utxo::BLOCK_REWARD);
+        let content = ProposerContent { parent_hash: p1.hash(), coinbase, transactions: vec![], proposer_refs: vec![] };
+        let difficulty = p1.header.difficulty;
+        let bad = Block::new(p1.hash(), 0, 2, [0; 32].into(), vec![], Content::Proposer(content), 0, difficulty);
+
+        assert!(matches!(blockchain.insert(&bad), InsertStatus::Orphan));
+        assert!(blockchain.has_block(bad.hash()));
+        assert!(blockchain.get_block(bad.hash()).is_none());
+        blockchain.insert(&p1);
+        assert_eq!(blockchain.get_proposer_tip(), p1.hash());
+        assert!(!blockchain.has_block(bad.hash()));
+        assert!(blockchain.get_block(bad.hash()).is_none());
+    }
+
+    #[test]
+    fn voter_chain_reorg() {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let mut blockchain = Blockchain::new(1, &mempool);
+        let genesis = blockchain.get_voter_tip(1);
+        let p1 = proposer_block(&blockchain, blockchain.get_proposer_tip(), 1);
+        blockchain.insert(&p1);
+        let p2 = proposer_block(&blockchain, p1.hash(), 2);
+        blockchain.insert(&p2);
+
+        // main chain: genesis <- a (votes p1, p2)
+        let a = voter_block(&blockchain, 1, genesis, vec![p1.hash(), p2.hash()], 3);
+        blockchain.insert(&a);
+        assert_eq!(blockchain.proposer2votecount[&p2.hash()], 1);
+        assert_eq!(blockchain.chain2level[&1], 3);
+        assert!(blockchain.get_votes(1).is_empty());
+
+        // fork of the same length does not count
+        let b = voter_block(&blockchain, 1, genesis, vec![p1.hash()], 4);
+        blockchain.insert(&b);
+        assert_eq!(blockchain.get_voter_tip(1), a.hash());
+        assert_eq!(blockchain.proposer2votecount[&p1.hash()], 1);
+        assert_eq!(blockchain.proposer2voterinfo[&p1.hash()], vec![(1, a.hash())]);
+
+        // fork becomes longer: votes of a are retracted
+        let c = voter_block(&blockchain, 1, b.hash(), vec![], 5);
+        blockchain.insert(&c);
+        assert_eq!(blockchain.get_voter_tip(1), c.hash());
+        assert_eq!(blockchain.voter_depths[0], 3);
+        assert_eq!(blockchain.proposer2votecount[&p1.hash()], 1);
+        assert_eq!(blockchain.proposer2voterinfo[&p1.hash()], vec![(1, b.hash())]);
+        assert_eq!(blockchain.proposer2votecount[&p2.hash()], 0);
+        assert!(blockchain.proposer2voterinfo[&p2.hash()].is_empty());
+        assert_eq!(blockchain.chain2level[&1], 2);
+        assert_eq!(blockchain.get_votes(1), vec![p2.hash()]);
+    }
+
+    #[test]
+    fn difficulty_retarget() {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let mut blockchain = Blockchain::new(1, &mempool);
+        let initial = miner::get_difficulty(1);
+
+        // one epoch of proposer blocks mined twice as fast as targeted
+        let mut tip = blockchain.get_proposer_tip();
+        for i in 0..DIFFICULTY_EPOCH {
+            assert_eq!(blockchain.get_difficulty(&tip), Some(initial));
+            let block = proposer_block_at(&blockchain, tip, i as u128 * TARGET_PROPOSER_INTERVAL / 2, i);
+            blockchain.insert(&block);
+            tip = block.hash();
+        }
+        assert_eq!(blockchain.proposer_depth, DIFFICULTY_EPOCH + 1);
+        let expected_span = (DIFFICULTY_EPOCH - 1) as u128 * TARGET_PROPOSER_INTERVAL;
+        let harder = miner::retarget_difficulty(initial, expected_span / 2, expected_span);
+        assert!(harder < initial);
+        assert_eq!(blockchain.get_difficulty(&tip), Some(harder));
+
+        // a block still carrying the old difficulty is rejected
+        let content = ProposerContent { parent_hash: tip, coinbase: coinbase(&blockchain, tip), transactions: vec![], proposer_refs: vec![] };
+        let timestamp = DIFFICULTY_EPOCH as u128 * TARGET_PROPOSER_INTERVAL / 2;
+        let stale = Block::new(tip, timestamp, 0, [0; 32].into(), vec![], Content::Proposer(content), 0, initial);
+        assert!(matches!(blockchain.insert(&stale), InsertStatus::Invalid));
+        assert!(!blockchain.has_block(stale.hash()));
+
+        let block = proposer_block_at(&blockchain, tip, timestamp, 0);
+        assert!(matches!(blockchain.insert(&block), InsertStatus::Valid));
+        assert_eq!(blockchain.get_difficulty(&block.hash()), Some(harder));
+    }
+
+    #[test]
+    fn proposer_timestamps_are_checked() {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let mut blockchain = Blockchain::new(1, &mempool);
+        let p1 = proposer_block_at(&blockchain, blockchain.get_proposer_tip(), 1_000, 1);
+        assert!(matches!(blockchain.insert(&p1), InsertStatus::Valid));
+
+        // older than its parent
+        let older = proposer_block_at(&blockchain, p1.hash(), 999, 2);
+        assert!(matches!(blockchain.insert(&older), InsertStatus::Invalid));
+        // too far in the future
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_micros();
+        let future = proposer_block_at(&blockchain, p1.hash(), now + 2 * crate::validation::MAX_FUTURE_BLOCK_TIME, 3);
+        assert!(matches!(blockchain.insert(&future), InsertStatus::Invalid));
+
+        let same_time = proposer_block_at(&blockchain, p1.hash(), 1_000, 4);
+        assert!(matches!(blockchain.insert(&same_time), InsertStatus::Valid));
+        assert_eq!(blockchain.get_proposer_tip(), same_time.hash());
+    }
+
+    #[test]
+    fn retarget_is_bounded() {
+        // at most four times harder per epoch
+        let initial = miner::get_difficulty(1);
+        let bounded = miner::retarget_difficulty(initial, 1, 1_000_000);
+        let quarter = U256::from_big_endian(initial.as_ref()) / 1_000_000u64.into() * 250_000u64.into();
+        assert_eq!(U256::from_big_endian(bounded.as_ref()), quarter);
+        // never easier than the maximum target
+        let easiest = miner::retarget_difficulty([255; 32].into(), 10, 1);
+        assert_eq!(easiest, [255; 32].into());
     }
 }
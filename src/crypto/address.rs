@@ -2,6 +2,7 @@ use serde::{Serialize,Deserialize};
 use ring::signature::{Ed25519KeyPair, KeyPair};
 use ring::digest;
 use rand::Rng;
+use std::convert::TryInto;
 
 //Last 20 bytes of Public Key - used in tx
 #[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
@@ -17,6 +18,20 @@ impl std::fmt::Debug for H160 {
     }
 }
 
+impl std::str::FromStr for H160 {
+    type Err = String;
+
+    // Parse an address from its 40 character hex encoding
+    fn from_str(s: &str) -> Result<H160, String> {
+        let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+        let bytes: [u8; 20] = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| format!("expected 20 bytes, got {}", bytes.len()))?;
+        Ok(H160(bytes))
+    }
+}
+
 impl std::convert::AsRef<[u8]> for H160 {
     fn as_ref(&self) -> &[u8] {
         &self.0
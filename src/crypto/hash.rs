@@ -46,6 +46,20 @@ impl std::fmt::Debug for H256 {
     }
 }
 
+impl std::str::FromStr for H256 {
+    type Err = String;
+
+    /// Parse a hash from its 64 character hex encoding, as printed by `Display`.
+    fn from_str(s: &str) -> Result<H256, String> {
+        let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+        let bytes: [u8; 32] = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| format!("expected 32 bytes, got {}", bytes.len()))?;
+        Ok(H256(bytes))
+    }
+}
+
 impl std::convert::AsRef<[u8]> for H256 {
     fn as_ref(&self) -> &[u8] {
         &self.0
@@ -58,7 +58,8 @@ impl MerkleTree {
     }
 
     pub fn root(&self) -> H256 {
-        self.hashes[self.hashes.len()-1]
+        // an empty tree, e.g. of a voter block without votes, has the all zero root
+        self.hashes.last().copied().unwrap_or_default()
     }
 
     /// Returns the Merkle Proof of data at index i
@@ -151,6 +152,12 @@ mod tests {
         // notice that the order of these two matters
     }
 
+    #[test]
+    fn empty_tree_root() {
+        let merkle_tree = MerkleTree::new::<H256>(&[]);
+        assert_eq!(merkle_tree.root(), H256::default());
+    }
+
     #[test]
     fn proof() {
         let input_data: Vec<H256> = gen_merkle_tree_data!();
@@ -0,0 +1,176 @@
+use crate::crypto::hash::H256;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+use stderrlog::StdErrLog;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Log target of the structured events, records with this target only go to the event log
+pub const EVENT_TARGET: &str = "prism::events";
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockKind {
+    Proposer,
+    Voter,
+}
+
+// Events of interest for experiment analysis, one JSON object per line in the event log
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event")]
+pub enum Event {
+    BlockMined {
+        #[serde(serialize_with = "hex")]
+        hash: H256,
+        kind: BlockKind,
+        level: u32,
+    },
+    // a new block arrived from a peer
+    BlockReceived {
+        #[serde(serialize_with = "hex")]
+        hash: H256,
+        peer: String,
+    },
+    // a block got attached to the DAG, orphans only once their parents are known
+    BlockInserted {
+        #[serde(serialize_with = "hex")]
+        hash: H256,
+        kind: BlockKind,
+        level: u32,
+    },
+    // a transaction entered the mempool
+    TxReceived {
+        #[serde(serialize_with = "hex")]
+        hash: H256,
+    },
+    TxConfirmed {
+        #[serde(serialize_with = "hex")]
+        hash: H256,
+        level: u32,
+    },
+    TxRejected {
+        #[serde(serialize_with = "hex")]
+        hash: H256,
+        reason: String,
+    },
+    LeaderConfirmed {
+        level: u32,
+        #[serde(serialize_with = "hex")]
+        hash: H256,
+    },
+    LeaderRolledBack {
+        level: u32,
+        #[serde(serialize_with = "hex")]
+        hash: H256,
+    },
+    PeerConnected {
+        addr: String,
+        outgoing: bool,
+    },
+    PeerDisconnected {
+        addr: String,
+    },
+    PeerBanned {
+        addr: String,
+        reason: String,
+    },
+}
+
+fn hex<S: serde::Serializer>(hash: &H256, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(hash)
+}
+
+#[derive(Serialize)]
+struct EventRecord<'a> {
+    // microseconds since the unix epoch, same unit as block timestamps
+    timestamp: u128,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+// Write an event to the event log, a no-op if no event log is configured
+pub fn emit(event: Event) {
+    if log::log_enabled!(target: EVENT_TARGET, Level::Info) {
+        let record = EventRecord {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros(),
+            event: &event,
+        };
+        log::info!(target: EVENT_TARGET, "{}", serde_json::to_string(&record).unwrap());
+    }
+}
+
+// Sends regular log records to stderr and events to the event log file
+struct Logger {
+    stderr: StdErrLog,
+    events: Option<Mutex<LineWriter<File>>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        if metadata.target() == EVENT_TARGET {
+            self.events.is_some()
+        } else {
+            self.stderr.enabled(metadata)
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if record.target() != EVENT_TARGET {
+            self.stderr.log(record);
+        } else if let Some(events) = &self.events {
+            let _ = writeln!(events.lock().unwrap(), "{}", record.args());
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+        if let Some(events) = &self.events {
+            let _ = events.lock().unwrap().flush();
+        }
+    }
+}
+
+// Install the logger. Events are appended to `event_log` if given and dropped otherwise.
+pub fn init_logger<P: AsRef<Path>>(verbosity: usize, event_log: Option<P>) -> io::Result<()> {
+    let level = match verbosity {
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Warn,
+        2 => LevelFilter::Info,
+        3 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let events = match event_log {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Some(Mutex::new(LineWriter::new(file)))
+        }
+        None => None,
+    };
+    let max_level = if events.is_some() { std::cmp::max(level, LevelFilter::Info) } else { level };
+    let mut stderr = stderrlog::new();
+    stderr.verbosity(verbosity);
+    log::set_max_level(max_level);
+    log::set_boxed_logger(Box::new(Logger { stderr, events }))
+        .map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_tagged_json() {
+        let event = Event::TxConfirmed { hash: H256::from([1u8; 32]), level: 3 };
+        let record = EventRecord { timestamp: 42, event: &event };
+        let json: serde_json::Value = serde_json::from_str(&serde_json::to_string(&record).unwrap()).unwrap();
+        assert_eq!(json["event"], "TxConfirmed");
+        assert_eq!(json["timestamp"], 42);
+        assert_eq!(json["level"], 3);
+        assert_eq!(json["hash"], H256::from([1u8; 32]).to_string());
+    }
+}
@@ -1,50 +1,168 @@
 use crate::crypto::hash::{H256, Hashable};
 use crate::blockchain::Blockchain;
+use crate::mempool::TransactionMempool;
 use crate::block::Content;
-use crate::transaction::SignedTransaction;
-use crate::utxo::UtxoState;
+use crate::transaction::{SignedTransaction, CoinbaseTransaction};
+use crate::utxo::{UtxoState, TxUndo, BLOCK_REWARD};
+use crate::validation::transaction::{check_tx, TxError};
+use crate::metrics::metrics;
+use crate::events::{emit, Event};
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::thread;
-use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use std::time::Duration;
 use std::sync::{Arc, Mutex};
+use std::cmp;
 
 use statrs::distribution::{Discrete, Poisson, Univariate};
 
-use log::debug;
+use log::{debug, info, warn};
+
+// Number of most recently confirmed levels which can be rolled back
+pub const MAX_UNDO_LEVELS: usize = 256;
+
+// Everything done to the ledger when confirming the leader of one level
+pub struct LevelUndo {
+    pub level: u32,
+    pub leader: H256,
+    // proposer blocks marked as processed at this level
+    pub proposer_blocks: Vec<H256>,
+    // confirmed transactions in the order they were applied
+    pub txs: Vec<TxUndo>,
+}
+
+// Contents of a proposer block the ledger processes
+struct BlockTxs {
+    level: u32,
+    coinbase: CoinbaseTransaction,
+    transactions: Vec<SignedTransaction>,
+}
 
 //state required by ledger-manager
 pub struct LedgerManagerState {
     pub last_level_processed: u32,
     pub leader_sequence: Vec<H256>,
     pub proposer_blocks_processed: HashSet<H256>,
-    pub tx_confirmed: HashSet<H256>,
     pub tx_count: usize,
+    // undo records of the last MAX_UNDO_LEVELS confirmed levels, oldest first
+    pub undo_log: VecDeque<LevelUndo>,
 }
 
 //ledger-manager will periodically loop and confirm the transactions 
 pub struct LedgerManager {
     pub ledger_manager_state: LedgerManagerState,
     pub blockchain: Arc<Mutex<Blockchain>>,
+    pub mempool: Arc<Mutex<TransactionMempool>>,
     pub utxo_state: Arc<Mutex<UtxoState>>,
-    pub voter_depth_k: u32,
+    // copy of the leader sequence shared with the API, updated after every pass
+    pub leaders: Arc<Mutex<Vec<H256>>>,
+    // error probability tolerated when confirming a leader
+    pub epsilon: f64,
+    // fraction of the mining power assumed to be adversarial
+    pub adversary_ratio: f64,
+}
+
+// Probability that a vote `depth` blocks deep in its voter chain gets reversed by an adversary
+// holding `beta` of the mining power, i.e. the private chain catches up (Nakamoto's analysis
+// with the Poisson approximation of the adversary's progress)
+pub fn vote_reversal_prob(depth: u32, beta: f64) -> f64 {
+    if beta <= 0.0 {
+        return 0.0;
+    }
+    if depth == 0 {
+        return 1.0;
+    }
+    let ratio = beta / (1.0 - beta);
+    let adversary_blocks = Poisson::new(depth as f64 * ratio).unwrap();
+    let mut prob = 1.0;
+    for k in 0..(depth + 1) {
+        prob -= adversary_blocks.pmf(k as u64) * (1.0 - ratio.powi((depth - k) as i32));
+    }
+    prob.clamp(0.0, 1.0)
+}
+
+// Upper bound, holding with probability at least 1 - epsilon, on how many of the votes
+// with the given reversal probabilities get reversed. The number of reversed votes is
+// approximated by a Poisson variable with the same mean.
+fn reversed_votes_bound(reversal_probs: &[f64], epsilon: f64) -> u32 {
+    let mean: f64 = reversal_probs.iter().sum();
+    if mean <= 0.0 {
+        return 0;
+    }
+    let reversed = Poisson::new(mean).unwrap();
+    let max_votes = reversal_probs.len() as u32;
+    let mut bound = 0;
+    while bound < max_votes && reversed.cdf(bound as f64) < 1.0 - epsilon {
+        bound += 1;
+    }
+    bound
+}
+
+// Prism's list confirmation rule. `votes_depth` maps each proposer block of a level to the
+// depths of the votes it received. The proposer with the largest lower confidence bound on
+// its votes is confirmed once that bound exceeds the upper confidence bound of every other
+// proposer, including ones not seen yet which could still collect the votes of voter chains
+// that have not voted on this level.
+pub fn confirm_from_votes(
+    votes_depth: &HashMap<H256, Vec<u32>>,
+    num_voter_chains: u32,
+    epsilon: f64,
+    beta: f64,
+) -> Option<H256> {
+    let mut total_votes: u32 = 0;
+    let mut all_probs: Vec<f64> = Vec::new();
+    let mut lcbs: Vec<(H256, u32, u32)> = Vec::new();
+    for (proposer, depths) in votes_depth.iter() {
+        let probs: Vec<f64> = depths.iter().map(|depth| vote_reversal_prob(*depth, beta)).collect();
+        let num_votes = depths.len() as u32;
+        let lcb = num_votes - reversed_votes_bound(&probs, epsilon);
+        debug!("proposer {:?} votes {} lower bound {}", proposer, num_votes, lcb);
+        lcbs.push((*proposer, num_votes, lcb));
+        total_votes += num_votes;
+        all_probs.extend(probs);
+    }
+
+    // votes which could end up for any proposer
+    let undecided = num_voter_chains.saturating_sub(total_votes);
+    let swing = undecided + reversed_votes_bound(&all_probs, epsilon);
+
+    let (leader, _, leader_lcb) = *lcbs.iter().max_by_key(|(_, _, lcb)| *lcb)?;
+    let max_other_votes = lcbs.iter()
+        .filter(|(proposer, _, _)| *proposer != leader)
+        .map(|(_, num_votes, _)| *num_votes)
+        .max()
+        .unwrap_or(0);
+    if leader_lcb > max_other_votes + swing {
+        Some(leader)
+    } else {
+        None
+    }
 }
 
 impl LedgerManager {
-    pub fn new(blockchain: &Arc<Mutex<Blockchain>>, utxo_state: &Arc<Mutex<UtxoState>>, k: u32) -> Self {
+    pub fn new(
+        blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<TransactionMempool>>,
+        utxo_state: &Arc<Mutex<UtxoState>>,
+        epsilon: f64,
+        adversary_ratio: f64,
+    ) -> Self {
         let ledger_manager_state = LedgerManagerState{
             last_level_processed: 1,
             proposer_blocks_processed: HashSet::new(),
             leader_sequence: Vec::new(),
-            tx_confirmed: HashSet::new(),
             tx_count: 0,
+            undo_log: VecDeque::new(),
         };
 
         LedgerManager {
             ledger_manager_state: ledger_manager_state,
             blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
             utxo_state: Arc::clone(utxo_state),
-            voter_depth_k: k,
+            leaders: Arc::new(Mutex::new(Vec::new())),
+            epsilon,
+            adversary_ratio,
         }
     }
 
@@ -57,30 +175,172 @@ impl LedgerManager {
         .unwrap();
     }
 
+    fn ledger_manager_loop(&mut self) {
+        loop{
+            self.update_ledger();
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    // One pass of the ledger manager, confirms the leaders of the levels which can be decided now
     //Three Steps
     //1. Get the leader sequence
     //2. Get Transaction sequence
     //3. Sanitize Tx and update UTXO state
-    //All 3 steps are done in the loop
+    //All 3 steps are done in each pass, after undoing levels whose leader changed
     //
-    fn ledger_manager_loop(&mut self) {
-        loop{
-            //Step 1
-            //let leader_sequence = self.get_leader_sequence();
-            
-            //This one uses the algorithm described in Prism Paper
-            let leader_sequence = self.get_confirmed_leader_sequence();
-            
+    pub fn update_ledger(&mut self) {
+        //Step 0
+        //Votes may have moved because of voter chain reorgs
+        self.rollback_changed_leaders();
+
+        //Step 1
+        //let leader_sequence = self.get_leader_sequence();
+        
+        //This one uses the algorithm described in Prism Paper
+        let leader_sequence = self.get_confirmed_leader_sequence();
+        let confirmed_any = !leader_sequence.is_empty();
+
+        for (level, leader) in leader_sequence {
             //Step 2
-            let tx_sequence = self.get_transaction_sequence(&leader_sequence);
-            
+            let (proposer_blocks, block_sequence) = self.get_transaction_sequence(&leader);
+
             //Step 3
-            self.confirm_transactions(&tx_sequence);
-            
-            thread::sleep(Duration::from_secs(1));
+            let timestamp = self.leader_timestamp(&leader);
+            let mut locked_utxostate = self.utxo_state.lock().unwrap();
+            locked_utxostate.confirmed_level = level;
+            locked_utxostate.confirmed_timestamp = timestamp;
+            drop(locked_utxostate);
+            metrics().confirmed_level.set(level as i64);
+            let mut txs: Vec<TxUndo> = Vec::new();
+            for block_txs in &block_sequence {
+                txs.append(&mut self.confirm_block(block_txs));
+            }
+
+            self.ledger_manager_state.undo_log.push_back(LevelUndo {
+                level,
+                leader,
+                proposer_blocks,
+                txs,
+            });
+            if self.ledger_manager_state.undo_log.len() > MAX_UNDO_LEVELS {
+                self.ledger_manager_state.undo_log.pop_front();
+            }
+        }
+
+        // mempool transactions may spend outputs confirmed by this pass, the mempool is
+        // locked before the state like in the API
+        if confirmed_any {
+            let mut locked_mempool = self.mempool.lock().unwrap();
+            locked_mempool.update_fees(&self.utxo_state.lock().unwrap());
+            drop(locked_mempool);
+        }
+
+        self.leaders.lock().unwrap().clone_from(&self.ledger_manager_state.leader_sequence);
+    }
+
+    // Find the oldest confirmed level whose leader is now a different proposer, or cannot
+    // be confirmed anymore, and roll the ledger back to the level before it
+    fn rollback_changed_leaders(&mut self) {
+        let confirmed: Vec<(u32, H256)> = self.ledger_manager_state.undo_log.iter()
+            .map(|undo| (undo.level, undo.leader))
+            .collect();
+        for (level, leader) in confirmed {
+            match self.confirm_leader(level) {
+                Some(new_leader) if new_leader == leader => {}
+                Some(new_leader) => {
+                    info!("Leader at level {} changed from {:?} to {:?}", level, leader, new_leader);
+                    self.rollback(level - 1);
+                    return;
+                }
+                None => {
+                    info!("Leader {:?} at level {} is no longer confirmed", leader, level);
+                    self.rollback(level - 1);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Undo all levels after `level`: their transactions are reverted in the UTXO state,
+    // go back to the mempool and their leaders will be confirmed again
+    pub fn rollback(&mut self, level: u32) {
+        let state = &mut self.ledger_manager_state;
+        if let Some(oldest) = state.undo_log.front() {
+            if oldest.level > level + 1 {
+                warn!("Cannot roll back to level {}, undo records start at level {}", level, oldest.level);
+                return;
+            }
+        }
+
+        // reverted levels, newest first
+        let mut reverted: Vec<LevelUndo> = Vec::new();
+        let mut locked_utxostate = self.utxo_state.lock().unwrap();
+        while state.undo_log.back().is_some_and(|undo| undo.level > level) {
+            let undo = state.undo_log.pop_back().unwrap();
+            for tx in undo.txs.iter().rev() {
+                locked_utxostate.revert_state(tx);
+            }
+            for proposer in &undo.proposer_blocks {
+                state.proposer_blocks_processed.remove(proposer);
+            }
+            state.leader_sequence.pop();
+            metrics().rolled_back_levels.inc();
+            emit(Event::LeaderRolledBack { level: undo.level, hash: undo.leader });
+            info!("Rolled back level {} with {} transactions", undo.level, undo.txs.len());
+            reverted.push(undo);
+        }
+        state.last_level_processed = cmp::min(state.last_level_processed, level);
+        locked_utxostate.confirmed_level = state.last_level_processed;
+        metrics().confirmed_level.set(state.last_level_processed as i64);
+        drop(locked_utxostate);
+
+        // time locks are evaluated against the last leader which is still confirmed
+        let last_leader = state.leader_sequence.last().copied();
+        let timestamp = last_leader.map_or(0, |leader| self.leader_timestamp(&leader));
+        self.utxo_state.lock().unwrap().confirmed_timestamp = timestamp;
+
+        reverted.reverse();
+        self.requeue_transactions(&reverted);
+    }
+
+    // Put the transactions confirmed in reverted levels back into the mempool, in the
+    // order they were confirmed so that they can be confirmed again by other leaders
+    fn requeue_transactions(&self, reverted: &[LevelUndo]) {
+        let locked_blockchain = self.blockchain.lock().unwrap();
+        let mut txs: Vec<SignedTransaction> = Vec::new();
+        for undo in reverted {
+            let confirmed: HashSet<H256> = undo.txs.iter().map(|tx| tx.tx_hash).collect();
+            for proposer in &undo.proposer_blocks {
+                if let Content::Proposer(content) = &locked_blockchain.proposer_chain[proposer].block.content {
+                    txs.extend(content.transactions.iter().filter(|tx| confirmed.contains(&tx.hash())).cloned());
+                }
+            }
+        }
+        drop(locked_blockchain);
+
+        // same lock order as the API, the mempool before the state
+        let mut locked_mempool = self.mempool.lock().unwrap();
+        let locked_utxostate = self.utxo_state.lock().unwrap();
+        for tx in txs {
+            if locked_mempool.contains(&tx.hash()) || locked_mempool.conflicting_input(&tx).is_some() {
+                continue;
+            }
+            match check_tx(&locked_utxostate, &tx) {
+                Ok(fee) => locked_mempool.insert(tx, fee),
+                // spends the outputs of another reverted transaction
+                Err(TxError::MissingInput(_)) => locked_mempool.insert_unpriced(tx),
+                Err(e) => debug!("Dropping reverted transaction {:?}: {}", tx.hash(), e),
+            }
         }
     }
 
+    // Timestamp of a confirmed leader, used by time locked outputs
+    fn leader_timestamp(&self, leader: &H256) -> u128 {
+        let locked_blockchain = self.blockchain.lock().unwrap();
+        locked_blockchain.proposer_chain[leader].block.header.timestamp
+    }
+
     fn get_leader_sequence(&mut self) -> Vec<H256> {
         let locked_blockchain = self.blockchain.lock().unwrap();
         
@@ -113,18 +373,18 @@ impl LedgerManager {
                 break;
             }
 
-            println!("Adding leader at level {}, leader hash: {:?}, max votes: {}", level, leader, max_vote_count);
+            debug!("Adding leader at level {}, leader hash: {:?}, max votes: {}", level, leader, max_vote_count);
+            emit(Event::LeaderConfirmed { level, hash: leader });
             leader_sequence.push(leader);
             self.ledger_manager_state.leader_sequence.push(leader);
-            println!("Leader sequence: {:?}", self.ledger_manager_state.leader_sequence);
             self.ledger_manager_state.last_level_processed = level;
         }
 
         leader_sequence
     }
     
-    fn get_confirmed_leader_sequence(&mut self) -> Vec<H256> {
-        let mut leader_sequence: Vec<H256> = vec![];
+    fn get_confirmed_leader_sequence(&mut self) -> Vec<(u32, H256)> {
+        let mut leader_sequence: Vec<(u32, H256)> = vec![];
 
         //Locking Blockchain to get proposer_depth currently. Then dropping the lock
         //Will be holding locj for each level processing inside the subroutine
@@ -140,16 +400,15 @@ impl LedgerManager {
 
             match leader {
                 Some(leader_hash) => {  
-                    println!("Adding leader at level {}, leader hash: {:?}", level, leader_hash);
-                    leader_sequence.push(leader_hash);
-                    // self.ledger_manager_state.leader_sequence.push(leader_hash);
+                    emit(Event::LeaderConfirmed { level, hash: leader_hash });
+                    leader_sequence.push((level, leader_hash));
+                    self.ledger_manager_state.leader_sequence.push(leader_hash);
                     // println!("Leader sequence: {:?}", self.ledger_manager_state.leader_sequence);
                     self.ledger_manager_state.last_level_processed = level;
                 }
 
                 None => {
-                    println!("Unable to confirm leader at level {}", level);
-                    println!("Returning from get_confirmed_leader_sequence func");
+                    debug!("Unable to confirm leader at level {}", level);
                     break; // TODO: Will this break out of loop??
                 }
             }
@@ -165,66 +424,44 @@ impl LedgerManager {
 
         let locked_blockchain = self.blockchain.lock().unwrap();
         
-        let proposer_blocks = &locked_blockchain.level2allproposers[&level];
-        let mut new_leader: Option<H256> = None;
-
+        let proposer_blocks = locked_blockchain.level2allproposers.get(&level)?;
         let num_voter_chains: u32 = locked_blockchain.num_voter_chains;
 
-        // for each proposer count the number of confirmed votes i.e. votes that are k-deep (2-deep).
-        let mut num_confirmed_votes: HashMap<H256, u32> = HashMap::new(); 
-
+        // for each proposer collect the depth of each of its votes in the voter chains
+        let mut votes_depth: HashMap<H256, Vec<u32>> = HashMap::new();
         for block in proposer_blocks {
-            if locked_blockchain.proposer2voterinfo.contains_key(block) {
-                
-                //TODO: We might also need number of voter blocks at a particular level of a voter chain
-                //This is not urgent as we can **assume**, there is one block at each level
-                let voters_info = &locked_blockchain.proposer2voterinfo[block];
-                if voters_info.len() < (num_voter_chains as usize / 2) {
-                    println!("number of votes for {:?} is {}", block, voters_info.len());
-                    continue;
-                }
-
-                let mut total_k_deep_votes: u32 = 0;
+            if let Some(voters_info) = locked_blockchain.proposer2voterinfo.get(block) {
+                let mut depths: Vec<u32> = Vec::new();
                 for (voter_chain, voter_block) in voters_info {
-
                     let voter_block_level = locked_blockchain.voter_chains[(*voter_chain-1) as usize][voter_block].level;
                     let voter_chain_level = locked_blockchain.voter_depths[(*voter_chain-1) as usize];
-                    
-                    let this_vote_depth = voter_chain_level - voter_block_level;
-                    if this_vote_depth >= self.voter_depth_k {
-                        total_k_deep_votes += 1;
-                    }
+                    // the voter block itself counts, a vote in the tip is 1-deep
+                    depths.push(voter_chain_level - voter_block_level + 1);
                 }
-                num_confirmed_votes.insert(*block, total_k_deep_votes);
-            }
-        }
-
-        for (proposer, votes) in  num_confirmed_votes.iter() {
-            println!("proposer {:?}  votes {}", proposer, *votes);
-            if *votes > (num_voter_chains / 2) {
-                new_leader = Some(*proposer);
-                break;
+                votes_depth.insert(*block, depths);
             }
         }
+        drop(locked_blockchain);
 
-        new_leader
+        confirm_from_votes(&votes_depth, num_voter_chains, self.epsilon, self.adversary_ratio)
     }
 
     // needs to process parent as well
-    fn get_transaction_sequence(&mut self, leader_sequence: &Vec<H256>) -> Vec<SignedTransaction> {        
+    // Returns the proposer blocks newly marked as processed and their contents
+    fn get_transaction_sequence(&mut self, leader: &H256) -> (Vec<H256>, Vec<BlockTxs>) {
         let locked_blockchain = self.blockchain.lock().unwrap();
 
-        let mut tx_sequence: Vec<SignedTransaction> = Vec::new();
+        let mut block_sequence: Vec<BlockTxs> = Vec::new();
+        let mut processed: Vec<H256> = Vec::new();
 
         //TODO: Should we do it recusrively? Like should we also see references to
         //proposer references of leader?
         //TODO: Also we should refactor it later
-        for leader in leader_sequence {
+        {
             let leader_block = &locked_blockchain.proposer_chain[leader].block;
 
             //processing parent and proposer refs
             let mut proposer_refs_to_process: Vec<H256> = Vec::new();
-            let mut leader_txs: Vec<SignedTransaction> = Vec::new();
             match &leader_block.content {
                 Content::Proposer(content) => {
                     // parent and proposer_refs of leader
@@ -240,61 +477,234 @@ impl LedgerManager {
                             proposer_refs_to_process.push(*proposer_ref);
                         }
                     }
-
-                    //txs of leader
-                    leader_txs = content.transactions.clone(); 
                 }
                 _ => {
 
                 }
             }
 
-            //TODO: Do we have to do match in this and previous loop as we know it will always
-            //match to Proposer(content). Can we unwrap??
-            for proposer_ref in &proposer_refs_to_process {
-                let proposer_block = &locked_blockchain.proposer_chain[proposer_ref].block;
-                match &proposer_block.content {
-                    Content::Proposer(content) => {
-                        tx_sequence.append(&mut content.transactions.clone()); 
-                    }
-                    _ => {
+            //leader is processed last
+            proposer_refs_to_process.push(*leader);
 
-                    }
-                }             
-                
-                self.ledger_manager_state.proposer_blocks_processed.insert(*proposer_ref);
+            for proposer_ref in &proposer_refs_to_process {
+                if !self.ledger_manager_state.proposer_blocks_processed.insert(*proposer_ref) {
+                    continue;
+                }
+                processed.push(*proposer_ref);
+
+                let metablock = &locked_blockchain.proposer_chain[proposer_ref];
+                if let Content::Proposer(content) = &metablock.block.content {
+                    block_sequence.push(BlockTxs {
+                        level: metablock.level,
+                        coinbase: content.coinbase.clone(),
+                        transactions: content.transactions.clone(),
+                    });
+                }
             }
-
-            //appending leader txs finally
-            //adding leader to proposer_blocks_processed
-            tx_sequence.append(&mut leader_txs);
-            self.ledger_manager_state.proposer_blocks_processed.insert(*leader);
         }
 
-        tx_sequence
+        (processed, block_sequence)
     }
 
-    fn confirm_transactions(&mut self, tx_sequence: &Vec<SignedTransaction>) {
+    // Confirm the transactions of a proposer block, then mint its reward and the fees
+    // it collected. Returns the undo records in the order they were applied.
+    fn confirm_block(&mut self, block_txs: &BlockTxs) -> Vec<TxUndo> {
+        let (mut undo_records, fees) = self.confirm_transactions(&block_txs.transactions);
+
+        // e.g. the genesis block mints nothing
+        if block_txs.coinbase.tx_output.is_empty() {
+            return undo_records;
+        }
+        // transactions confirmed by an earlier block or rejected pay no fee to this one,
+        // the reward is minted anyway
+        let max_value = BLOCK_REWARD as u64 + fees;
+        if block_txs.coinbase.value() > max_value {
+            warn!("Coinbase {:?} claims {}, minting only the reward and {} fees", block_txs.coinbase.hash(), block_txs.coinbase.value(), fees);
+        }
+        let mut locked_utxostate = self.utxo_state.lock().unwrap();
+        if locked_utxostate.is_coinbase_valid(&block_txs.coinbase, block_txs.level) {
+            undo_records.push(locked_utxostate.apply_coinbase(&block_txs.coinbase, max_value));
+        } else {
+            warn!("Ignoring invalid coinbase {:?} at level {}", block_txs.coinbase.hash(), block_txs.level);
+        }
+        drop(locked_utxostate);
+        undo_records
+    }
+
+    // Returns the undo records of the transactions which got confirmed and their total fee
+    fn confirm_transactions(&mut self, tx_sequence: &Vec<SignedTransaction>) -> (Vec<TxUndo>, u64) {
+        let mut undo_records: Vec<TxUndo> = Vec::new();
+        let mut fees: u64 = 0;
         self.ledger_manager_state.tx_count += tx_sequence.len();
         // println!("Number of transactions considered yet {}", self.ledger_manager_state.tx_count);
         let mut locked_utxostate = self.utxo_state.lock().unwrap();
         for tx in tx_sequence {
             //if already processed continue
-            if self.ledger_manager_state.tx_confirmed.contains(&tx.hash()) {
-                println!("DUPLICATE TXS! Already confirmed");
+            if locked_utxostate.confirmed_txs.contains_key(&tx.hash()) {
+                debug!("Transaction {:?} already confirmed", tx.hash());
                 continue;
             }
 
             //check for validity
             //if valid, update utxo_state and add to confirmed transactions
-            if locked_utxostate.is_tx_valid(tx){
-                locked_utxostate.update_state(tx);
-                self.ledger_manager_state.tx_confirmed.insert(tx.hash());
-                println!("Confirmed trans hash {} at {}", tx.hash(), SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros());
-                // Print UTXO state
-                // locked_utxostate.print();
+            match check_tx(&locked_utxostate, tx) {
+                Ok(fee) => {
+                    fees += fee;
+                    undo_records.push(locked_utxostate.update_state(tx));
+                    metrics().tx_confirmed(&tx.hash());
+                    emit(Event::TxConfirmed { hash: tx.hash(), level: locked_utxostate.confirmed_level });
+                    // Print UTXO state
+                    // locked_utxostate.print();
+                }
+                Err(e) => {
+                    emit(Event::TxRejected { hash: tx.hash(), reason: e.to_string() });
+                    metrics().rejected_txs.inc();
+                }
             }
         }
         drop(locked_utxostate);
+        (undo_records, fees)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proposer(byte: u8) -> H256 {
+        [byte; 32].into()
+    }
+
+    #[test]
+    fn reversal_prob_decreases_with_depth() {
+        assert_eq!(vote_reversal_prob(0, 0.3), 1.0);
+        assert_eq!(vote_reversal_prob(5, 0.0), 0.0);
+        let mut last = 1.0;
+        for depth in 1..20 {
+            let prob = vote_reversal_prob(depth, 0.3);
+            assert!(prob < last);
+            last = prob;
+        }
+        assert!(vote_reversal_prob(20, 0.1) < 1e-6);
+    }
+
+    #[test]
+    fn confirm_with_deep_votes() {
+        let mut votes_depth = HashMap::new();
+        votes_depth.insert(proposer(1), vec![1; 40]);
+        // votes in the tips can still be reversed
+        assert_eq!(confirm_from_votes(&votes_depth, 40, 1e-3, 0.3), None);
+
+        votes_depth.insert(proposer(1), vec![5; 40]);
+        assert_eq!(confirm_from_votes(&votes_depth, 40, 1e-3, 0.3), Some(proposer(1)));
+        // a lower error probability requires deeper votes
+        assert_eq!(confirm_from_votes(&votes_depth, 40, 1e-12, 0.3), None);
+    }
+
+    #[test]
+    fn no_confirmation_when_undecided() {
+        let mut votes_depth = HashMap::new();
+        // half of the voter chains have not voted yet
+        votes_depth.insert(proposer(1), vec![10; 20]);
+        assert_eq!(confirm_from_votes(&votes_depth, 40, 1e-3, 0.3), None);
+
+        // split votes
+        votes_depth.insert(proposer(2), vec![10; 20]);
+        assert_eq!(confirm_from_votes(&votes_depth, 40, 1e-3, 0.3), None);
+
+        // clear majority
+        votes_depth.insert(proposer(1), vec![10; 34]);
+        votes_depth.insert(proposer(2), vec![10; 6]);
+        assert_eq!(confirm_from_votes(&votes_depth, 40, 1e-3, 0.3), Some(proposer(1)));
+    }
+
+    #[test]
+    fn shared_transaction_pays_its_fee_once() {
+        use crate::crypto::address;
+        use crate::transaction::{Transaction, UtxoInput, UtxoOutput};
+        use ring::signature::KeyPair;
+
+        let key = crate::crypto::key_pair::random();
+        let owner = address::address_from_public_key_vec_ref(&key.public_key().as_ref().to_vec());
+        let input = UtxoInput{tx_hash: proposer(9), idx: 0};
+        let mut utxo_state = UtxoState::default();
+        utxo_state.state_map.insert(input.clone(), UtxoOutput::new(owner, 10));
+        let tx = Transaction {
+            tx_input: vec![input],
+            tx_output: vec![UtxoOutput::new(owner, 7)],
+        };
+        let signed_tx = SignedTransaction::new(tx, &[&key]);
+
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let blockchain = Arc::new(Mutex::new(Blockchain::new(1, &mempool)));
+        let utxo_state = Arc::new(Mutex::new(utxo_state));
+        let mut ledger_manager = LedgerManager::new(&blockchain, &mempool, &utxo_state, 1e-3, 0.3);
+
+        // both proposer blocks include the transaction and claim its fee of 3
+        let mut minted = vec![];
+        for level in 1..3 {
+            let block_txs = BlockTxs {
+                level,
+                coinbase: CoinbaseTransaction::new(level, proposer(level as u8), owner, BLOCK_REWARD + 3),
+                transactions: vec![signed_tx.clone()],
+            };
+            let undo_records = ledger_manager.confirm_block(&block_txs);
+            let coinbase = undo_records.last().unwrap();
+            assert_eq!(coinbase.tx_hash, block_txs.coinbase.hash());
+            minted.push(utxo_state.lock().unwrap().state_map[&coinbase.created[0]].value);
+        }
+        // the second block confirmed nothing, it still gets the block reward
+        assert_eq!(minted, vec![BLOCK_REWARD + 3, BLOCK_REWARD]);
+    }
+
+    #[test]
+    fn rolled_back_transactions_return_to_the_mempool() {
+        use crate::block::{Block, ProposerContent};
+        use crate::crypto::address;
+        use crate::transaction::{Transaction, UtxoInput, UtxoOutput};
+        use ring::signature::KeyPair;
+
+        let key = crate::crypto::key_pair::random();
+        let owner = address::address_from_public_key_vec_ref(&key.public_key().as_ref().to_vec());
+        let input = UtxoInput{tx_hash: proposer(9), idx: 0};
+        let mut utxo_state = UtxoState::default();
+        utxo_state.state_map.insert(input.clone(), UtxoOutput::new(owner, 10));
+        let tx = Transaction {
+            tx_input: vec![input],
+            tx_output: vec![UtxoOutput::new(owner, 7)],
+        };
+        let signed_tx = SignedTransaction::new(tx, &[&key]);
+
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let blockchain = Arc::new(Mutex::new(Blockchain::new(1, &mempool)));
+        let utxo_state = Arc::new(Mutex::new(utxo_state));
+        let mut ledger_manager = LedgerManager::new(&blockchain, &mempool, &utxo_state, 1e-3, 0.3);
+
+        let mut locked_blockchain = blockchain.lock().unwrap();
+        let parent = locked_blockchain.get_proposer_tip();
+        let level = locked_blockchain.proposer_chain[&parent].level + 1;
+        let content = ProposerContent {
+            parent_hash: parent,
+            coinbase: CoinbaseTransaction::new(level, parent, crate::utxo::miner_address(0), BLOCK_REWARD + 3),
+            transactions: vec![signed_tx.clone()],
+            proposer_refs: vec![],
+        };
+        let difficulty = locked_blockchain.get_difficulty(&parent).unwrap();
+        let block = Block::new(parent, 0, 0, [0; 32].into(), vec![], Content::Proposer(content), 0, difficulty);
+        locked_blockchain.insert(&block);
+        drop(locked_blockchain);
+
+        // confirm the block as the leader of its level, then revert it
+        let (proposer_blocks, block_sequence) = ledger_manager.get_transaction_sequence(&block.hash());
+        let mut txs = vec![];
+        for block_txs in &block_sequence {
+            txs.append(&mut ledger_manager.confirm_block(block_txs));
+        }
+        ledger_manager.ledger_manager_state.undo_log.push_back(LevelUndo { level, leader: block.hash(), proposer_blocks, txs });
+        assert!(!mempool.lock().unwrap().contains(&signed_tx.hash()));
+        ledger_manager.rollback(level - 1);
+
+        assert!(mempool.lock().unwrap().contains(&signed_tx.hash()));
+        assert!(!utxo_state.lock().unwrap().confirmed_txs.contains_key(&signed_tx.hash()));
     }
 }
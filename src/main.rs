@@ -14,6 +14,11 @@ pub mod tx_generator;
 pub mod validation;
 pub mod ledger_manager;
 pub mod utxo;
+pub mod storage;
+pub mod wallet;
+pub mod metrics;
+pub mod events;
+pub mod simulator;
 
 use clap::clap_app;
 use crossbeam::channel;
@@ -29,6 +34,20 @@ use crate::crypto::hash::{self, H256, Hashable};
 use crate::block::{*};
 use crate::utxo::{UtxoState};
 use std::collections::HashSet;
+use std::io;
+
+// Environment variable holding the keystore passphrase, it is asked on the terminal if not set
+const PASSPHRASE_VAR: &str = "PRISM_KEYSTORE_PASSPHRASE";
+
+// The passphrase of the keystore at `path`. It is never taken from the command line, where
+// it would show up in the process list and the shell history.
+fn read_passphrase(path: &str) -> io::Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_VAR) {
+        return Ok(passphrase);
+    }
+    // not echoed when stdin is a terminal
+    rpassword::prompt_password_stderr(&format!("Passphrase of keystore {}: ", path))
+}
 
 
 
@@ -41,15 +60,30 @@ fn main() {
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+     (@arg peers_file: --("peers-file") [FILE] "Sets the file the address book of known peers is saved to, it is kept in memory only if not set")
+     (@arg outbound: --outbound [INT] default_value("8") "Sets the number of outbound peers picked from the address book the server keeps connected")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
      (@arg voter_chains: --("voter-chains") [INT] default_value("40") "Sets the number of voter chains")
-     (@arg voter_depth_k: --("voter-depth-k") [INT] default_value("2") "Depth of votes before ledger manager can confirm")
+     (@arg confirm_epsilon: --("confirm-epsilon") [FLOAT] default_value("0.001") "Error probability tolerated when confirming a leader")
+     (@arg adversary_ratio: --("adversary-ratio") [FLOAT] default_value("0.3") "Fraction of mining power assumed to be adversarial when confirming a leader")
+     (@arg miner_threads: --("miner-threads") [INT] default_value("1") "Sets the number of nonce search threads of the miner")
+     (@arg keystore: --keystore [FILE] "Sets the encrypted keystore the transaction generator spends from, the ICO keys of the node are used if not set. Its passphrase is read from PRISM_KEYSTORE_PASSPHRASE, or asked on the terminal")
+     (@arg event_log: --("event-log") [FILE] "Appends structured events as JSON lines to the file, events are dropped if not set")
+     (@arg simulate: --simulate [NODES] "Runs a simulated network of NODES nodes in this process and prints a summary instead of starting a node")
+     (@arg sim_duration: --("sim-duration") [SECS] default_value("600") "Sets the virtual time the simulation runs for")
+     (@arg sim_latency: --("sim-latency") [MS] default_value("100") "Sets the one way latency of the simulated links")
+     (@arg sim_bandwidth: --("sim-bandwidth") [BYTES] default_value("0") "Sets the bandwidth of the simulated links in bytes per second, unlimited if 0")
+     (@arg seed: --seed [INT] default_value("0") "Sets the seed of the simulation")
+     (@arg db_path: --db [DIR] "Sets the directory of the persistent block store, blocks are kept in memory only if not set")
     )
     .get_matches();
 
     // init logger
     let verbosity = matches.occurrences_of("verbose") as usize;
-    stderrlog::new().verbosity(verbosity).init().unwrap();
+    if let Err(e) = events::init_logger(verbosity, matches.value_of("event_log")) {
+        eprintln!("Error opening event log: {}", e);
+        process::exit(1);
+    }
 
     // parse p2p server address
     let p2p_addr = matches
@@ -71,13 +105,6 @@ fn main() {
             process::exit(1);
         });
 
-    // create channels between server and worker
-    let (msg_tx, msg_rx) = channel::unbounded();
-
-    // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
-    server_ctx.start().unwrap();
-
     //INTMOD
     let num_chains = matches
     .value_of("voter_chains")
@@ -90,46 +117,162 @@ fn main() {
 
     info!("voter chains {}", num_chains);
 
-    let voter_depth_k = matches
-    .value_of("voter_depth_k")
+    let confirm_epsilon = matches
+    .value_of("confirm_epsilon")
     .unwrap()
-    .parse::<u32>()
+    .parse::<f64>()
     .unwrap_or_else(|e| {
-        error!("Error parsing voter depth k: {}", e);
+        error!("Error parsing confirmation epsilon: {}", e);
         process::exit(1);
     });
+    if !(confirm_epsilon > 0.0 && confirm_epsilon < 1.0) {
+        error!("Confirmation epsilon must be in (0, 1)");
+        process::exit(1);
+    }
+
+    let adversary_ratio = matches
+    .value_of("adversary_ratio")
+    .unwrap()
+    .parse::<f64>()
+    .unwrap_or_else(|e| {
+        error!("Error parsing adversary ratio: {}", e);
+        process::exit(1);
+    });
+    if !(0.0..0.5).contains(&adversary_ratio) {
+        error!("Adversary ratio must be in [0, 0.5)");
+        process::exit(1);
+    }
+
+    // run a simulated network in this process instead of a node
+    if matches.is_present("simulate") {
+        let parse = |name: &str| {
+            matches.value_of(name).unwrap().parse::<u64>().unwrap_or_else(|e| {
+                error!("Error parsing {}: {}", name, e);
+                process::exit(1);
+            })
+        };
+        let config = simulator::Config {
+            num_nodes: parse("simulate") as usize,
+            num_voter_chains: num_chains,
+            seed: parse("seed"),
+            block_interval: miner::TARGET_PROPOSER_INTERVAL as u64 / (num_chains as u64 + 1),
+            latency: parse("sim_latency") * 1000,
+            bandwidth: parse("sim_bandwidth"),
+            epsilon: confirm_epsilon,
+            adversary_ratio,
+            ..Default::default()
+        };
+        let mut sim = simulator::Simulator::new(config);
+        sim.run_for(parse("sim_duration") * 1_000_000);
+        sim.print_summary();
+        return;
+    }
+
+    // create channels between server and worker
+    let (msg_tx, msg_rx) = channel::unbounded();
+
+    // open the address book, the outbound peers are picked from it
+    let addr_book = match matches.value_of("peers_file") {
+        Some(path) => network::addrbook::AddressBook::open(path).unwrap_or_else(|e| {
+            error!("Error opening address book {}: {}", path, e);
+            process::exit(1);
+        }),
+        None => network::addrbook::AddressBook::new(),
+    };
+    info!("Address book has {} peers", addr_book.len());
+    let addr_book = Arc::new(Mutex::new(addr_book));
+    let target_outbound = matches
+        .value_of("outbound")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing outbound peers: {}", e);
+            process::exit(1);
+        });
+
+    let ban_list = Arc::new(Mutex::new(network::misbehavior::BanList::new()));
+
+    // start the p2p server
+    let version = network::handshake::local_version(rand::random(), num_chains, p2p_addr.port());
+    let (server_ctx, server) = server::new(p2p_addr, msg_tx, version, &addr_book, &ban_list, target_outbound).unwrap();
+    server_ctx.start().unwrap();
 
     let utxo_state = Arc::new(Mutex::new(UtxoState::new()));
 
     // create mempool
     let mempool = Arc::new(Mutex::new(mempool::TransactionMempool::new()));
 
-    // create blockchain
-    let blockchain = Arc::new(Mutex::new(blockchain::Blockchain::new(num_chains, &mempool)));
+    // create blockchain, restoring it from the block store if one is given
+    let block_store: Box<dyn storage::BlockStore> = match matches.value_of("db_path") {
+        Some(path) => Box::new(storage::FileStore::open(path).unwrap_or_else(|e| {
+            error!("Error opening block store {}: {}", path, e);
+            process::exit(1);
+        })),
+        None => Box::new(storage::MemoryStore::new()),
+    };
+    let blockchain = blockchain::Blockchain::with_store(num_chains, &mempool, block_store)
+        .unwrap_or_else(|e| {
+            error!("Error loading blockchain: {}", e);
+            process::exit(1);
+        });
+    let blockchain = Arc::new(Mutex::new(blockchain));
 
     //create ledger_manager
     let ledger_manager = ledger_manager::LedgerManager::new(
         &blockchain,
+        &mempool,
         &utxo_state,
-        voter_depth_k,
+        confirm_epsilon,
+        adversary_ratio,
     );
+    let ledger_leaders = Arc::clone(&ledger_manager.leaders);
     ledger_manager.start();
 
+    // open the wallet, creating a keystore with a fresh key if the file doesn't exist
+    let wallet = matches.value_of("keystore").map(|path| {
+        let passphrase = read_passphrase(path).unwrap_or_else(|e| {
+            error!("Error reading the keystore passphrase: {}", e);
+            process::exit(1);
+        });
+        let mut wallet = wallet::Wallet::open(path, &passphrase).unwrap_or_else(|e| {
+            error!("Error opening keystore {}: {}", path, e);
+            process::exit(1);
+        });
+        if wallet.addresses().is_empty() {
+            let addr = wallet.generate_key().unwrap_or_else(|e| {
+                error!("Error generating key: {}", e);
+                process::exit(1);
+            });
+            info!("Generated key for address {:?}", addr);
+        }
+        wallet
+    });
+
     // start the transaction generator
     // The transaction generator should not use blockchain
     let (txgen_ctx,txgen) = tx_generator::new(
         &server,
         &mempool,
         &utxo_state,
+        wallet,
     );
     txgen_ctx.start(); 
 
   
      // start the miner
+    let miner_threads = matches
+        .value_of("miner_threads")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing miner threads: {}", e);
+            process::exit(1);
+        });
     let (miner_ctx, miner) = miner::new(
         &server,
         &blockchain,
         &mempool,
+        miner_threads,
     );
     miner_ctx.start();
 
@@ -148,6 +291,8 @@ fn main() {
         &server,
         &blockchain,
         &mempool,
+        &utxo_state,
+        &addr_book,
     );
     worker_ctx.start();
 
@@ -195,6 +340,11 @@ fn main() {
         &miner,
         &server,
         &txgen,
+        &mempool,
+        &utxo_state,
+        &blockchain,
+        &ledger_leaders,
+        &ban_list,
     );
 
     loop {
@@ -1,14 +1,17 @@
 use crate::crypto::hash::H256;
 use crate::transaction::{SignedTransaction,UtxoInput};
 use crate::crypto::hash::Hashable;
+use crate::metrics::metrics;
+use crate::events::{emit, Event};
+use crate::utxo::UtxoState;
+use log::{debug, warn};
 use std::collections::VecDeque;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::BTreeMap;
 use std::convert::TryInto;
 
-use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
-use std::cmp;
+use std::cmp::{self, Reverse};
   
 #[derive(Debug)]
 pub struct TransactionMempool{
@@ -22,9 +25,16 @@ pub struct TransactionMempool{
     // to speed up duplicate removal
     // if a -> b, trans hash b is consuming utxoinput a
     // input_to_hash:HashMap<UtxoInput,H256>,
-    // storage_index to txhash, used for maintaining FIFO order
-    index_to_hash: BTreeMap<u32, H256>,
-    utxoinputs: HashSet<H256>
+    // (fee rate, storage_index) to txhash, highest fee rate first and
+    // FIFO order among transactions paying the same rate
+    priority_to_hash: BTreeMap<(Reverse<u64>, u32), H256>,
+    // hash of an input -> hash of the first transaction spending it
+    utxoinputs: HashMap<H256, H256>,
+    // transactions received with inputs the ledger has not confirmed yet, their fee is
+    // counted as 0 until `update_fees` can compute it
+    unpriced: HashSet<H256>,
+    // incremented on every insertion and deletion
+    version: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -35,37 +45,83 @@ pub struct TxStore{  //used for storing a tx and its btree index
     //storage index for btree
     index: u32,
 
+    // inputs minus outputs, paid to the miner of the proposer block
+    pub fee: u64,
+
+    // fee in millis per byte of the serialized transaction
+    pub fee_rate: u64,
+}
+
+// Fee per byte of `tx`, scaled by 1000 so that small fees still order transactions
+pub fn fee_rate(tx: &SignedTransaction, fee: u64) -> u64 {
+    let size = bincode::serialized_size(tx).unwrap();
+    fee.saturating_mul(1000) / cmp::max(size, 1)
 }
   
 impl TransactionMempool{
     pub fn new() -> Self{
         TransactionMempool{ counter: 0,
             hash_to_txstore: HashMap::new(),
-            index_to_hash: BTreeMap::new(), 
-            utxoinputs: HashSet::new(),
+            priority_to_hash: BTreeMap::new(), 
+            utxoinputs: HashMap::new(),
+            unpriced: HashSet::new(),
+            version: 0,
         }
     }
 
-    pub fn insert(&mut self, tx: SignedTransaction) {
+    pub fn insert(&mut self, tx: SignedTransaction, fee: u64) {
             // println!("Size of mempool: {}", self.hash_to_txstore.len());
-            println!("Received trans hash {} at {}", tx.hash(), SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros());
+            emit(Event::TxReceived { hash: tx.hash() });
 
             let hash = tx.hash();
             for utxoinput in &tx.tx.tx_input {
                 let utxoinput_hash = utxoinput.hash();
-                if !(self.utxoinputs.insert(utxoinput_hash)) {
-                    println!("Thief! {:?} trying to insert a douple spend in mempool", hash);
+                if *self.utxoinputs.entry(utxoinput_hash).or_insert(hash) != hash {
+                    warn!("Thief! {:?} trying to insert a douple spend in mempool", hash);
                 }
             }
             
             let txstore = TxStore{
+                fee_rate: fee_rate(&tx, fee),
                 signed_tx: tx,
                 index: self.counter,
+                fee,
             };
             self.counter += 1;
             
-            self.index_to_hash.insert(txstore.index, hash);
+            self.priority_to_hash.insert((Reverse(txstore.fee_rate), txstore.index), hash);
             self.hash_to_txstore.insert(hash, txstore);
+            self.version += 1;
+            metrics().mempool_inserted.inc();
+            metrics().mempool_txs.set(self.hash_to_txstore.len() as i64);
+            metrics().tx_inserted(hash);
+    }
+
+    // Insert a transaction whose fee cannot be computed yet because some of its inputs are
+    // not in the UTXO state
+    pub fn insert_unpriced(&mut self, tx: SignedTransaction) {
+        self.unpriced.insert(tx.hash());
+        self.insert(tx, 0);
+    }
+
+    // Compute the fee of the unpriced transactions whose inputs got confirmed since they
+    // were inserted and move them to the place of their fee rate
+    pub fn update_fees(&mut self, utxo_state: &UtxoState) {
+        let mut priced: Vec<(H256, u64)> = Vec::new();
+        for hash in &self.unpriced {
+            if let Some(fee) = self.hash_to_txstore.get(hash).and_then(|txstore| utxo_state.tx_fee(&txstore.signed_tx)) {
+                priced.push((*hash, fee));
+            }
+        }
+        for (hash, fee) in priced {
+            self.unpriced.remove(&hash);
+            let txstore = self.hash_to_txstore.get_mut(&hash).unwrap();
+            self.priority_to_hash.remove(&(Reverse(txstore.fee_rate), txstore.index));
+            txstore.fee = fee;
+            txstore.fee_rate = fee_rate(&txstore.signed_tx, fee);
+            self.priority_to_hash.insert((Reverse(txstore.fee_rate), txstore.index), hash);
+            self.version += 1;
+        }
     }
 
     // https://doc.rust-lang.org/std/option/
@@ -81,31 +137,123 @@ impl TransactionMempool{
     }
 
     pub fn contains_utxoinput(&self, inputhash: &H256) -> bool {
-        self.utxoinputs.contains(inputhash)
+        self.utxoinputs.contains_key(inputhash)
+    }
+
+    // First input of `tx` already spent by a transaction in the mempool
+    pub fn conflicting_input<'a>(&self, tx: &'a SignedTransaction) -> Option<&'a UtxoInput> {
+        tx.tx.tx_input.iter().find(|input| self.utxoinputs.contains_key(&input.hash()))
     }
 
     pub fn delete(&mut self, hash: &H256) -> bool {
         let txstore = self.hash_to_txstore.remove(hash);
         match txstore {
             Some(txstore) => {
-                self.index_to_hash.remove(&txstore.index);
+                self.priority_to_hash.remove(&(Reverse(txstore.fee_rate), txstore.index));
+                self.unpriced.remove(hash);
+                for utxoinput in &txstore.signed_tx.tx.tx_input {
+                    let utxoinput_hash = utxoinput.hash();
+                    if self.utxoinputs.get(&utxoinput_hash) == Some(hash) {
+                        self.utxoinputs.remove(&utxoinput_hash);
+                    }
+                }
+                self.version += 1;
+                metrics().mempool_txs.set(self.hash_to_txstore.len() as i64);
                 true
             }
             None => {
-                println!("Trying to delete non-existent hash");
+                debug!("Trying to delete non-existent hash");
                 false
             }
         }
     }
 
+    // The `n` transactions paying the highest fee rates
     pub fn get_transactions(&self, n: u32) -> Vec<SignedTransaction> {
         let count = cmp::min(n, self.len().try_into().unwrap());
-        self.index_to_hash.values().take(count as usize).map(|hash| self.get(hash).unwrap().signed_tx.clone()).collect()
+        self.priority_to_hash.values().take(count as usize).map(|hash| self.get(hash).unwrap().signed_tx.clone()).collect()
+    }
+
+    // Sum of the fees of the given transactions which are in the mempool
+    pub fn total_fee(&self, txs: &[SignedTransaction]) -> u64 {
+        txs.iter().filter_map(|tx| self.get(&tx.hash())).map(|txstore| txstore.fee).sum()
     }
     
     pub fn len(&self) -> usize {
         self.hash_to_txstore.len()
     }
 
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::generate_random_signed_transaction;
+
+    #[test]
+    fn highest_fee_rate_first() {
+        let mut mempool = TransactionMempool::new();
+        let txs: Vec<SignedTransaction> = (0..4).map(|_| generate_random_signed_transaction()).collect();
+        mempool.insert(txs[0].clone(), 1);
+        mempool.insert(txs[1].clone(), 5);
+        mempool.insert(txs[2].clone(), 1);
+        mempool.insert(txs[3].clone(), 3);
+
+        let selected: Vec<H256> = mempool.get_transactions(3).iter().map(|tx| tx.hash()).collect();
+        assert_eq!(selected, vec![txs[1].hash(), txs[3].hash(), txs[0].hash()]);
+        assert_eq!(mempool.total_fee(&txs[..2]), 6);
+
+        assert!(mempool.delete(&txs[1].hash()));
+        let selected: Vec<H256> = mempool.get_transactions(4).iter().map(|tx| tx.hash()).collect();
+        assert_eq!(selected, vec![txs[3].hash(), txs[0].hash(), txs[2].hash()]);
+    }
+
+    #[test]
+    fn deleted_transactions_release_their_inputs() {
+        let mut mempool = TransactionMempool::new();
+        let tx = generate_random_signed_transaction();
+        let mut double_spend = generate_random_signed_transaction();
+        double_spend.tx.tx_input = tx.tx.tx_input.clone();
+        mempool.insert(tx.clone(), 1);
+        mempool.insert(double_spend.clone(), 1);
+        assert!(mempool.conflicting_input(&tx).is_some());
+
+        // the inputs stay spent by the transaction which got them first
+        assert!(mempool.delete(&double_spend.hash()));
+        assert!(mempool.conflicting_input(&tx).is_some());
+        assert!(mempool.delete(&tx.hash()));
+        assert!(mempool.conflicting_input(&tx).is_none());
+        assert!(!mempool.contains_utxoinput(&tx.tx.tx_input[0].hash()));
+    }
+
+    #[test]
+    fn unpriced_transactions_get_their_fee() {
+        use crate::transaction::UtxoOutput;
+
+        let mut mempool = TransactionMempool::new();
+        let txs: Vec<SignedTransaction> = (0..2).map(|_| generate_random_signed_transaction()).collect();
+        mempool.insert(txs[0].clone(), 1);
+        mempool.insert_unpriced(txs[1].clone());
+        assert_eq!(mempool.get(&txs[1].hash()).unwrap().fee, 0);
+
+        // the ledger confirms the inputs of the unpriced transaction
+        let mut utxo_state = UtxoState::default();
+        mempool.update_fees(&utxo_state);
+        assert_eq!(mempool.get(&txs[1].hash()).unwrap().fee, 0);
+        let outputs: u64 = txs[1].tx.tx_output.iter().map(|output| output.value as u64).sum();
+        for (i, input) in txs[1].tx.tx_input.iter().enumerate() {
+            let value = if i == 0 { outputs as u32 + 1000 } else { 0 };
+            utxo_state.state_map.insert(input.clone(), UtxoOutput::new(Default::default(), value));
+        }
+        let version = mempool.version();
+        mempool.update_fees(&utxo_state);
+        assert_eq!(mempool.get(&txs[1].hash()).unwrap().fee, 1000);
+        assert!(mempool.version() > version);
+        let selected: Vec<H256> = mempool.get_transactions(2).iter().map(|tx| tx.hash()).collect();
+        assert_eq!(selected, vec![txs[1].hash(), txs[0].hash()]);
+    }
 }
-  
@@ -0,0 +1,280 @@
+use crate::crypto::hash::H256;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+// Process wide metrics registry, rendered in the Prometheus text format by the API.
+// Metrics are plain atomics so they can be updated from any thread without locks.
+
+// Upper bounds in seconds of the confirmation latency buckets
+const LATENCY_BUCKETS: [f64; 12] = [0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0];
+// Upper bounds in seconds of the block delay buckets
+const DELAY_BUCKETS: [f64; 10] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+// Transactions still unconfirmed after this long are no longer tracked for latency
+const TX_TRACKING_TTL: Duration = Duration::from_secs(3600);
+// Pruning of the tracked transactions starts at this size
+const MAX_TRACKED_TXS: usize = 100_000;
+
+#[derive(Default)]
+pub struct Counter {
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge {
+    value: AtomicI64,
+}
+
+impl Gauge {
+    pub fn set(&self, value: i64) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+// Cumulative histogram with fixed buckets, the sum is kept in microunits
+pub struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            if value <= *bound {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add((value.max(0.0) * 1e6) as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    // Observations less than or equal to each bucket bound
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        self.bounds.iter().zip(&self.counts).map(|(bound, count)| (*bound, count.load(Ordering::Relaxed))).collect()
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+    }
+}
+
+pub struct Metrics {
+    // miner
+    pub hashes: Counter,
+    pub proposer_blocks_mined: Counter,
+    pub voter_blocks_mined: Counter,
+    // worker
+    pub blocks_received: Counter,
+    pub invalid_blocks: Counter,
+    pub txs_received: Counter,
+    // delay between the timestamp of a block and its arrival, skewed by clock differences
+    pub block_delay: Histogram,
+    // blockchain
+    pub proposer_depth: Gauge,
+    pub orphan_blocks: Gauge,
+    // mempool
+    pub mempool_txs: Gauge,
+    pub mempool_inserted: Counter,
+    // ledger manager
+    pub confirmed_txs: Counter,
+    pub rejected_txs: Counter,
+    pub confirmed_level: Gauge,
+    pub rolled_back_levels: Counter,
+    // from the first mempool insertion on this node to the confirmation of the tx
+    pub confirmation_latency: Histogram,
+    // peer I/O
+    pub peers: Gauge,
+    pub bytes_received: Counter,
+    pub bytes_sent: Counter,
+    pub messages_received: Counter,
+    pub messages_sent: Counter,
+    // tx hash -> time it entered the mempool, until it gets confirmed
+    tx_inserted: Mutex<HashMap<H256, Instant>>,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            hashes: Counter::default(),
+            proposer_blocks_mined: Counter::default(),
+            voter_blocks_mined: Counter::default(),
+            blocks_received: Counter::default(),
+            invalid_blocks: Counter::default(),
+            txs_received: Counter::default(),
+            block_delay: Histogram::new(&DELAY_BUCKETS),
+            proposer_depth: Gauge::default(),
+            orphan_blocks: Gauge::default(),
+            mempool_txs: Gauge::default(),
+            mempool_inserted: Counter::default(),
+            confirmed_txs: Counter::default(),
+            rejected_txs: Counter::default(),
+            confirmed_level: Gauge::default(),
+            rolled_back_levels: Counter::default(),
+            confirmation_latency: Histogram::new(&LATENCY_BUCKETS),
+            peers: Gauge::default(),
+            bytes_received: Counter::default(),
+            bytes_sent: Counter::default(),
+            messages_received: Counter::default(),
+            messages_sent: Counter::default(),
+            tx_inserted: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Remember when a transaction entered the mempool, the first insertion counts
+    pub fn tx_inserted(&self, hash: H256) {
+        let mut inserted = self.tx_inserted.lock().unwrap();
+        if inserted.len() >= MAX_TRACKED_TXS {
+            inserted.retain(|_, time| time.elapsed() < TX_TRACKING_TTL);
+        }
+        inserted.entry(hash).or_insert_with(Instant::now);
+    }
+
+    // Record the confirmation latency of a transaction inserted into the mempool earlier.
+    // Transactions this node never had in its mempool are not observed.
+    pub fn tx_confirmed(&self, hash: &H256) {
+        self.confirmed_txs.inc();
+        if let Some(time) = self.tx_inserted.lock().unwrap().remove(hash) {
+            self.confirmation_latency.observe(time.elapsed().as_secs_f64());
+        }
+    }
+
+    // All metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        counter(&mut out, "prism_miner_hashes_total", "Nonces tried by the miner", &[("", &self.hashes)]);
+        counter(&mut out, "prism_blocks_mined_total", "Blocks mined by this node", &[
+            ("type=\"proposer\"", &self.proposer_blocks_mined),
+            ("type=\"voter\"", &self.voter_blocks_mined),
+        ]);
+        counter(&mut out, "prism_blocks_received_total", "Blocks received from peers", &[("", &self.blocks_received)]);
+        counter(&mut out, "prism_invalid_blocks_total", "Received blocks failing validation", &[("", &self.invalid_blocks)]);
+        counter(&mut out, "prism_txs_received_total", "Transactions received from peers", &[("", &self.txs_received)]);
+        histogram(&mut out, "prism_block_delay_seconds", "Delay between the timestamp of a received block and its arrival", &self.block_delay);
+        gauge(&mut out, "prism_proposer_depth", "Depth of the proposer chain", &self.proposer_depth);
+        gauge(&mut out, "prism_orphan_blocks", "Blocks waiting for a missing parent or reference", &self.orphan_blocks);
+        gauge(&mut out, "prism_mempool_txs", "Transactions in the mempool", &self.mempool_txs);
+        counter(&mut out, "prism_mempool_inserted_total", "Transactions inserted into the mempool", &[("", &self.mempool_inserted)]);
+        counter(&mut out, "prism_confirmed_txs_total", "Transactions confirmed by the ledger", &[("", &self.confirmed_txs)]);
+        counter(&mut out, "prism_rejected_txs_total", "Transactions of confirmed blocks rejected by the ledger", &[("", &self.rejected_txs)]);
+        gauge(&mut out, "prism_confirmed_level", "Level of the last confirmed leader", &self.confirmed_level);
+        counter(&mut out, "prism_rolled_back_levels_total", "Confirmed levels undone after a leader changed", &[("", &self.rolled_back_levels)]);
+        histogram(&mut out, "prism_tx_confirmation_latency_seconds", "Time from mempool insertion to ledger confirmation", &self.confirmation_latency);
+        gauge(&mut out, "prism_peers", "Connected peers", &self.peers);
+        counter(&mut out, "prism_p2p_bytes_total", "Bytes exchanged with peers", &[
+            ("direction=\"received\"", &self.bytes_received),
+            ("direction=\"sent\"", &self.bytes_sent),
+        ]);
+        counter(&mut out, "prism_p2p_messages_total", "Messages exchanged with peers", &[
+            ("direction=\"received\"", &self.messages_received),
+            ("direction=\"sent\"", &self.messages_sent),
+        ]);
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, series: &[(&str, &Counter)]) {
+    writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name).unwrap();
+    for (labels, counter) in series {
+        if labels.is_empty() {
+            writeln!(out, "{} {}", name, counter.get()).unwrap();
+        } else {
+            writeln!(out, "{}{{{}}} {}", name, labels, counter.get()).unwrap();
+        }
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, gauge: &Gauge) {
+    writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, gauge.get()).unwrap();
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name).unwrap();
+    for (bound, count) in histogram.buckets() {
+        writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count).unwrap();
+    }
+    writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count()).unwrap();
+    writeln!(out, "{}_sum {}", name, histogram.sum()).unwrap();
+    writeln!(out, "{}_count {}", name, histogram.count()).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_is_cumulative() {
+        let histogram = Histogram::new(&[1.0, 5.0]);
+        histogram.observe(0.5);
+        histogram.observe(3.0);
+        histogram.observe(7.0);
+        assert_eq!(histogram.buckets(), vec![(1.0, 1), (5.0, 2)]);
+        assert_eq!(histogram.count(), 3);
+        assert!((histogram.sum() - 10.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn confirmation_latency_is_rendered() {
+        let metrics = Metrics::new();
+        let hash = H256::from([7u8; 32]);
+        metrics.tx_inserted(hash);
+        metrics.tx_confirmed(&hash);
+        // confirmed twice, e.g. after a rollback, is observed once
+        metrics.tx_confirmed(&hash);
+        assert_eq!(metrics.confirmation_latency.count(), 1);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE prism_tx_confirmation_latency_seconds histogram\n"));
+        assert!(text.contains("prism_tx_confirmation_latency_seconds_bucket{le=\"0.5\"} 1\n"));
+        assert!(text.contains("prism_tx_confirmation_latency_seconds_count 1\n"));
+        assert!(text.contains("prism_confirmed_txs_total 2\n"));
+        assert!(text.contains("prism_blocks_mined_total{type=\"voter\"} 0\n"));
+    }
+}
@@ -4,16 +4,22 @@ use crate::blockchain::{Blockchain};
 use crate::crypto::hash::{H256, Hashable};
 use crate::mempool::{TransactionMempool};
 use crate::crypto::merkle::MerkleTree;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
 use crate::network::message::{Message};
-use log::info;
+use log::{info, debug, warn};
 use bigint::uint::U256;
 use rand::Rng;
-use crate::transaction::{self, SignedTransaction};
+use crate::transaction::{SignedTransaction, CoinbaseTransaction};
+use crate::utxo::{miner_address, BLOCK_REWARD};
+use crate::metrics::metrics;
+use crate::events::{emit, Event, BlockKind};
+use serde::Serialize;
 
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError, RecvTimeoutError};
 use std::time;
+use std::cmp;
 
 use std::thread;
 
@@ -21,17 +27,6 @@ const TOTAL_SORTITION_WIDTH: u64 = std::u64::MAX;
 pub const PROPOSER_INDEX: u32 = 0;
 pub const FIRST_VOTER_IDX: u32 = 1;
 
-pub struct Superblock {
-    pub header: Header,
-    pub content: Vec<Content>,
-}
-
-impl Hashable for Superblock {
-    fn hash(&self) -> H256 {
-        self.header.hash()
-    }
-}
-
 pub fn get_difficulty(num_voter_chains: u32) -> H256 {
     let base_difficulty: H256 = (hex!("0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff")).into();
     let difficulty = U256::from_big_endian(base_difficulty.as_ref());
@@ -42,6 +37,32 @@ pub fn get_difficulty(num_voter_chains: u32) -> H256 {
 }
 
 
+// Number of proposer levels between two difficulty adjustments
+pub const DIFFICULTY_EPOCH: u32 = 16;
+// Targeted time between two proposer blocks, in microseconds like header timestamps
+pub const TARGET_PROPOSER_INTERVAL: u128 = 10_000_000;
+// Bound on the adjustment factor of a single retarget
+const MAX_RETARGET_FACTOR: u64 = 4;
+
+// Scale `difficulty` (a target, higher is easier) by the ratio between the time an epoch
+// took and the time it should have taken. The ratio is clamped to
+// [1/MAX_RETARGET_FACTOR, MAX_RETARGET_FACTOR] so that skewed timestamps cannot move the
+// difficulty arbitrarily far in a single epoch.
+pub fn retarget_difficulty(difficulty: H256, actual_span: u128, expected_span: u128) -> H256 {
+    let expected_span = cmp::max(expected_span, 1) as u64;
+    let actual_span = cmp::min(
+        cmp::max(actual_span, (expected_span / MAX_RETARGET_FACTOR) as u128),
+        expected_span as u128 * MAX_RETARGET_FACTOR as u128,
+    ) as u64;
+
+    let difficulty = U256::from_big_endian(difficulty.as_ref());
+    let (adjusted, overflow) = (difficulty / expected_span.into()).overflowing_mul(actual_span.into());
+    let adjusted = if overflow { U256::max_value() } else { adjusted };
+    let mut buffer: [u8; 32] = [0; 32];
+    adjusted.to_big_endian(&mut buffer);
+    buffer.into()
+}
+
 pub fn sortition_hash(hash: H256, difficulty: H256, num_voter_chains: u32) -> Option<u32> {
     let hash = U256::from_big_endian(hash.as_ref());
     let difficulty = U256::from_big_endian(difficulty.as_ref());
@@ -56,22 +77,188 @@ pub fn sortition_hash(hash: H256, difficulty: H256, num_voter_chains: u32) -> Op
         let voter_idx = (hash - proposer_width) % num_voter_chains.into();
         Some(FIRST_VOTER_IDX + voter_idx.as_u32())
     } else {
-        println!("Why you sortitioning something that is not less than difficulty?");
+        warn!("Sortition of hash {} which is not less than the difficulty", hash);
         None
     }
 }
 
+// Contents of a superblock on top of the current tips: the proposer content with the
+// best transactions of the mempool, then the content of each voter chain
+pub fn assemble_contents(blockchain: &Blockchain, mempool: &TransactionMempool, miner_id: u64) -> Vec<Content> {
+    let txs: Vec<SignedTransaction> = mempool.get_transactions(MAX_BLOCK_TXS);
+    // the coinbase collects the fees of the selected transactions
+    let fees = mempool.total_fee(&txs);
+    let reward = cmp::min(BLOCK_REWARD as u64 + fees, u32::MAX as u64) as u32;
+
+    let mut contents: Vec<Content> = Vec::new();
+
+    //proposer
+    let parent = blockchain.get_proposer_tip();
+    let level = blockchain.proposer_chain[&parent].level + 1;
+    let proposer_content = ProposerContent {
+        parent_hash: parent,
+        coinbase: CoinbaseTransaction::new(level, parent, miner_address(miner_id as i32), reward),
+        transactions: txs,
+        proposer_refs: blockchain.get_unref_proposers(),
+    };
+    contents.push(block::Content::Proposer(proposer_content));
+
+    // Voters
+    for chain_num in 1..(blockchain.num_voter_chains + 1) {
+        let tmp = VoterContent {
+            votes: blockchain.get_votes(chain_num),
+            parent_hash: blockchain.get_voter_tip(chain_num),
+            chain_num,
+        };
+        contents.push(block::Content::Voter(tmp));
+    }
+    contents
+}
+
+// The block a solved superblock turns into: its header, the content selected by sortition
+// - proposer(0), voters(1..m) - and the proof of that content
+pub fn sortition_block(header: Header, contents: &[Content], merkle_tree: &MerkleTree, num_voter_chains: u32) -> Block {
+    let block_idx: u32 = sortition_hash(header.hash(), header.difficulty, num_voter_chains).unwrap();
+    Block {
+        header,
+        content: contents[block_idx as usize].clone(),
+        sortition_proof: merkle_tree.proof(block_idx as usize),
+    }
+}
+
+// Insert a block mined by this node, recording it in the metrics and the event log
+pub fn insert_mined_block(blockchain: &mut Blockchain, block: &Block) {
+    let block_hash = block.hash();
+    match &block.content {
+        Content::Proposer(content) => {
+            metrics().proposer_blocks_mined.inc();
+            let level = blockchain.proposer_chain[&content.parent_hash].level + 1;
+            emit(Event::BlockMined { hash: block_hash, kind: BlockKind::Proposer, level });
+        }
+        Content::Voter(content) => {
+            metrics().voter_blocks_mined.inc();
+            let level = blockchain.voter_chains[(content.chain_num-1) as usize][&content.parent_hash].level + 1;
+            emit(Event::BlockMined { hash: block_hash, kind: BlockKind::Voter, level });
+        }
+    }
+    blockchain.insert(block);
+}
+
 enum ControlSignal {
-    Start(u64,u64), // the number controls the lambda of interval between block generation
+    Start(u64,u64), // interval between blocks in microseconds and miner id
+    Pause,
     Exit,
 }
 
+// Operating state reported by the miner and the transaction generator
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RunState {
+    #[default]
+    Paused,
+    Running,
+    Stopped,
+}
+
 enum OperatingState {
     Paused,
     Run(u64,u64),
     ShutDown,
 }
 
+// Maximum number of transactions put in a proposer block
+const MAX_BLOCK_TXS: u32 = 5;
+// Number of nonces a search thread tries before checking whether its template is still current
+const NONCE_BATCH: u32 = 4096;
+// How often the coordinator checks blockchain and mempool for changes
+const TEMPLATE_CHECK_INTERVAL: Duration = Duration::from_millis(20);
+// Age a template reaches before new mempool transactions are worth rebuilding it,
+// tip changes rebuild it right away
+const MEMPOOL_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+// How often the hash rate is recomputed
+const HASH_RATE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Superblock being mined, assembled once and shared by all search threads
+struct Template {
+    id: u64,
+    header: Header,
+    contents: Vec<Content>,
+    merkle_tree: MerkleTree,
+    num_voter_chains: u32,
+    // state the template was assembled from
+    blockchain_version: u64,
+    mempool_version: u64,
+    assembled: Instant,
+}
+
+// Template slot the search threads wait on
+struct SharedTemplate {
+    current: Mutex<Option<Arc<Template>>>,
+    changed: Condvar,
+    // id of the current template, 0 when there is none
+    id: AtomicU64,
+}
+
+impl SharedTemplate {
+    fn publish(&self, template: Option<Arc<Template>>) {
+        let mut current = self.current.lock().unwrap();
+        self.id.store(template.as_ref().map_or(0, |t| t.id), Ordering::SeqCst);
+        *current = template;
+        self.changed.notify_all();
+    }
+
+    // Block until there is a template other than `last_id`
+    fn wait(&self, last_id: u64) -> Arc<Template> {
+        let mut current = self.current.lock().unwrap();
+        loop {
+            if let Some(template) = current.as_ref() {
+                if template.id != last_id {
+                    return Arc::clone(template);
+                }
+            }
+            current = self.changed.wait(current).unwrap();
+        }
+    }
+
+    fn is_current(&self, id: u64) -> bool {
+        self.id.load(Ordering::Relaxed) == id
+    }
+}
+
+// Header of a superblock that satisfies the difficulty of its template
+struct Solution {
+    template_id: u64,
+    header: Header,
+}
+
+// Counters of the miner, shared with its handle
+#[derive(Default)]
+pub struct MinerStats {
+    pub hashes: AtomicU64,
+    pub proposer_blocks: AtomicU64,
+    pub voter_blocks: AtomicU64,
+    // hashes per second over the last HASH_RATE_INTERVAL
+    pub hash_rate: AtomicU64,
+    // state and parameters of the last control signal handled by the miner
+    pub control: Mutex<(RunState, u64, u64)>,
+    // set by the first stop request, the miner can't be started again afterwards
+    pub stopped: AtomicBool,
+}
+
+// Snapshot of the miner served by the API
+#[derive(Serialize, Debug, Clone)]
+pub struct MinerStatus {
+    pub state: RunState,
+    // sleep after each mined block, in microseconds
+    pub interval: u64,
+    pub miner_id: u64,
+    pub threads: usize,
+    pub hash_rate: u64,
+    pub hashes: u64,
+    pub proposer_blocks: u64,
+    pub voter_blocks: u64,
+}
+
 pub struct Context {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
@@ -79,252 +266,383 @@ pub struct Context {
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool:Arc<Mutex<TransactionMempool>>,
+    num_threads: usize,
+    template: Arc<SharedTemplate>,
+    next_template_id: u64,
+    stats: Arc<MinerStats>,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    stats: Arc<MinerStats>,
+    num_threads: usize,
 }
 
 pub fn new(
     server: &ServerHandle,
     blockchain: &Arc<Mutex<Blockchain>>,
     mempool: &Arc<Mutex<TransactionMempool>>,  
+    num_threads: usize,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let stats = Arc::new(MinerStats::default());
+    let num_threads = cmp::max(num_threads, 1);
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
         server: server.clone(),
         blockchain: Arc::clone(blockchain),
-        mempool: Arc::clone(mempool)
+        mempool: Arc::clone(mempool),
+        num_threads,
+        template: Arc::new(SharedTemplate {
+            current: Mutex::new(None),
+            changed: Condvar::new(),
+            id: AtomicU64::new(0),
+        }),
+        next_template_id: 1,
+        stats: Arc::clone(&stats),
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        stats,
+        num_threads,
     };
 
     (ctx, handle)
 }
 
+// Stopping the miner is final, every signal after the first stop is refused and the send
+// methods return false
 impl Handle {
-    pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+    pub fn exit(&self) -> bool {
+        !self.stats.stopped.swap(true, Ordering::SeqCst) && self.control_chan.send(ControlSignal::Exit).is_ok()
     }
 
-    pub fn start(&self, lambda: u64,index: u64) {
-        self.control_chan
-            .send(ControlSignal::Start(lambda,index))
-            .unwrap();
+    pub fn start(&self, interval: u64, miner_id: u64) -> bool {
+        !self.stats.stopped.load(Ordering::SeqCst) && self.control_chan
+            .send(ControlSignal::Start(interval, miner_id))
+            .is_ok()
+    }
+
+    pub fn pause(&self) -> bool {
+        !self.stats.stopped.load(Ordering::SeqCst) && self.control_chan.send(ControlSignal::Pause).is_ok()
     }
 
+    pub fn status(&self) -> MinerStatus {
+        let (state, interval, miner_id) = *self.stats.control.lock().unwrap();
+        MinerStatus {
+            state,
+            interval,
+            miner_id,
+            threads: self.num_threads,
+            hash_rate: self.stats.hash_rate.load(Ordering::Relaxed),
+            hashes: self.stats.hashes.load(Ordering::Relaxed),
+            proposer_blocks: self.stats.proposer_blocks.load(Ordering::Relaxed),
+            voter_blocks: self.stats.voter_blocks.load(Ordering::Relaxed),
+        }
+    }
+
+    // Hashes per second computed by all search threads
+    pub fn hash_rate(&self) -> u64 {
+        self.stats.hash_rate.load(Ordering::Relaxed)
+    }
+
+}
+
+// Search the nonces `first`, `first + step`, ... of each template for a header below the difficulty
+fn search_loop(first: u32, step: u32, template: Arc<SharedTemplate>, solutions: Sender<Solution>, stats: Arc<MinerStats>) {
+    let mut last_id = 0;
+    loop {
+        let current = template.wait(last_id);
+        last_id = current.id;
+        let mut header = current.header.clone();
+        header.nonce = first;
+        'search: loop {
+            for _ in 0..NONCE_BATCH {
+                if header.hash() < header.difficulty {
+                    if solutions.send(Solution { template_id: current.id, header: header.clone() }).is_err() {
+                        return;
+                    }
+                    break 'search;
+                }
+                let (nonce, wrapped) = header.nonce.overflowing_add(step);
+                header.nonce = nonce;
+                if wrapped {
+                    // nonce space exhausted, a new timestamp gives a fresh one
+                    header.timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros();
+                }
+            }
+            stats.hashes.fetch_add(NONCE_BATCH as u64, Ordering::Relaxed);
+            metrics().hashes.inc_by(NONCE_BATCH as u64);
+            if !template.is_current(current.id) {
+                break;
+            }
+        }
+    }
 }
 
 impl Context {
     pub fn start(mut self) {
+        let (solution_sender, solution_receiver) = unbounded();
+        for i in 0..self.num_threads {
+            let template = Arc::clone(&self.template);
+            let solutions = solution_sender.clone();
+            let stats = Arc::clone(&self.stats);
+            let step = self.num_threads as u32;
+            thread::Builder::new()
+                .name(format!("miner-search-{}", i))
+                .spawn(move || {
+                    search_loop(i as u32, step, template, solutions, stats);
+                })
+                .unwrap();
+        }
         thread::Builder::new()
             .name("miner".to_string())
             .spawn(move || {
-                self.miner_loop();
+                self.miner_loop(solution_receiver);
             })
             .unwrap();
         info!("Miner initialized into paused mode");
     }
 
     fn handle_control_signal(&mut self, signal: ControlSignal) {
+        let mut control = self.stats.control.lock().unwrap();
         match signal {
             ControlSignal::Exit => {
                 info!("Miner shutting down");
                 self.operating_state = OperatingState::ShutDown;
+                control.0 = RunState::Stopped;
+            }
+            ControlSignal::Pause => {
+                info!("Miner paused");
+                self.operating_state = OperatingState::Paused;
+                control.0 = RunState::Paused;
             }
             ControlSignal::Start(i,j) => {
-                info!("Miner starting in continuous mode with lambda {}", i);
+                info!("Miner starting in continuous mode with interval {} and miner id {}", i, j);
                 self.operating_state = OperatingState::Run(i,j);
+                *control = (RunState::Running, i, j);
             }
         }
     }
 
-    fn miner_loop(&mut self) {
-        // main mining loop
-        // let experiment_duration = 300;   // in secs
-        // let start = Instant::now();
-        // let mut num_proposer_blocks = 0;
+    // Assemble a superblock on top of the current tips, None if the mempool is empty
+    fn assemble_template(&mut self, miner_id: u64) -> Option<Template> {
+        let locked_blockchain = self.blockchain.lock().unwrap();
+        let locked_mempool = self.mempool.lock().unwrap();
+        let mempool_version = locked_mempool.version();
+        if locked_mempool.len() == 0 {
+            return None;
+        }
+        let contents = assemble_contents(&locked_blockchain, &locked_mempool, miner_id);
+        drop(locked_mempool);
 
-        loop {
-            let mut index:u64 = 0;
-            let mut time_i:u64 = 0;
+        let parent = locked_blockchain.get_proposer_tip();
+        let num_voter_chains = locked_blockchain.num_voter_chains;
+        let difficulty = locked_blockchain.get_difficulty(&parent).unwrap();
+        // a block older than its parent is invalid, whatever the clock of the parent's miner
+        let parent_timestamp = locked_blockchain.proposer_chain[&parent].block.header.timestamp;
+        let blockchain_version = locked_blockchain.version();
+        drop(locked_blockchain);
+
+        let merkle_tree = MerkleTree::new(&contents);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros();
+        let header = Header {
+            parent,
+            nonce: 0,
+            difficulty,
+            timestamp: cmp::max(now, parent_timestamp),
+            merkle_root: merkle_tree.root(),
+            miner_id: miner_id as i32,
+        };
+
+        let id = self.next_template_id;
+        self.next_template_id += 1;
+        Some(Template {
+            id,
+            header,
+            contents,
+            merkle_tree,
+            num_voter_chains,
+            blockchain_version,
+            mempool_version,
+            assembled: Instant::now(),
+        })
+    }
+
+    fn is_stale(&self, template: &Template) -> bool {
+        if self.blockchain.lock().unwrap().version() != template.blockchain_version {
+            return true;
+        }
+        template.assembled.elapsed() >= MEMPOOL_REFRESH_INTERVAL
+            && self.mempool.lock().unwrap().version() != template.mempool_version
+    }
 
+    // Turn a solved superblock into the block selected by sortition, insert and broadcast it
+    fn process_solution(&self, template: &Template, header: Header) {
+        let processed_block = sortition_block(header, &template.contents, &template.merkle_tree, template.num_voter_chains);
+        let block_hash = processed_block.hash();
+
+        // Insert into local blockchain
+        let mut locked_blockchain = self.blockchain.lock().unwrap();
+        match &processed_block.content {
+            Content::Proposer(_) => self.stats.proposer_blocks.fetch_add(1, Ordering::Relaxed),
+            Content::Voter(_) => self.stats.voter_blocks.fetch_add(1, Ordering::Relaxed),
+        };
+        insert_mined_block(&mut locked_blockchain, &processed_block);
+        drop(locked_blockchain);
+
+        // Broadcast new block hash to the network
+        self.server.broadcast(Message::NewBlockHashes(vec![block_hash]));
+    }
+
+    // Coordinates the search threads: publishes a template, replaces it when the tips or the
+    // mempool change, and turns solutions into blocks. Locks are only held while assembling
+    // a template and inserting a block, never while searching for a nonce.
+    fn miner_loop(&mut self, solutions: Receiver<Solution>) {
+        let mut current: Option<Arc<Template>> = None;
+        let mut rate_start = Instant::now();
+        let mut rate_hashes: u64 = 0;
+
+        loop {
             // check and react to control signals
             match self.operating_state {
                 OperatingState::Paused => {
+                    self.template.publish(None);
+                    current = None;
                     let signal = self.control_chan.recv().unwrap();
                     self.handle_control_signal(signal);
                     continue;
                 }
                 OperatingState::ShutDown => {
+                    self.template.publish(None);
                     return;
                 }
                 _ => match self.control_chan.try_recv() {
                     Ok(signal) => {
                         self.handle_control_signal(signal);
+                        continue;
                     }
                     Err(TryRecvError::Empty) => {},
                     Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
                 },
             }
 
-            if let OperatingState::ShutDown = self.operating_state {
-                return;
-            }
-            if let OperatingState::Run(i, j) = self.operating_state {
-                index = j;
-                time_i = i; 
-                
-                if time_i != 0 {
-                    let interval = time::Duration::from_micros(time_i as u64);
-                    thread::sleep(interval);
-                }
-
-                let mut txs: Vec<SignedTransaction> = Vec::new();
+            let (lambda, miner_id) = match self.operating_state {
+                OperatingState::Run(i, j) => (i, j),
+                _ => continue,
+            };
 
-                let locked_mempool = self.mempool.lock().unwrap();
-                // println!("miner: acquired mempool lock");
-                if (locked_mempool.len() == 0) {
-                    drop(locked_mempool);
-                    // println!("miner: dropped mempool lock");
+            let needs_template = match &current {
+                Some(template) => self.is_stale(template),
+                None => true,
+            };
+            if needs_template {
+                current = self.assemble_template(miner_id).map(Arc::new);
+                if current.is_none() {
                     // println!("Mempool is empty, see ya later, sleeping");
-                    let interval = time::Duration::from_micros(time_i as u64);
-                    thread::sleep(interval);
-                } else {
-                    txs = locked_mempool.get_transactions(5);
-                    // println!("length of txs in miner {}", txs.len());
-                    drop(locked_mempool);
-                    // println!("miner: dropped mempool lock");
+                    debug!("Mempool is empty, waiting for transactions");
                 }
+                self.template.publish(current.clone());
+            }
 
-                let mut empty_mempool:bool = false; 
-                
-                while (true) {
-                    // step1: assemble a new superblock
-                    // TODO: We can optimize the assembly by using the version numbers trick
-                    // println!("miner: acquired blockchain lock");
-                    let mut locked_blockchain = self.blockchain.lock().unwrap();
-                    // println!("miner: dropped blockchain lock");
-
-                    // if locked_blockchain.proposer_chain.len() == 10 {
-                    //     info!("[Miner] finished experiment, shutting down ...");
-                    //     self.operating_state = OperatingState::ShutDown;
-                    //     break;
-                    // }
-    
-                    if (locked_blockchain.has_new_proposer() || empty_mempool) {
-                        let locked_mempool = self.mempool.lock().unwrap();
-                        // println!("miner: acquired mempool lock");
-                        if (locked_mempool.len() == 0) {
-                            drop(locked_mempool);
-                            // println!("miner: dropped mempool lock");
-                            // println!("Mempool is empty, see ya later, sleeping");
-                            let interval = time::Duration::from_micros(time_i as u64);
-                            thread::sleep(interval);
+            match solutions.recv_timeout(TEMPLATE_CHECK_INTERVAL) {
+                Ok(solution) => {
+                    if let Some(template) = current.take() {
+                        if template.id == solution.template_id {
+                            self.template.publish(None);
+                            self.process_solution(&template, solution.header);
+                            if lambda != 0 {
+                                thread::sleep(time::Duration::from_micros(lambda));
+                            }
                         } else {
-                            txs = locked_mempool.get_transactions(5);
-                            // println!("length of txs in miner {}", txs.len());
-                            drop(locked_mempool);
-                            // println!("miner: dropped mempool lock");
+                            current = Some(template);
                         }
                     }
-
-                    if (txs.len() == 0) {
-                        empty_mempool = true;
-                        println!("txs is empty {}", txs.len());
-                        continue;
-                    }
-                    
-                    let mut contents: Vec<Content> = Vec::new();
-    
-                    //proposer
-                    let proposer_content = ProposerContent {
-                        parent_hash: locked_blockchain.get_proposer_tip(),
-                        transactions: txs.clone(),
-                        proposer_refs: locked_blockchain.get_unref_proposers(),
-                    };
-                    contents.push(block::Content::Proposer(proposer_content));
-    
-                    // Voters
-                    let num_voter_chains = locked_blockchain.num_voter_chains;
-                    for chain_num in 1..(num_voter_chains + 1) {
-                        let tmp = VoterContent {
-                            votes: locked_blockchain.get_votes(chain_num),
-                            parent_hash: locked_blockchain.get_voter_tip(chain_num),
-                            chain_num: chain_num,
-                        };
-                        contents.push(block::Content::Voter(tmp));
-                    }
-    
-                    //drop(locked_blockchain);
-    
-                    let content_mkl_tree = MerkleTree::new(&contents);
-    
-                    let mut rng = rand::thread_rng();
-                    let header = Header {
-                        nonce: rng.gen::<u32>(),
-                        difficulty: get_difficulty(num_voter_chains),
-                        timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros(),
-                        merkle_root: content_mkl_tree.root(),
-                        miner_id: index as i32,   
-                    };
-    
-                    let superblock = Superblock {
-                        header: header,
-                        content: contents,
-                    };
-    
-                    let block_hash = superblock.hash();
-                    // NOTE: Below works only for static difficulty
-                    let difficulty = get_difficulty(num_voter_chains);
-    
-                    if block_hash < difficulty {
-                        
-                        // Sortition and decide the block index - proposer(0), voters(1..m)
-                        let block_idx: u32 = sortition_hash(block_hash, difficulty, num_voter_chains).unwrap();
-                        // println!("Mined a new block with {:?} hash", block_hash);
-                        match &superblock.content[block_idx as usize] {
-                            Content::Proposer(content) => {
-                                println!("Mined a proposer with hash {:?} at index: {} and height {}",block_hash,block_idx,locked_blockchain.proposer_chain[&content.parent_hash].level+1);
-                            }
-                            Content::Voter(content) => {
-                                println!("Mined a voter with hash {:?} at index: {} and height {}",block_hash,block_idx,locked_blockchain.voter_chains[(block_idx-1) as usize][&content.parent_hash].level+1);
-                            }
-                        }    
-    
-                        // Add header, relevant content and sortition proof
-                        let sortition_proof = content_mkl_tree.proof(block_idx as usize);
-                        let processed_block = Block {
-                            header: superblock.header,
-                            content: superblock.content[block_idx as usize].clone(),
-                            sortition_proof: sortition_proof,
-                        };
-    
-                        // Insert into local blockchain
-                        // let mut locked_blockchain = self.blockchain.lock().unwrap();
-                        locked_blockchain.insert(&processed_block);
-                        // locked_blockchain.print_chains();
-                        drop(locked_blockchain);
-    
-                        // Broadcast new block hash to the network
-                        self.server.broadcast(Message::NewBlockHashes(vec![block_hash]));
-                        
-                        break;
-                    }
                 }
-                // if (start.elapsed().as_secs() > experiment_duration) {
-                //     info!("[Miner] finished experiment, shutting down ...");
-                //     self.operating_state = OperatingState::ShutDown;
-                //     break;
-                // }
-            }    
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => panic!("Miner search threads exited"),
+            }
+
+            if rate_start.elapsed() >= HASH_RATE_INTERVAL {
+                let hashes = self.stats.hashes.load(Ordering::Relaxed);
+                let rate = (hashes - rate_hashes) as f64 / rate_start.elapsed().as_secs_f64();
+                self.stats.hash_rate.store(rate as u64, Ordering::Relaxed);
+                info!("Miner hash rate {:.0} H/s", rate);
+                rate_start = Instant::now();
+                rate_hashes = hashes;
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shared_template() -> Arc<SharedTemplate> {
+        Arc::new(SharedTemplate {
+            current: Mutex::new(None),
+            changed: Condvar::new(),
+            id: AtomicU64::new(0),
+        })
+    }
+
+    fn template(id: u64, difficulty: H256) -> Template {
+        let contents: Vec<Content> = (1..4)
+            .map(|chain_num| Content::Voter(VoterContent {
+                votes: vec![genesis_proposer().hash()],
+                parent_hash: genesis_voter(chain_num).hash(),
+                chain_num,
+            }))
+            .collect();
+        let merkle_tree = MerkleTree::new(&contents);
+        let mut header = genesis_proposer().header;
+        header.difficulty = difficulty;
+        header.merkle_root = merkle_tree.root();
+        Template {
+            id,
+            header,
+            contents,
+            merkle_tree,
+            num_voter_chains: 2,
+            blockchain_version: 0,
+            mempool_version: 0,
+            assembled: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn search_threads_solve_current_template() {
+        let shared = shared_template();
+        let stats = Arc::new(MinerStats::default());
+        let (sender, receiver) = unbounded();
+        for i in 0..2 {
+            let shared = Arc::clone(&shared);
+            let sender = sender.clone();
+            let stats = Arc::clone(&stats);
+            thread::spawn(move || search_loop(i, 2, shared, sender, stats));
+        }
+        let difficulty: H256 = (hex!("00ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff")).into();
+        shared.publish(Some(Arc::new(template(1, difficulty))));
+        let solution = receiver.recv_timeout(Duration::from_secs(30)).unwrap();
+        assert_eq!(solution.template_id, 1);
+        assert!(solution.header.hash() < difficulty);
+
+        // a replaced template is abandoned by the search threads
+        shared.publish(Some(Arc::new(template(2, difficulty))));
+        assert!(!shared.is_current(1));
+        let solution = loop {
+            let solution = receiver.recv_timeout(Duration::from_secs(30)).unwrap();
+            if solution.template_id == 2 {
+                break solution;
+            }
+        };
+        assert!(solution.header.hash() < difficulty);
+        shared.publish(None);
+    }
+}
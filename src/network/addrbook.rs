@@ -0,0 +1,164 @@
+use serde::{Serialize, Deserialize};
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Addresses kept at most, the ones seen least recently are dropped beyond it
+pub const MAX_ADDRESSES: usize = 1000;
+// Addresses sent in reply to a GetAddr
+pub const MAX_ADDR_REPLY: usize = 100;
+// Failed connection attempts in a row after which an address is forgotten
+pub const MAX_CONNECT_FAILURES: u32 = 3;
+
+// A P2P server address with the last time (UNIX seconds) a node was connected to it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct KnownAddress {
+    pub addr: SocketAddr,
+    pub last_seen: u64,
+}
+
+// Addresses of the P2P servers of other nodes, learnt from our connections and from Addr
+// messages. The outbound connections of the server are picked from it.
+#[derive(Default)]
+pub struct AddressBook {
+    addresses: HashMap<SocketAddr, u64>,
+    failures: HashMap<SocketAddr, u32>,
+    // JSON file the book is saved to, kept in memory only if not set
+    path: Option<PathBuf>,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Open the address book saved at `path`, an empty one if the file doesn't exist
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut book = Self::new();
+        match fs::read(&path) {
+            Ok(raw) => {
+                let known: Vec<KnownAddress> = serde_json::from_slice(&raw)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                book.add(&known);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        book.path = Some(path.as_ref().to_path_buf());
+        Ok(book)
+    }
+
+    // Write the book to its file, replacing it atomically
+    pub fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let encoded = serde_json::to_vec_pretty(&self.sample(MAX_ADDRESSES)).unwrap();
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&encoded)?;
+        tmp.sync_data()?;
+        fs::rename(&tmp_path, path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    // Record a connection to `addr` at time `last_seen`
+    pub fn seen(&mut self, addr: SocketAddr, last_seen: u64) {
+        self.failures.remove(&addr);
+        self.add(&[KnownAddress { addr, last_seen }]);
+    }
+
+    // Merge addresses learnt from a peer, times in the future are clamped to now
+    pub fn add(&mut self, known: &[KnownAddress]) {
+        let now = now();
+        for address in known {
+            let last_seen = self.addresses.entry(address.addr).or_insert(0);
+            *last_seen = std::cmp::max(*last_seen, std::cmp::min(address.last_seen, now));
+        }
+        if self.addresses.len() > MAX_ADDRESSES {
+            let kept: Vec<KnownAddress> = self.sample(MAX_ADDRESSES);
+            self.addresses = kept.iter().map(|known| (known.addr, known.last_seen)).collect();
+        }
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.addresses.remove(addr);
+        self.failures.remove(addr);
+    }
+
+    // Record a failed connection attempt, the address is forgotten after a few of them
+    pub fn failed(&mut self, addr: SocketAddr) {
+        let failures = self.failures.entry(addr).or_insert(0);
+        *failures += 1;
+        if *failures >= MAX_CONNECT_FAILURES {
+            self.remove(&addr);
+        }
+    }
+
+    // Up to `count` addresses, the most recently seen first
+    pub fn sample(&self, count: usize) -> Vec<KnownAddress> {
+        let mut known: Vec<KnownAddress> = self.addresses.iter()
+            .map(|(addr, last_seen)| KnownAddress { addr: *addr, last_seen: *last_seen })
+            .collect();
+        known.sort_by(|a, b| b.last_seen.cmp(&a.last_seen).then(a.addr.cmp(&b.addr)));
+        known.truncate(count);
+        known
+    }
+
+    // Up to `count` addresses to connect to, leaving out the ones in `exclude`
+    pub fn candidates(&self, count: usize, exclude: &[SocketAddr]) -> Vec<SocketAddr> {
+        self.sample(MAX_ADDRESSES).into_iter()
+            .map(|known| known.addr)
+            .filter(|addr| !exclude.contains(addr))
+            .take(count)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn book_is_saved_and_pruned() {
+        let path = std::env::temp_dir().join(format!("prism-addrbook-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let addr = |port: u16| SocketAddr::from(([127, 0, 0, 1], port));
+
+        let mut book = AddressBook::open(&path).unwrap();
+        assert!(book.is_empty());
+        book.seen(addr(6001), 10);
+        book.add(&[KnownAddress { addr: addr(6002), last_seen: 20 }, KnownAddress { addr: addr(6001), last_seen: 5 }]);
+        // the peer cannot make us believe it was seen in the future
+        book.add(&[KnownAddress { addr: addr(6003), last_seen: u64::MAX }]);
+        assert!(book.sample(3)[0].last_seen <= now());
+        assert_eq!(book.candidates(2, &[addr(6003)]), vec![addr(6002), addr(6001)]);
+        book.save().unwrap();
+
+        let mut book = AddressBook::open(&path).unwrap();
+        assert_eq!(book.len(), 3);
+        assert_eq!(book.sample(3)[2], KnownAddress { addr: addr(6001), last_seen: 10 });
+        for _ in 0..MAX_CONNECT_FAILURES {
+            book.failed(addr(6002));
+        }
+        assert_eq!(book.candidates(3, &[]), vec![addr(6003), addr(6001)]);
+        fs::remove_file(&path).unwrap();
+    }
+}
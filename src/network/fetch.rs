@@ -0,0 +1,90 @@
+use crate::crypto::hash::H256;
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// A block not received this long after it was asked is asked again from another peer
+pub const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+// Peers a missing block is asked from before giving up on it
+pub const MAX_FETCH_ATTEMPTS: usize = 4;
+
+struct Fetch {
+    sent: Instant,
+    // peers asked so far, the last one is the one we are waiting for
+    peers: Vec<SocketAddr>,
+}
+
+// Blocks the orphan buffer waits for that were asked from peers. The first request goes to
+// the peer that sent the orphan, retries go to the next peers we hear from.
+#[derive(Default)]
+pub struct Fetcher {
+    pending: HashMap<H256, Fetch>,
+}
+
+impl Fetcher {
+    // The blocks among `missing` that were not asked yet, they are now asked from `peer`
+    pub fn request(&mut self, missing: Vec<H256>, peer: SocketAddr, now: Instant) -> Vec<H256> {
+        let mut requested = vec![];
+        for hash in missing {
+            if let Entry::Vacant(entry) = self.pending.entry(hash) {
+                entry.insert(Fetch { sent: now, peers: vec![peer] });
+                requested.push(hash);
+            }
+        }
+        requested
+    }
+
+    // The timed out blocks to ask from `peer`, which was not asked for them yet. Blocks asked
+    // from MAX_FETCH_ATTEMPTS peers are given up, their orphans expire from the buffer.
+    pub fn retries(&mut self, peer: SocketAddr, now: Instant) -> Vec<H256> {
+        self.pending.retain(|_, fetch| {
+            fetch.peers.len() < MAX_FETCH_ATTEMPTS || now.duration_since(fetch.sent) < FETCH_TIMEOUT
+        });
+        let mut retries = vec![];
+        for (hash, fetch) in self.pending.iter_mut() {
+            if now.duration_since(fetch.sent) >= FETCH_TIMEOUT && !fetch.peers.contains(&peer) {
+                fetch.sent = now;
+                fetch.peers.push(peer);
+                retries.push(*hash);
+            }
+        }
+        retries
+    }
+
+    pub fn received(&mut self, hash: &H256) {
+        self.pending.remove(hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_blocks_are_retried_elsewhere() {
+        let peers: Vec<SocketAddr> = (0..6).map(|i| SocketAddr::from(([127, 0, 0, 1], 6000 + i))).collect();
+        let (a, b) = ([1; 32].into(), [2; 32].into());
+        let mut fetcher = Fetcher::default();
+        let mut now = Instant::now();
+
+        assert_eq!(fetcher.request(vec![a], peers[0], now), vec![a]);
+        assert_eq!(fetcher.request(vec![a, b], peers[1], now), vec![b]);
+        assert!(fetcher.retries(peers[2], now).is_empty());
+
+        now += FETCH_TIMEOUT;
+        fetcher.received(&b);
+        // the peer that was asked first is not asked again
+        assert!(fetcher.retries(peers[0], now).is_empty());
+        assert_eq!(fetcher.retries(peers[1], now), vec![a]);
+        for peer in &peers[2..4] {
+            now += FETCH_TIMEOUT;
+            assert_eq!(fetcher.retries(*peer, now), vec![a]);
+        }
+        now += FETCH_TIMEOUT;
+        assert!(fetcher.retries(peers[4], now).is_empty());
+        // given up blocks can be asked again when another orphan needs them
+        assert_eq!(fetcher.request(vec![a], peers[5], now), vec![a]);
+    }
+}
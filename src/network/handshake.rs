@@ -0,0 +1,120 @@
+use super::message::{Message, Version};
+use crate::block::genesis_proposer;
+use crate::crypto::hash::{H256, Hashable};
+
+use std::fmt;
+
+// Bumped whenever the messages change in a way older nodes cannot handle
+pub const PROTOCOL_VERSION: u32 = 2;
+
+// The Version a node announces to its peers
+pub fn local_version(node_id: u64, num_voter_chains: u32, listen_port: u16) -> Version {
+    Version {
+        protocol_version: PROTOCOL_VERSION,
+        node_id,
+        num_voter_chains,
+        genesis: genesis_hash(),
+        listen_port,
+    }
+}
+
+pub fn genesis_hash() -> H256 {
+    genesis_proposer().hash()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HandshakeError {
+    ProtocolVersion { ours: u32, theirs: u32 },
+    VoterChains { ours: u32, theirs: u32 },
+    Genesis(H256),
+    // the peer is this node, reached through another address
+    SelfConnection,
+    // a message other than Version/VerAck before the handshake completed, or a second Version
+    UnexpectedMessage,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HandshakeError::ProtocolVersion { ours, theirs } => write!(f, "protocol version {} instead of {}", theirs, ours),
+            HandshakeError::VoterChains { ours, theirs } => write!(f, "{} voter chains instead of {}", theirs, ours),
+            HandshakeError::Genesis(genesis) => write!(f, "unknown genesis block {}", genesis),
+            HandshakeError::SelfConnection => write!(f, "connected to itself"),
+            HandshakeError::UnexpectedMessage => write!(f, "unexpected message during the handshake"),
+        }
+    }
+}
+
+// Progress of the handshake with one peer. Both sides send their Version as soon as the
+// connection is up and answer the Version of the other with a VerAck. The peer is
+// established once its Version was accepted and it acknowledged ours.
+#[derive(Default)]
+pub struct Handshake {
+    pub version: Option<Version>,
+    acked: bool,
+}
+
+impl Handshake {
+    pub fn is_established(&self) -> bool {
+        self.version.is_some() && self.acked
+    }
+
+    // Process a message received before the peer is established, returns the reply to send
+    pub fn receive(&mut self, msg: Message, local: &Version) -> Result<Option<Message>, HandshakeError> {
+        match msg {
+            Message::Version(version) if self.version.is_none() => {
+                check_compatible(local, &version)?;
+                self.version = Some(version);
+                Ok(Some(Message::VerAck))
+            }
+            Message::VerAck if !self.acked => {
+                self.acked = true;
+                Ok(None)
+            }
+            _ => Err(HandshakeError::UnexpectedMessage),
+        }
+    }
+}
+
+fn check_compatible(local: &Version, remote: &Version) -> Result<(), HandshakeError> {
+    if remote.protocol_version != local.protocol_version {
+        return Err(HandshakeError::ProtocolVersion { ours: local.protocol_version, theirs: remote.protocol_version });
+    }
+    if remote.num_voter_chains != local.num_voter_chains {
+        return Err(HandshakeError::VoterChains { ours: local.num_voter_chains, theirs: remote.num_voter_chains });
+    }
+    if remote.genesis != local.genesis {
+        return Err(HandshakeError::Genesis(remote.genesis));
+    }
+    if remote.node_id == local.node_id {
+        return Err(HandshakeError::SelfConnection);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incompatible_peers_are_rejected() {
+        let local = local_version(1, 10, 6000);
+
+        let mut handshake = Handshake::default();
+        assert!(matches!(handshake.receive(Message::Ping("hi".to_string()), &local), Err(HandshakeError::UnexpectedMessage)));
+        assert!(matches!(handshake.receive(Message::Version(local_version(2, 10, 6000)), &local), Ok(Some(Message::VerAck))));
+        assert!(!handshake.is_established());
+        assert!(matches!(handshake.receive(Message::VerAck, &local), Ok(None)));
+        assert!(handshake.is_established());
+
+        let mut handshake = Handshake::default();
+        assert!(matches!(
+            handshake.receive(Message::Version(local_version(2, 40, 6000)), &local),
+            Err(HandshakeError::VoterChains { ours: 10, theirs: 40 })
+        ));
+        assert!(matches!(handshake.receive(Message::Version(local_version(1, 10, 6000)), &local), Err(HandshakeError::SelfConnection)));
+        let mut old = local_version(2, 10, 6000);
+        old.protocol_version = 0;
+        assert!(matches!(handshake.receive(Message::Version(old), &local), Err(HandshakeError::ProtocolVersion { .. })));
+    }
+}
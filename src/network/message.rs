@@ -2,9 +2,25 @@ use serde::{Serialize, Deserialize};
 use crate::crypto::hash::H256;
 use crate::block::Block;
 use crate::transaction::SignedTransaction;
+use super::sync::ChainStatus;
+use super::addrbook::KnownAddress;
+
+// Parameters a node announces when connecting, peers must agree on them
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Version {
+    pub protocol_version: u32,
+    // random id picked at startup, detects connections of a node to itself
+    pub node_id: u64,
+    pub num_voter_chains: u32,
+    pub genesis: H256,
+    // port of the P2P server, peers tell others about ip:listen_port
+    pub listen_port: u16,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
+    Version(Version),
+    VerAck,
     Ping(String),
     Pong(String),
     NewBlockHashes(Vec<H256>),
@@ -13,4 +29,11 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    GetStatus,
+    Status(ChainStatus),
+    // hashes of the blocks of a chain with a level in [start, end), chain 0 is the proposer chain
+    GetBlockHashes { chain: u32, start: u32, end: u32 },
+    BlockHashes { chain: u32, start: u32, hashes: Vec<H256> },
+    GetAddr,
+    Addr(Vec<KnownAddress>),
 }
@@ -0,0 +1,137 @@
+use crate::crypto::hash::H256;
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+// Peers reaching this score are disconnected and banned
+pub const BAN_SCORE: u32 = 100;
+// How long a ban lasts, in seconds
+pub const BAN_DURATION: u64 = 24 * 3600;
+// Blocks and transactions received this long after we asked for them count as unsolicited
+pub const REQUEST_EXPIRY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Misbehavior {
+    UndecodableMessage,
+    // frame longer than MAX_MESSAGE_SIZE
+    OversizedMessage,
+    // failed the PoW, sortition, difficulty or coinbase checks
+    InvalidBlock,
+    // invalid whatever the UTXO state, e.g. a bad signature
+    InvalidTransaction,
+    // blocks, transactions or block hashes we did not ask for
+    UnsolicitedData,
+}
+
+impl Misbehavior {
+    pub fn score(&self) -> u32 {
+        match self {
+            Misbehavior::UndecodableMessage => 100,
+            Misbehavior::OversizedMessage => 100,
+            Misbehavior::InvalidBlock => 100,
+            Misbehavior::InvalidTransaction => 20,
+            Misbehavior::UnsolicitedData => 10,
+        }
+    }
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Misbehavior::UndecodableMessage => write!(f, "undecodable message"),
+            Misbehavior::OversizedMessage => write!(f, "oversized message"),
+            Misbehavior::InvalidBlock => write!(f, "invalid block"),
+            Misbehavior::InvalidTransaction => write!(f, "invalid transaction"),
+            Misbehavior::UnsolicitedData => write!(f, "unsolicited data"),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Ban {
+    // IP address of the peer, whatever port it connects from or listens on
+    pub ip: IpAddr,
+    // UNIX time in seconds the ban expires at
+    pub until: u64,
+    pub reason: String,
+}
+
+// Hosts we refuse to connect to, both ways
+#[derive(Default)]
+pub struct BanList {
+    bans: HashMap<IpAddr, Ban>,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ban(&mut self, ip: IpAddr, reason: String, now: u64) {
+        self.bans.insert(ip, Ban { ip, until: now + BAN_DURATION, reason });
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr, now: u64) -> bool {
+        self.bans.get(ip).is_some_and(|ban| ban.until > now)
+    }
+
+    // The bans in force, expired ones are forgotten
+    pub fn bans(&mut self, now: u64) -> Vec<Ban> {
+        self.bans.retain(|_, ban| ban.until > now);
+        let mut bans: Vec<Ban> = self.bans.values().cloned().collect();
+        bans.sort_by_key(|ban| ban.until);
+        bans
+    }
+}
+
+// Hashes of the blocks and transactions recently asked from each peer. Peers only send
+// data we asked them for, data asked from another peer counts as unsolicited.
+#[derive(Default)]
+pub struct Solicited {
+    requests: HashMap<(SocketAddr, H256), Instant>,
+}
+
+impl Solicited {
+    pub fn ask(&mut self, peer: SocketAddr, hashes: &[H256], now: Instant) {
+        self.requests.retain(|_, asked| now.duration_since(*asked) < REQUEST_EXPIRY);
+        for hash in hashes {
+            self.requests.insert((peer, *hash), now);
+        }
+    }
+
+    pub fn contains(&self, peer: SocketAddr, hash: &H256, now: Instant) -> bool {
+        self.requests.get(&(peer, *hash)).is_some_and(|asked| now.duration_since(*asked) < REQUEST_EXPIRY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_and_requests_expire() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 6001));
+        let mut ban_list = BanList::new();
+        ban_list.ban(addr.ip(), Misbehavior::InvalidBlock.to_string(), 1000);
+        assert!(ban_list.is_banned(&addr.ip(), 1000 + BAN_DURATION - 1));
+        // the whole host is banned, not only the port it used
+        assert!(ban_list.is_banned(&SocketAddr::from(([127, 0, 0, 1], 7001)).ip(), 1000));
+        assert_eq!(ban_list.bans(1000)[0].reason, "invalid block");
+        assert!(!ban_list.is_banned(&addr.ip(), 1000 + BAN_DURATION));
+        assert!(ban_list.bans(1000 + BAN_DURATION).is_empty());
+
+        let hash: H256 = [1; 32].into();
+        let other = SocketAddr::from(([127, 0, 0, 1], 6002));
+        let now = Instant::now();
+        let mut solicited = Solicited::default();
+        assert!(!solicited.contains(addr, &hash, now));
+        solicited.ask(addr, &[hash], now);
+        assert!(solicited.contains(addr, &hash, now + REQUEST_EXPIRY / 2));
+        // only the peer that was asked may send it
+        assert!(!solicited.contains(other, &hash, now));
+        assert!(!solicited.contains(addr, &hash, now + REQUEST_EXPIRY));
+    }
+}
@@ -1,4 +1,9 @@
+pub mod addrbook;
+pub mod fetch;
+pub mod handshake;
 pub mod message;
+pub mod misbehavior;
 pub mod peer;
 pub mod server;
+pub mod sync;
 pub mod worker;
@@ -1,4 +1,6 @@
+use super::handshake::Handshake;
 use super::message;
+use crate::metrics::metrics;
 use log::{trace, warn};
 use mio;
 use mio_extras::channel;
@@ -6,6 +8,9 @@ use std::convert::TryInto;
 use std::io::{Read, Write};
 use std::sync::mpsc;
 
+// Frames announcing a longer payload are refused before anything is allocated for them
+pub const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+
 enum DecodeState {
     Length,
     Payload,
@@ -14,6 +19,8 @@ enum DecodeState {
 pub enum ReadResult {
     Continue,
     Message(Vec<u8>),
+    // the length prefix exceeds MAX_MESSAGE_SIZE, the stream cannot be read any further
+    Oversized(usize),
     EOF,
 }
 
@@ -37,6 +44,7 @@ impl ReadContext {
             }
             Ok(size) => {
                 trace!("Read {} bytes from socket", size);
+                metrics().bytes_received.inc_by(size as u64);
                 // we got some data, move the cursor
                 self.read_length += size;
                 if self.read_length == self.msg_length {
@@ -45,6 +53,9 @@ impl ReadContext {
                         DecodeState::Length => {
                             let message_length =
                                 u32::from_be_bytes(self.buffer[0..4].try_into().unwrap());
+                            if message_length as usize > MAX_MESSAGE_SIZE {
+                                return Ok(ReadResult::Oversized(message_length as usize));
+                            }
                             self.state = DecodeState::Payload;
                             self.read_length = 0;
                             self.msg_length = message_length as usize;
@@ -60,6 +71,7 @@ impl ReadContext {
                             self.read_length = 0;
                             self.msg_length = std::mem::size_of::<u32>();
                             trace!("Received full message");
+                            metrics().messages_received.inc();
                             Ok(ReadResult::Message(new_payload))
                         }
                     }
@@ -112,6 +124,7 @@ impl WriteContext {
                             return Ok(WriteResult::EOF);
                         }
                         self.written_length += written;
+                        metrics().bytes_sent.inc_by(written as u64);
                         continue;
                     }
                 }
@@ -131,6 +144,7 @@ impl WriteContext {
                         };
 
                         // encode the message and the length
+                        metrics().messages_sent.inc();
                         self.msg_buffer = msg;
                         self.msg_length = self.msg_buffer.len();
                         self.len_buffer[..4]
@@ -147,6 +161,7 @@ impl WriteContext {
                             return Ok(WriteResult::EOF);
                         }
                         self.written_length += written;
+                        metrics().bytes_sent.inc_by(written as u64);
                         continue;
                     }
                 }
@@ -192,6 +207,8 @@ pub fn new(
         writer: write_ctx,
         handle: handle.clone(),
         direction,
+        handshake: Handshake::default(),
+        score: 0,
     };
     Ok((ctx, handle))
 }
@@ -209,6 +226,21 @@ pub struct Context {
     pub writer: WriteContext,
     pub handle: Handle,
     pub direction: Direction,
+    // messages are only passed to the workers once the handshake completed
+    pub handshake: Handshake,
+    // misbehavior score, the peer is banned once it reaches BAN_SCORE
+    pub score: u32,
+}
+
+impl Context {
+    // Address of the P2P server of the peer. Incoming peers connect from another port than
+    // the one they listen on, which they announce in their Version.
+    pub fn server_addr(&self) -> std::net::SocketAddr {
+        match (self.direction, &self.handshake.version) {
+            (Direction::Incoming, Some(version)) => std::net::SocketAddr::new(self.addr.ip(), version.listen_port),
+            _ => self.addr,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -218,6 +250,10 @@ pub struct Handle {
 }
 
 impl Handle {
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
     pub fn write(&self, msg: message::Message) {
         // TODO: return result
         let buffer = bincode::serialize(&msg).unwrap();
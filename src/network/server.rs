@@ -1,18 +1,32 @@
+use super::addrbook::{self, AddressBook, MAX_ADDRESSES};
+use super::handshake::HandshakeError;
 use super::message;
+use super::misbehavior::{BanList, Misbehavior, BAN_SCORE};
 use super::peer::{self, ReadResult, WriteResult};
+use crate::metrics::metrics;
+use crate::events::{emit, Event};
 use crossbeam::channel as cbchannel;
 use log::{info, error, debug, trace, warn};
 use mio::{self, net};
 use mio_extras::channel;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
 const MAX_INCOMING_CLIENT: usize = 256;
 const MAX_EVENT: usize = 1024;
+// Unreachable addresses are given up after this long
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+// How often the outbound peers are topped up and the address book saved
+const OUTBOUND_INTERVAL: Duration = Duration::from_secs(10);
 
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    version: message::Version,
+    addr_book: &Arc<Mutex<AddressBook>>,
+    ban_list: &Arc<Mutex<BanList>>,
+    target_outbound: usize,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = channel::channel();
     let handle = Handle {
@@ -25,6 +39,10 @@ pub fn new(
         poll: mio::Poll::new()?,
         control_chan: control_signal_receiver,
         new_msg_chan: msg_sink,
+        version,
+        addr_book: Arc::clone(addr_book),
+        ban_list: Arc::clone(ban_list),
+        target_outbound,
         _handle: handle.clone(),
     };
     Ok((ctx, handle))
@@ -37,12 +55,31 @@ pub struct Context {
     poll: mio::Poll,
     control_chan: channel::Receiver<ControlSignal>,
     new_msg_chan: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    // announced to every peer in the handshake
+    version: message::Version,
+    addr_book: Arc<Mutex<AddressBook>>,
+    ban_list: Arc<Mutex<BanList>>,
+    // outbound connections the server keeps, new peers are picked from the address book
+    target_outbound: usize,
     _handle: Handle,
 }
 
 impl Context {
     /// Start a new server context.
     pub fn start(mut self) -> std::io::Result<()> {
+        if self.target_outbound > 0 {
+            let handle = self._handle.clone();
+            let addr_book = Arc::clone(&self.addr_book);
+            let ban_list = Arc::clone(&self.ban_list);
+            let (local, target) = (self.addr, self.target_outbound);
+            thread::spawn(move || loop {
+                maintain_outbound(&handle, &addr_book, &ban_list, local, target);
+                if let Err(e) = addr_book.lock().unwrap().save() {
+                    warn!("Error saving the address book: {}", e);
+                }
+                thread::sleep(OUTBOUND_INTERVAL);
+            });
+        }
         thread::spawn(move || {
             self.listen().unwrap_or_else(|e| {
                 error!("P2P server error: {}", e);
@@ -80,6 +117,10 @@ impl Context {
             mio::PollOpt::edge(),
         )?;
         let (ctx, handle) = peer::new(stream, direction)?;
+        emit(Event::PeerConnected {
+            addr: ctx.addr.to_string(),
+            outgoing: matches!(direction, peer::Direction::Outgoing),
+        });
 
         // register the writer queue
         self.poll.register(
@@ -89,19 +130,24 @@ impl Context {
             mio::PollOpt::edge() | mio::PollOpt::oneshot(),
         )?;
 
+        // the handshake starts with our version, in both directions
+        handle.write(message::Message::Version(self.version.clone()));
+
         // insert the context and return the handle
         vacant.insert(ctx);
         // record the key of this peer
         self.peer_list.push(key);
+        metrics().peers.set(self.peer_list.len() as i64);
         trace!("Registering peer with event token={}", key);
         Ok(handle)
     }
 
-    /// Connect to a peer, and register this peer
-    fn connect(&mut self, addr: &std::net::SocketAddr) -> std::io::Result<peer::Handle> {
-        // we need to estabilsh a stdlib tcp stream, since we need it to block
-        println!("Establishing connection to peer {}", addr);
-        let stream = std::net::TcpStream::connect(addr)?;
+    /// Register a stream connected to a peer by `Handle::connect`
+    fn connect(&mut self, stream: std::net::TcpStream) -> std::io::Result<peer::Handle> {
+        let ip = stream.peer_addr()?.ip();
+        if self.ban_list.lock().unwrap().is_banned(&ip, addrbook::now()) {
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("{} is banned", ip)));
+        }
         let mio_stream = net::TcpStream::from_stream(stream)?;
         self.register(mio_stream, peer::Direction::Outgoing)
     }
@@ -112,6 +158,11 @@ impl Context {
         stream: net::TcpStream,
         addr: std::net::SocketAddr,
     ) -> std::io::Result<()> {
+        // nothing a banned host sends is read, not even its Version
+        if self.ban_list.lock().unwrap().is_banned(&addr.ip(), addrbook::now()) {
+            debug!("Refusing connection from banned host {}", addr);
+            return Ok(());
+        }
         info!("New incoming connection from {}", addr);
         match self.register(stream, peer::Direction::Incoming) {
             Ok(_) => {
@@ -128,13 +179,30 @@ impl Context {
         match req {
             ControlSignal::ConnectNewPeer(req) => {
                 trace!("Processing ConnectNewPeer command");
-                let handle = self.connect(&req.addr);
+                let handle = self.connect(req.stream);
                 req.result_chan.send(handle).unwrap();
             }
+            ControlSignal::GetPeers(result_chan) => {
+                trace!("Processing GetPeers command");
+                let peers = self.peer_list.iter()
+                    .map(|peer_id| {
+                        let peer = &self.peers[*peer_id];
+                        (peer.server_addr(), peer.direction)
+                    })
+                    .collect();
+                result_chan.send(peers).unwrap();
+            }
+            ControlSignal::Penalize(addr, misbehavior) => {
+                trace!("Processing Penalize command");
+                self.penalize(addr, misbehavior);
+            }
             ControlSignal::BroadcastMessage(msg) => {
                 trace!("Processing BroadcastMessage command");
                 for peer_id in &self.peer_list {
-                    self.peers[*peer_id].handle.write(msg.clone());
+                    let peer = &self.peers[*peer_id];
+                    if peer.handshake.is_established() {
+                        peer.handle.write(msg.clone());
+                    }
                 }
             }
         }
@@ -156,17 +224,80 @@ impl Context {
         Ok(())
     }
 
+    // Remove a peer from the connections set
+    fn drop_peer(&mut self, peer_id: usize) {
+        let peer = self.peers.remove(peer_id);
+        emit(Event::PeerDisconnected { addr: peer.addr.to_string() });
+        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+        self.peer_list.swap_remove(index);
+    }
+
+    // Raise the score of the peer connected from `addr`, disconnect and ban it once too high
+    fn penalize(&mut self, addr: std::net::SocketAddr, misbehavior: Misbehavior) {
+        let peer_id = match self.peer_list.iter().find(|peer_id| self.peers[**peer_id].addr == addr) {
+            Some(peer_id) => *peer_id,
+            // already disconnected
+            None => return,
+        };
+        let peer = &mut self.peers[peer_id];
+        peer.score += misbehavior.score();
+        warn!("Peer {} misbehaved: {}, score {}", addr, misbehavior, peer.score);
+        if peer.score >= BAN_SCORE {
+            let server_addr = peer.server_addr();
+            self.ban_list.lock().unwrap().ban(addr.ip(), misbehavior.to_string(), addrbook::now());
+            self.addr_book.lock().unwrap().remove(&server_addr);
+            emit(Event::PeerBanned { addr: addr.ip().to_string(), reason: misbehavior.to_string() });
+            // every connection of the host goes
+            let banned: Vec<usize> = self.peer_list.iter()
+                .filter(|peer_id| self.peers[**peer_id].addr.ip() == addr.ip())
+                .cloned()
+                .collect();
+            for peer_id in banned {
+                self.drop_peer(peer_id);
+            }
+            metrics().peers.set(self.peer_list.len() as i64);
+        }
+    }
+
+    // Handle a message of a peer which is not established yet
+    fn process_handshake(&mut self, peer_id: usize, msg: &[u8]) -> Result<(), String> {
+        let msg: message::Message = bincode::deserialize(msg).map_err(|e| e.to_string())?;
+        let peer = &mut self.peers[peer_id];
+        match peer.handshake.receive(msg, &self.version) {
+            Ok(reply) => {
+                if let Some(reply) = reply {
+                    peer.handle.write(reply);
+                }
+            }
+            Err(e) => {
+                // the address is of ourselves or of a node on another chain, don't retry it
+                if e != HandshakeError::UnexpectedMessage {
+                    self.addr_book.lock().unwrap().remove(&peer.server_addr());
+                }
+                return Err(e.to_string());
+            }
+        }
+        if peer.handshake.is_established() {
+            info!("Handshake with peer {} complete", peer.addr);
+            self.addr_book.lock().unwrap().seen(peer.server_addr(), addrbook::now());
+            // start syncing with the chains of the peer
+            peer.handle.write(message::Message::GetStatus);
+            if let peer::Direction::Outgoing = peer.direction {
+                peer.handle.write(message::Message::GetAddr);
+            }
+        }
+        Ok(())
+    }
+
     fn process_readable(&mut self, peer_id: usize) -> std::io::Result<()> {
         // we are using edge-triggered events, loop until block
-        let peer = &mut self.peers[peer_id];
         loop {
+            let peer = &mut self.peers[peer_id];
             match peer.reader.read() {
                 Ok(ReadResult::EOF) => {
                     // EOF, remove it from the connections set
-                    println!("Peer {} dropped connection", peer.addr);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    info!("Peer {} dropped connection", peer.addr);
+                    self.drop_peer(peer_id);
                     break;
                 }
                 Ok(ReadResult::Continue) => {
@@ -177,9 +308,25 @@ impl Context {
                 Ok(ReadResult::Message(m)) => {
                     trace!("Peer {} yield message", peer_id);
                     // we just received a full message
-                    self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
+                    if peer.handshake.is_established() {
+                        self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
+                    } else if let Err(e) = self.process_handshake(peer_id, &m) {
+                        warn!("Handshake with peer {} failed, disconnecting: {}", self.peers[peer_id].addr, e);
+                        self.drop_peer(peer_id);
+                        break;
+                    }
                     continue;
                 }
+                Ok(ReadResult::Oversized(length)) => {
+                    warn!("Peer {} sent a {} bytes message, disconnecting", peer.addr, length);
+                    let addr = peer.addr;
+                    self.penalize(addr, Misbehavior::OversizedMessage);
+                    // the rest of the stream cannot be framed, even if the peer was not banned
+                    if self.peer_list.contains(&peer_id) {
+                        self.drop_peer(peer_id);
+                    }
+                    break;
+                }
                 Err(e) => {
                     if e.kind() == std::io::ErrorKind::WouldBlock {
                         trace!("Peer {} finished reading", peer_id);
@@ -187,14 +334,13 @@ impl Context {
                         break;
                     } else {
                         warn!("Error reading peer {}, disconnecting: {}", peer.addr, e);
-                        self.peers.remove(peer_id);
-                        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                        self.peer_list.swap_remove(index);
+                        self.drop_peer(peer_id);
                         break;
                     }
                 }
             }
         }
+        metrics().peers.set(self.peer_list.len() as i64);
         Ok(())
     }
 
@@ -223,10 +369,8 @@ impl Context {
             }
             Ok(WriteResult::EOF) => {
                 // EOF, remove it from the connections set
-                println!("Peer {} dropped connection", peer.addr);
-                self.peers.remove(peer_id);
-                let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                self.peer_list.swap_remove(index);
+                info!("Peer {} dropped connection", peer.addr);
+                self.drop_peer(peer_id);
             }
             Ok(WriteResult::ChanClosed) => {
                 // the channel is closed. no more writes.
@@ -246,12 +390,11 @@ impl Context {
                 // socket is not ready anymore, stop reading
                 } else {
                     warn!("Error writing peer {}, disconnecting: {}", peer.addr, e);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.drop_peer(peer_id);
                 }
             }
         }
+        metrics().peers.set(self.peer_list.len() as i64);
         Ok(())
     }
 
@@ -370,10 +513,14 @@ pub struct Handle {
 }
 
 impl Handle {
+    // Connect to a peer and register it in the server. The TCP connection is established on
+    // the calling thread, the event loop only registers the stream.
     pub fn connect(&self, addr: std::net::SocketAddr) -> std::io::Result<peer::Handle> {
+        info!("Establishing connection to peer {}", addr);
+        let stream = std::net::TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
         let (sender, receiver) = cbchannel::unbounded();
         let request = ConnectRequest {
-            addr,
+            stream,
             result_chan: sender,
         };
         self.control_chan
@@ -382,6 +529,22 @@ impl Handle {
         receiver.recv().unwrap()
     }
 
+    // Report a misbehavior of the peer connected from `addr`
+    pub fn penalize(&self, addr: std::net::SocketAddr, misbehavior: Misbehavior) {
+        self.control_chan
+            .send(ControlSignal::Penalize(addr, misbehavior))
+            .unwrap();
+    }
+
+    // Server addresses and directions of the connected peers
+    pub fn peers(&self) -> Vec<(std::net::SocketAddr, peer::Direction)> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan
+            .send(ControlSignal::GetPeers(sender))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
+
     pub fn broadcast(&self, msg: message::Message) {
         self.control_chan
             .send(ControlSignal::BroadcastMessage(msg))
@@ -391,10 +554,45 @@ impl Handle {
 
 enum ControlSignal {
     ConnectNewPeer(ConnectRequest),
+    GetPeers(cbchannel::Sender<Vec<(std::net::SocketAddr, peer::Direction)>>),
+    Penalize(std::net::SocketAddr, Misbehavior),
     BroadcastMessage(message::Message),
 }
 
 struct ConnectRequest {
-    addr: std::net::SocketAddr,
+    stream: std::net::TcpStream,
     result_chan: cbchannel::Sender<std::io::Result<peer::Handle>>,
 }
+
+// Connect to peers from the address book until `target` outbound peers are connected
+fn maintain_outbound(
+    handle: &Handle,
+    addr_book: &Arc<Mutex<AddressBook>>,
+    ban_list: &Arc<Mutex<BanList>>,
+    local: std::net::SocketAddr,
+    target: usize,
+) {
+    let peers = handle.peers();
+    let outbound = peers.iter().filter(|(_, direction)| matches!(direction, peer::Direction::Outgoing)).count();
+    if outbound >= target {
+        return;
+    }
+    let mut exclude: Vec<std::net::SocketAddr> = peers.into_iter().map(|(addr, _)| addr).collect();
+    exclude.push(local);
+    let now = addrbook::now();
+    let locked_ban_list = ban_list.lock().unwrap();
+    let candidates: Vec<std::net::SocketAddr> = addr_book.lock().unwrap().candidates(MAX_ADDRESSES, &exclude).into_iter()
+        .filter(|addr| !locked_ban_list.is_banned(&addr.ip(), now))
+        .take(target - outbound)
+        .collect();
+    drop(locked_ban_list);
+    for addr in candidates {
+        match handle.connect(addr) {
+            Ok(_) => info!("Connected to outgoing peer {} from the address book", addr),
+            Err(e) => {
+                debug!("Error connecting to peer {} from the address book: {}", addr, e);
+                addr_book.lock().unwrap().failed(addr);
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use super::message::Message;
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::H256;
+use serde::{Serialize, Deserialize};
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// Levels of one chain covered by a GetBlockHashes request
+pub const SYNC_BATCH_LEVELS: u32 = 50;
+// Requests a peer may have unanswered at the same time
+pub const MAX_PEER_REQUESTS: usize = 4;
+// Unanswered requests are handed to another peer after this long
+pub const SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Depth of the proposer chain and of every voter chain of a node
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChainStatus {
+    pub proposer_depth: u32,
+    pub voter_depths: Vec<u32>,
+}
+
+impl ChainStatus {
+    pub fn new(blockchain: &Blockchain) -> Self {
+        ChainStatus {
+            proposer_depth: blockchain.proposer_depth,
+            voter_depths: blockchain.voter_depths.clone(),
+        }
+    }
+
+    // Depth of a chain, 0 is the proposer chain and voter chains are numbered from 1
+    pub fn depth(&self, chain: u32) -> u32 {
+        match chain {
+            0 => self.proposer_depth,
+            c => self.voter_depths.get(c as usize - 1).cloned().unwrap_or(0),
+        }
+    }
+
+    fn num_chains(&self) -> u32 {
+        self.voter_depths.len() as u32 + 1
+    }
+}
+
+struct Request {
+    peer: SocketAddr,
+    sent: Instant,
+    // levels below `end` are covered once the request is answered
+    end: u32,
+    answered: bool,
+}
+
+// Catches the node up with the chains of its peers. The levels of every chain are split in
+// batches of SYNC_BATCH_LEVELS, the hashes of each batch are asked from one peer that is
+// deep enough, so that different batches are fetched from different peers in parallel.
+#[derive(Default)]
+pub struct SyncState {
+    peers: HashMap<SocketAddr, ChainStatus>,
+    // requests by (chain, first level of the batch)
+    requests: HashMap<(u32, u32), Request>,
+}
+
+impl SyncState {
+    pub fn peer_status(&mut self, peer: SocketAddr, status: ChainStatus) {
+        self.peers.insert(peer, status);
+    }
+
+    // The GetBlockHashes to send to `peer` for the levels we are missing, given our own depths
+    pub fn next_requests(&mut self, peer: SocketAddr, ours: &ChainStatus, now: Instant) -> Vec<Message> {
+        let theirs = match self.peers.get(&peer) {
+            Some(status) => status.clone(),
+            None => return vec![],
+        };
+        let mut in_flight = self.requests.values()
+            .filter(|r| r.peer == peer && !r.answered && now.duration_since(r.sent) < SYNC_TIMEOUT)
+            .count();
+        let mut messages = vec![];
+        for chain in 0..theirs.num_chains() {
+            let depth = theirs.depth(chain);
+            if depth <= ours.depth(chain) {
+                continue;
+            }
+            let mut start = ours.depth(chain) / SYNC_BATCH_LEVELS * SYNC_BATCH_LEVELS;
+            while start <= depth && in_flight < MAX_PEER_REQUESTS {
+                let end = std::cmp::min(start + SYNC_BATCH_LEVELS, depth + 1);
+                let needed = match self.requests.get(&(chain, start)) {
+                    None => true,
+                    Some(r) if r.answered => r.end < end,
+                    Some(r) => now.duration_since(r.sent) >= SYNC_TIMEOUT,
+                };
+                if needed {
+                    self.requests.insert((chain, start), Request { peer, sent: now, end, answered: false });
+                    messages.push(Message::GetBlockHashes { chain, start, end });
+                    in_flight += 1;
+                }
+                start += SYNC_BATCH_LEVELS;
+            }
+        }
+        messages
+    }
+
+    // Record the answer of `peer` to the request of the batch starting at `start`, returns
+    // false if the batch was never requested. Late answers of peers the request was taken
+    // from after the timeout are accepted too.
+    pub fn received(&mut self, peer: SocketAddr, chain: u32, start: u32) -> bool {
+        match self.requests.get_mut(&(chain, start)) {
+            Some(request) => {
+                if request.peer == peer {
+                    request.answered = true;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Whether `peer` has requests it did not answer yet
+    pub fn is_waiting(&self, peer: SocketAddr) -> bool {
+        self.requests.values().any(|r| r.peer == peer && !r.answered)
+    }
+}
+
+// Hashes of the blocks of a chain with a level in [start, end), parents before children
+pub fn block_hashes(blockchain: &Blockchain, chain: u32, start: u32, end: u32) -> Vec<H256> {
+    let end = std::cmp::min(end, start.saturating_add(SYNC_BATCH_LEVELS));
+    if chain == 0 {
+        (start..end)
+            .filter_map(|level| blockchain.level2allproposers.get(&level))
+            .flatten()
+            .cloned()
+            .collect()
+    } else {
+        match blockchain.voter_chains.get(chain as usize - 1) {
+            Some(voter_chain) => {
+                let mut blocks: Vec<(u32, H256)> = voter_chain.iter()
+                    .filter(|(_, metablock)| metablock.level >= start && metablock.level < end)
+                    .map(|(hash, metablock)| (metablock.level, *hash))
+                    .collect();
+                blocks.sort();
+                blocks.into_iter().map(|(_, hash)| hash).collect()
+            }
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_are_spread_over_peers() {
+        let ours = ChainStatus { proposer_depth: 1, voter_depths: vec![1] };
+        let peer1 = SocketAddr::from(([127, 0, 0, 1], 6001));
+        let peer2 = SocketAddr::from(([127, 0, 0, 1], 6002));
+        let mut sync = SyncState::default();
+        sync.peer_status(peer1, ChainStatus { proposer_depth: 299, voter_depths: vec![10] });
+        sync.peer_status(peer2, ChainStatus { proposer_depth: 299, voter_depths: vec![10] });
+        let now = Instant::now();
+
+        let starts = |messages: Vec<Message>| -> Vec<(u32, u32)> {
+            messages.into_iter().map(|m| match m {
+                Message::GetBlockHashes { chain, start, .. } => (chain, start),
+                _ => panic!("unexpected message"),
+            }).collect()
+        };
+        assert_eq!(starts(sync.next_requests(peer1, &ours, now)), vec![(0, 0), (0, 50), (0, 100), (0, 150)]);
+        assert_eq!(starts(sync.next_requests(peer2, &ours, now)), vec![(0, 200), (0, 250), (1, 0)]);
+        assert!(sync.next_requests(peer2, &ours, now).is_empty());
+
+        // answered batches are not asked again, the peer has room for new requests
+        assert!(sync.received(peer1, 0, 0));
+        assert!(!sync.received(peer1, 0, 300));
+        assert!(sync.next_requests(peer1, &ours, now).is_empty());
+        // unanswered batches move to another peer after the timeout
+        sync.received(peer2, 1, 0);
+        let later = now + SYNC_TIMEOUT;
+        assert_eq!(starts(sync.next_requests(peer2, &ours, later)), vec![(0, 50), (0, 100), (0, 150), (0, 200)]);
+    }
+}
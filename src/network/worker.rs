@@ -1,18 +1,28 @@
 // use super::buffer::BlockBuffer;
 use super::message::Message;
+use super::addrbook::{AddressBook, MAX_ADDR_REPLY};
+use super::fetch::Fetcher;
+use super::misbehavior::{Misbehavior, Solicited};
 use super::peer;
+use super::sync::{self, ChainStatus, SyncState};
 use crate::network::server::Handle as ServerHandle;
 use crate::blockchain::{Blockchain, InsertStatus};
 use crate::block::*;
 use crate::transaction::SignedTransaction;
 use crate::mempool::TransactionMempool;
+use crate::utxo::UtxoState;
 use crate::crypto::hash::{H256, Hashable};
+use crate::metrics::metrics;
+use crate::events::{emit, Event};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use std::collections::{HashMap, HashSet};
 // use crate::validation::{BlockResult};
 use crossbeam::channel;
 use log::{info,debug, warn};
 use crate::validation::{BlockResult, check_pow_sortition_id, check_sortition_proof};
+use crate::validation::transaction::{check_tx, TxError};
 
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
@@ -21,8 +31,28 @@ pub struct Context {
     msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
+    handler: Handler,
+}
+
+// What to do after handling a message from a peer
+pub enum Outgoing {
+    Reply(Message),
+    Broadcast(Message),
+    // the peer misbehaved, its score is raised and it is banned once the score is too high
+    Penalize(Misbehavior),
+}
+
+// Handles the messages of the protocol against the node state. Shared by the worker
+// threads and the simulator, which delivers the outgoing messages itself.
+#[derive(Clone)]
+pub struct Handler {
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<TransactionMempool>>,
+    utxo_state: Arc<Mutex<UtxoState>>,
+    sync: Arc<Mutex<SyncState>>,
+    fetcher: Arc<Mutex<Fetcher>>,
+    addr_book: Arc<Mutex<AddressBook>>,
+    solicited: Arc<Mutex<Solicited>>,
 }
 
 pub fn new(
@@ -31,13 +61,14 @@ pub fn new(
     server: &ServerHandle,
     blockchain: &Arc<Mutex<Blockchain>>,
     mempool: &Arc<Mutex<TransactionMempool>>,
+    utxo_state: &Arc<Mutex<UtxoState>>,
+    addr_book: &Arc<Mutex<AddressBook>>,
 ) -> Context {
     Context {
         msg_chan: msg_src,
         num_worker,
         server: server.clone(),
-        blockchain: Arc::clone(blockchain),
-        mempool: Arc::clone(mempool),
+        handler: Handler::new(blockchain, mempool, utxo_state, addr_book),
     }
 }
 
@@ -57,141 +88,334 @@ impl Context {
         loop {
             let msg = self.msg_chan.recv().unwrap();
             let (msg, peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
-            
-            match msg {
-                Message::Ping(nonce) => {
-                    println!("Ping: {}", nonce);
-                    peer.write(Message::Pong(nonce.to_string()));
-                }
-                Message::Pong(nonce) => {
-                    println!("Pong: {}", nonce);
-                }
-                Message::NewBlockHashes(vec_hashes) => {
-                    let mut req_blocks = Vec::new();
-                    let locked_blockchain = self.blockchain.lock().unwrap();
-                    for block_hash in vec_hashes {
-                        if (!locked_blockchain.has_block(block_hash)) {
-                            req_blocks.push(block_hash);
-                        }
-                    }
-                    drop(locked_blockchain);
+            let msg: Message = match bincode::deserialize(&msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Undecodable message from peer {}: {}", peer.addr(), e);
+                    self.server.penalize(peer.addr(), Misbehavior::UndecodableMessage);
+                    continue;
+                }
+            };
+            for outgoing in self.handler.handle(msg, peer.addr()) {
+                match outgoing {
+                    Outgoing::Reply(msg) => peer.write(msg),
+                    Outgoing::Broadcast(msg) => self.server.broadcast(msg),
+                    Outgoing::Penalize(misbehavior) => self.server.penalize(peer.addr(), misbehavior),
+                }
+            }
+        }
+    }
+}
+
+impl Handler {
+    pub fn new(
+        blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<TransactionMempool>>,
+        utxo_state: &Arc<Mutex<UtxoState>>,
+        addr_book: &Arc<Mutex<AddressBook>>,
+    ) -> Self {
+        Handler {
+            blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            utxo_state: Arc::clone(utxo_state),
+            sync: Arc::new(Mutex::new(SyncState::default())),
+            fetcher: Arc::new(Mutex::new(Fetcher::default())),
+            addr_book: Arc::clone(addr_book),
+            solicited: Arc::new(Mutex::new(Solicited::default())),
+        }
+    }
 
-                    if req_blocks.len() > 0 {
-                        peer.write(Message::GetBlocks(req_blocks));
+    fn get_blocks(&self, peer: SocketAddr, hashes: Vec<H256>) -> Outgoing {
+        self.solicited.lock().unwrap().ask(peer, &hashes, Instant::now());
+        Outgoing::Reply(Message::GetBlocks(hashes))
+    }
+
+    fn get_transactions(&self, peer: SocketAddr, hashes: Vec<H256>) -> Outgoing {
+        self.solicited.lock().unwrap().ask(peer, &hashes, Instant::now());
+        Outgoing::Reply(Message::GetTransactions(hashes))
+    }
+
+    // Whether all the hashes were asked from `peer` recently
+    fn solicited(&self, peer: SocketAddr, mut hashes: impl Iterator<Item = H256>) -> bool {
+        let now = Instant::now();
+        let locked_solicited = self.solicited.lock().unwrap();
+        hashes.all(|hash| locked_solicited.contains(peer, &hash, now))
+    }
+
+    // The level ranges to ask `peer` for, in GetBlockHashes messages
+    fn sync_requests(&self, peer: SocketAddr) -> Vec<Outgoing> {
+        let ours = ChainStatus::new(&self.blockchain.lock().unwrap());
+        let mut locked_sync = self.sync.lock().unwrap();
+        locked_sync.next_requests(peer, &ours, Instant::now()).into_iter().map(Outgoing::Reply).collect()
+    }
+
+    // Process a message received from `peer` and return the messages to send in response
+    pub fn handle(&self, msg: Message, peer: SocketAddr) -> Vec<Outgoing> {
+        let mut outgoing = Vec::new();
+        // blocks other peers failed to send us are asked from this one
+        let retries = self.fetcher.lock().unwrap().retries(peer, Instant::now());
+        if !retries.is_empty() {
+            outgoing.push(self.get_blocks(peer, retries));
+        }
+        match msg {
+            // handled by the server before the peer reaches the workers
+            Message::Version(_) | Message::VerAck => {
+                debug!("Ignoring handshake message from established peer {}", peer);
+            }
+            Message::Ping(nonce) => {
+                debug!("Ping: {}", nonce);
+                outgoing.push(Outgoing::Reply(Message::Pong(nonce.to_string())));
+            }
+            Message::Pong(nonce) => {
+                debug!("Pong: {}", nonce);
+            }
+            Message::NewBlockHashes(vec_hashes) => {
+                let mut req_blocks = Vec::new();
+                let locked_blockchain = self.blockchain.lock().unwrap();
+                for block_hash in vec_hashes {
+                    if !locked_blockchain.has_block(block_hash) {
+                        req_blocks.push(block_hash);
                     }
                 }
+                drop(locked_blockchain);
 
-                Message::GetBlocks(vec_hashes) => {
-                    let mut newblocks: Vec<Block> = Vec::new();
-                    let locked_blockchain = self.blockchain.lock().unwrap();
-                    for block_hash in vec_hashes {
-                        let result = locked_blockchain.get_block(block_hash);
-                        match result {
-                            Some(block) => {
-                                newblocks.push(block.clone());
-                            }
-                            None => {
-                                println!("blocksdb does not contain {}", block_hash);
-                            }
+                if !req_blocks.is_empty() {
+                    outgoing.push(self.get_blocks(peer, req_blocks));
+                }
+            }
+
+            Message::GetBlocks(vec_hashes) => {
+                let mut newblocks: Vec<Block> = Vec::new();
+                let locked_blockchain = self.blockchain.lock().unwrap();
+                for block_hash in vec_hashes {
+                    let result = locked_blockchain.get_block(block_hash);
+                    match result {
+                        Some(block) => {
+                            newblocks.push(block.clone());
+                        }
+                        None => {
+                            debug!("blocksdb does not contain {}", block_hash);
                         }
                     }
-                    drop(locked_blockchain);
+                }
+                drop(locked_blockchain);
 
-                    if newblocks.len() > 0 {
-                        peer.write(Message::Blocks(newblocks));
-                    }
+                if !newblocks.is_empty() {
+                    outgoing.push(Outgoing::Reply(Message::Blocks(newblocks)));
                 }
+            }
 
-                Message::Blocks(vec_blocks) => {
-                    let mut locked_blockchain = self.blockchain.lock().unwrap();
-                    let num_voter_chains = locked_blockchain.num_voter_chains;
-                    let mut valid_block_hashes: Vec<H256> = Vec::new();
-                    for block in vec_blocks {
-                        let block_hash = block.hash();
-                        if (!locked_blockchain.has_block(block_hash)) {
-                            // perform validation checks -- hash < difficulty, sortition id, sortition proof
-                            let result = check_pow_sortition_id(&block, num_voter_chains);
-                            match result {
-                                BlockResult::Fail => {
-                                    println!("Invalid block {:?} pow/sortition failed", block_hash);
-                                    continue;
-                                }
-                                BlockResult::Pass => {
-                                    // println!("pow/sortition passed {:?}", block_hash);
-                                    let result2 = check_sortition_proof(&block, num_voter_chains);
-                                    match result2 {
-                                        BlockResult::Fail => {
-                                            println!("Invalid block {:?} sortition proof failed", block_hash);
-                                            continue;
-                                        }
-                                        BlockResult::Pass => {
-                                            // println!("both checks passed {:?}", block_hash);
-                                        }
+            Message::Blocks(vec_blocks) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros();
+                for block in &vec_blocks {
+                    metrics().blocks_received.inc();
+                    metrics().block_delay.observe(now.saturating_sub(block.header.timestamp) as f64 / 1e6);
+                }
+                if !self.solicited(peer, vec_blocks.iter().map(|block| block.hash())) {
+                    outgoing.push(Outgoing::Penalize(Misbehavior::UnsolicitedData));
+                }
+                let mut locked_fetcher = self.fetcher.lock().unwrap();
+                for block in &vec_blocks {
+                    locked_fetcher.received(&block.hash());
+                }
+                drop(locked_fetcher);
+                let mut locked_blockchain = self.blockchain.lock().unwrap();
+                let num_voter_chains = locked_blockchain.num_voter_chains;
+                let mut valid_block_hashes: Vec<H256> = Vec::new();
+                let mut orphans = false;
+                let mut invalid = false;
+                for block in vec_blocks {
+                    let block_hash = block.hash();
+                    if !locked_blockchain.has_block(block_hash) {
+                        emit(Event::BlockReceived { hash: block_hash, peer: peer.to_string() });
+                        // perform validation checks -- hash < difficulty, sortition id, sortition proof
+                        let result = check_pow_sortition_id(&block, num_voter_chains);
+                        match result {
+                            BlockResult::Fail => {
+                                warn!("Invalid block {:?} pow/sortition failed", block_hash);
+                                metrics().invalid_blocks.inc();
+                                invalid = true;
+                                continue;
+                            }
+                            BlockResult::Pass => {
+                                // println!("pow/sortition passed {:?}", block_hash);
+                                let result2 = check_sortition_proof(&block, num_voter_chains);
+                                match result2 {
+                                    BlockResult::Fail => {
+                                        warn!("Invalid block {:?} sortition proof failed", block_hash);
+                                        metrics().invalid_blocks.inc();
+                                        invalid = true;
+                                        continue;
+                                    }
+                                    BlockResult::Pass => {
+                                        // println!("both checks passed {:?}", block_hash);
                                     }
                                 }
                             }
-                            let result = locked_blockchain.insert(&block);
-                            if let result = InsertStatus::Valid {
+                        }
+                        let result = locked_blockchain.insert(&block);
+                        match result {
+                            InsertStatus::Valid => valid_block_hashes.push(block_hash),
+                            InsertStatus::Orphan => {
                                 valid_block_hashes.push(block_hash);
+                                orphans = true;
+                            }
+                            InsertStatus::Invalid => {
+                                warn!("Invalid block {:?} difficulty or coinbase check failed", block_hash);
+                                metrics().invalid_blocks.inc();
+                                invalid = true;
                             }
                         }
-                    } 
-                    drop(locked_blockchain);
-                    if valid_block_hashes.len() > 0 {
-                        self.server.broadcast(Message::NewBlockHashes(valid_block_hashes));
                     }
                 }
+                if invalid {
+                    outgoing.push(Outgoing::Penalize(Misbehavior::InvalidBlock));
+                }
+                // the dependencies of the orphans are asked from the peer that sent them
+                let missing = if orphans { locked_blockchain.missing_blocks() } else { vec![] };
+                drop(locked_blockchain);
+                let missing = self.fetcher.lock().unwrap().request(missing, peer, Instant::now());
+                if !missing.is_empty() {
+                    outgoing.push(self.get_blocks(peer, missing));
+                }
+                if !valid_block_hashes.is_empty() {
+                    outgoing.push(Outgoing::Broadcast(Message::NewBlockHashes(valid_block_hashes)));
+                }
+            }
 
-                Message::NewTransactionHashes(vec_tx_hashes) => {
-                    let mut req_txs: Vec<H256> = vec![];
-                    // println!("Received NewTransactionHashes");
-                    let locked_mempool = self.mempool.lock().unwrap();
-                    for tx_hash in vec_tx_hashes {
-                        if (!locked_mempool.contains(&tx_hash)) {
-                            req_txs.push(tx_hash);
-                        }
+            Message::NewTransactionHashes(vec_tx_hashes) => {
+                let mut req_txs: Vec<H256> = vec![];
+                // println!("Received NewTransactionHashes");
+                let locked_mempool = self.mempool.lock().unwrap();
+                for tx_hash in vec_tx_hashes {
+                    if !locked_mempool.contains(&tx_hash) {
+                        req_txs.push(tx_hash);
                     }
-                    drop(locked_mempool);
-                    if req_txs.len() > 0 {
-                        peer.write(Message::GetTransactions(req_txs));
+                }
+                drop(locked_mempool);
+                if !req_txs.is_empty() {
+                    outgoing.push(self.get_transactions(peer, req_txs));
+                }
+            }
+
+            Message::GetTransactions(vec_tx_hashes) => {
+                let mut newtrxs: Vec<SignedTransaction> = Vec::new();
+                let locked_mempool = self.mempool.lock().unwrap();
+                for tx_hash in vec_tx_hashes {
+                    let result = locked_mempool.get(&tx_hash);
+                    match result {
+                        Some(txstore) => newtrxs.push(txstore.signed_tx.clone()),
+                        None => debug!("mempool does not contain {}", tx_hash),
                     }
                 }
+                drop(locked_mempool);
+                if !newtrxs.is_empty() {
+                    // println!("Sending Transactions message");
+                    outgoing.push(Outgoing::Reply(Message::Transactions(newtrxs)));
+                }
+            }
 
-                Message::GetTransactions(vec_tx_hashes) => {
-                    let mut newtrxs: Vec<SignedTransaction> = Vec::new();
-                    let locked_mempool = self.mempool.lock().unwrap();
-                    for tx_hash in vec_tx_hashes {
-                        let result = locked_mempool.get(&tx_hash);
-                        match result {
-                            Some(txstore) => newtrxs.push(txstore.signed_tx.clone()),
-                            None => println!("mempool does not contain {}", tx_hash),
+            Message::Transactions(vec_txs) => {
+                metrics().txs_received.inc_by(vec_txs.len() as u64);
+                if !self.solicited(peer, vec_txs.iter().map(|tx| tx.hash())) {
+                    outgoing.push(Outgoing::Penalize(Misbehavior::UnsolicitedData));
+                }
+                // transactions spending outputs we don't know yet are priced once the ledger
+                // confirms them, any other failure is final
+                let locked_utxostate = self.utxo_state.lock().unwrap();
+                let mut accepted: Vec<(SignedTransaction, Option<u64>)> = Vec::new();
+                let mut invalid = false;
+                for tx in vec_txs {
+                    match check_tx(&locked_utxostate, &tx) {
+                        Ok(fee) => accepted.push((tx, Some(fee))),
+                        Err(TxError::MissingInput(_)) => accepted.push((tx, None)),
+                        Err(e) if e.is_malformed() => {
+                            warn!("Invalid transaction {:?} from peer {}: {}", tx.hash(), peer, e);
+                            invalid = true;
                         }
-                    }
-                    drop(locked_mempool);
-                    if newtrxs.len() > 0 {
-                        // println!("Sending Transactions message");
-                        peer.write(Message::Transactions(newtrxs));
+                        // may be valid for the peer, e.g. a timelock expiring in its ledger first
+                        Err(e) => debug!("Rejected transaction {:?} from peer {}: {}", tx.hash(), peer, e),
                     }
                 }
+                drop(locked_utxostate);
+                if invalid {
+                    outgoing.push(Outgoing::Penalize(Misbehavior::InvalidTransaction));
+                }
 
-                Message::Transactions(vec_txs) => {
-                    let mut locked_mempool = self.mempool.lock().unwrap();
-                    let mut new_tx_hashes: Vec<H256> = Vec::new();
-                    for tx in vec_txs {
-                        let tx_hash = tx.hash();
-                        if (!locked_mempool.contains(&tx_hash)) {
-                            locked_mempool.insert(tx);
-                            new_tx_hashes.push(tx_hash);
-                        }
+                let mut locked_mempool = self.mempool.lock().unwrap();
+                let mut new_tx_hashes: Vec<H256> = Vec::new();
+                for (tx, fee) in accepted {
+                    let tx_hash = tx.hash();
+                    if locked_mempool.contains(&tx_hash) {
+                        continue;
                     }
-                    drop(locked_mempool);
-                    if new_tx_hashes.len() > 0{
-                        self.server.broadcast(Message::NewTransactionHashes(new_tx_hashes));
+                    if let Some(input) = locked_mempool.conflicting_input(&tx) {
+                        debug!("Rejected transaction {:?} from peer {}: input {:?} already spent in the mempool", tx_hash, peer, input);
+                    } else {
+                        match fee {
+                            Some(fee) => locked_mempool.insert(tx, fee),
+                            None => locked_mempool.insert_unpriced(tx),
+                        }
+                        new_tx_hashes.push(tx_hash);
                     }
                 }
+                drop(locked_mempool);
+                if !new_tx_hashes.is_empty() {
+                    outgoing.push(Outgoing::Broadcast(Message::NewTransactionHashes(new_tx_hashes)));
+                }
+            }
+
+            Message::GetStatus => {
+                let status = ChainStatus::new(&self.blockchain.lock().unwrap());
+                outgoing.push(Outgoing::Reply(Message::Status(status)));
+            }
+
+            Message::Status(status) => {
+                debug!("Peer {} has proposer depth {}", peer, status.proposer_depth);
+                self.sync.lock().unwrap().peer_status(peer, status);
+                outgoing.extend(self.sync_requests(peer));
+            }
+
+            Message::GetBlockHashes { chain, start, end } => {
+                let hashes = sync::block_hashes(&self.blockchain.lock().unwrap(), chain, start, end);
+                outgoing.push(Outgoing::Reply(Message::BlockHashes { chain, start, hashes }));
+            }
+
+            Message::BlockHashes { chain, start, hashes } => {
+                if !self.sync.lock().unwrap().received(peer, chain, start) {
+                    outgoing.push(Outgoing::Penalize(Misbehavior::UnsolicitedData));
+                    return outgoing;
+                }
+                let locked_blockchain = self.blockchain.lock().unwrap();
+                let missing: Vec<H256> = hashes.into_iter().filter(|hash| !locked_blockchain.has_block(*hash)).collect();
+                drop(locked_blockchain);
+                let fetched = !missing.is_empty();
+                if fetched {
+                    outgoing.push(self.get_blocks(peer, missing));
+                }
+                let requests = self.sync_requests(peer);
+                // the peer kept mining while we synced, ask for its depths again once done. Peers
+                // already in sync get the blocks by gossip and stop here.
+                if fetched && requests.is_empty() && !self.sync.lock().unwrap().is_waiting(peer) {
+                    outgoing.push(Outgoing::Reply(Message::GetStatus));
+                }
+                outgoing.extend(requests);
+            }
 
+            Message::GetAddr => {
+                let known = self.addr_book.lock().unwrap().sample(MAX_ADDR_REPLY);
+                if !known.is_empty() {
+                    outgoing.push(Outgoing::Reply(Message::Addr(known)));
+                }
             }
+
+            Message::Addr(known) => {
+                debug!("Peer {} sent {} addresses", peer, known.len());
+                let known = &known[..std::cmp::min(known.len(), MAX_ADDR_REPLY)];
+                self.addr_book.lock().unwrap().add(known);
+            }
+
         }
+        outgoing
     }
 }
@@ -0,0 +1,459 @@
+use crate::api::submit_transaction;
+use crate::block::Header;
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::merkle::MerkleTree;
+use crate::ledger_manager::LedgerManager;
+use crate::mempool::TransactionMempool;
+use crate::miner::{assemble_contents, insert_mined_block, sortition_block, TARGET_PROPOSER_INTERVAL};
+use crate::network::addrbook::AddressBook;
+use crate::network::message::Message;
+use crate::network::worker::{Handler, Outgoing};
+use crate::transaction::UtxoOutput;
+use crate::tx_generator::MAX_TX_FEE;
+use crate::utxo::{UtxoState, ico_addresses};
+use crate::wallet::{Wallet, ICO_NODES};
+
+use log::{debug, warn};
+use rand::distributions::Exp;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+// Runs many nodes in one process on a virtual clock. Blocks, transactions and messages go
+// through the real blockchain, mempool, ledger manager and worker message handling; only
+// the network and the mining delays are simulated, from a seeded random generator, so a
+// run is reproducible. All times are in microseconds of virtual time.
+
+// Time between two passes of the ledger managers, as in the nodes
+const LEDGER_INTERVAL: u64 = 1_000_000;
+
+pub struct Config {
+    pub num_nodes: usize,
+    pub num_voter_chains: u32,
+    pub seed: u64,
+    // mean time between two superblocks of the whole network, every node has the same mining power
+    pub block_interval: u64,
+    // mean time between two transactions of each node owning ICO coins
+    pub tx_interval: u64,
+    // one way delay of every link
+    pub latency: u64,
+    // bytes per second of every link, 0 for unlimited
+    pub bandwidth: u64,
+    pub epsilon: f64,
+    pub adversary_ratio: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            num_nodes: 3,
+            num_voter_chains: 10,
+            seed: 0,
+            block_interval: TARGET_PROPOSER_INTERVAL as u64 / 11,
+            tx_interval: 1_000_000,
+            latency: 100_000,
+            bandwidth: 0,
+            epsilon: 0.001,
+            adversary_ratio: 0.1,
+        }
+    }
+}
+
+pub struct Node {
+    // address the other nodes see as the sender of its messages
+    pub addr: SocketAddr,
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub mempool: Arc<Mutex<TransactionMempool>>,
+    pub utxo_state: Arc<Mutex<UtxoState>>,
+    pub ledger: LedgerManager,
+    handler: Handler,
+    // ICO coins the node spends, the first ICO_NODES nodes have some
+    wallet: Option<Wallet>,
+}
+
+impl Node {
+    fn new(config: &Config, i: usize) -> Self {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let blockchain = Arc::new(Mutex::new(Blockchain::new(config.num_voter_chains, &mempool)));
+        let utxo_state = Arc::new(Mutex::new(UtxoState::new()));
+        let ledger = LedgerManager::new(&blockchain, &mempool, &utxo_state, config.epsilon, config.adversary_ratio);
+        Node {
+            addr: SocketAddr::from(([127, 0, 0, 1], 6000 + i as u16)),
+            handler: Handler::new(&blockchain, &mempool, &utxo_state, &Arc::new(Mutex::new(AddressBook::new()))),
+            blockchain,
+            mempool,
+            utxo_state,
+            ledger,
+            wallet: if i < ICO_NODES { Some(Wallet::ico_node(i)) } else { None },
+        }
+    }
+}
+
+enum Action {
+    Deliver { from: usize, to: usize, msg: Vec<u8> },
+    Mine(usize),
+    GenerateTx(usize),
+    UpdateLedgers,
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Stats {
+    pub messages: u64,
+    pub bytes: u64,
+    pub blocks_mined: u64,
+    pub txs_generated: u64,
+}
+
+pub struct Simulator {
+    config: Config,
+    nodes: Vec<Node>,
+    rng: StdRng,
+    now: u64,
+    // actions by (time, sequence number), the sequence number keeps ties in scheduling order
+    queue: BTreeMap<(u64, u64), Action>,
+    next_seq: u64,
+    // time each link (from, to) is done transmitting the messages queued on it
+    links: HashMap<(usize, usize), u64>,
+    // partition side of each node, messages only flow within a side
+    side: Vec<usize>,
+    // messages sent across the partition, delivered once it heals
+    held: Vec<(usize, usize, Vec<u8>)>,
+    stats: Stats,
+}
+
+impl Simulator {
+    pub fn new(config: Config) -> Self {
+        let nodes = (0..config.num_nodes).map(|i| Node::new(&config, i)).collect::<Vec<_>>();
+
+        let mut sim = Simulator {
+            rng: StdRng::seed_from_u64(config.seed),
+            side: vec![0; nodes.len()],
+            config,
+            nodes,
+            now: 0,
+            queue: BTreeMap::new(),
+            next_seq: 0,
+            links: HashMap::new(),
+            held: Vec::new(),
+            stats: Stats::default(),
+        };
+        for i in 0..sim.nodes.len() {
+            sim.schedule_mining(i);
+            if sim.nodes[i].wallet.is_some() {
+                sim.schedule_tx(i);
+            }
+        }
+        sim.schedule(LEDGER_INTERVAL, Action::UpdateLedgers);
+        sim
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    // Process every action due until `time`, then advance the clock to it
+    pub fn run_until(&mut self, time: u64) {
+        while let Some(entry) = self.queue.first_entry() {
+            if entry.key().0 > time {
+                break;
+            }
+            let ((at, _), action) = entry.remove_entry();
+            self.now = at;
+            self.process(action);
+        }
+        self.now = cmp::max(self.now, time);
+    }
+
+    pub fn run_for(&mut self, duration: u64) {
+        self.run_until(self.now + duration);
+    }
+
+    // Start a node with only the genesis blocks, connected to all others. It catches up by
+    // syncing with them. Returns the index of the node.
+    pub fn add_node(&mut self) -> usize {
+        let node = self.nodes.len();
+        self.nodes.push(Node::new(&self.config, node));
+        self.side.push(0);
+        self.schedule_mining(node);
+        if self.nodes[node].wallet.is_some() {
+            self.schedule_tx(node);
+        }
+        // what the server sends once the handshake completes
+        let msg = bincode::serialize(&Message::GetStatus).unwrap();
+        for other in 0..node {
+            self.send(node, other, msg.clone());
+            self.send(other, node, msg.clone());
+        }
+        node
+    }
+
+    // Cut the listed nodes off from the others until `heal` is called
+    pub fn partition(&mut self, nodes: &[usize]) {
+        for side in self.side.iter_mut() {
+            *side = 0;
+        }
+        for i in nodes {
+            self.side[*i] = 1;
+        }
+    }
+
+    // Reconnect all nodes, the messages held by the partition are sent now
+    pub fn heal(&mut self) {
+        for side in self.side.iter_mut() {
+            *side = 0;
+        }
+        for (from, to, msg) in std::mem::take(&mut self.held) {
+            self.send(from, to, msg);
+        }
+    }
+
+    pub fn print_summary(&self) {
+        println!("Simulated {} s, {} blocks mined, {} transactions generated, {} messages, {} bytes",
+            self.now / 1_000_000, self.stats.blocks_mined, self.stats.txs_generated, self.stats.messages, self.stats.bytes);
+        for (i, node) in self.nodes.iter().enumerate() {
+            let proposer_depth = node.blockchain.lock().unwrap().proposer_depth;
+            let confirmed_txs = node.utxo_state.lock().unwrap().confirmed_txs.len();
+            let mempool_txs = node.mempool.lock().unwrap().len();
+            println!("node {}: proposer depth {}, {} leaders confirmed, {} transactions confirmed, {} in the mempool",
+                i, proposer_depth, node.ledger.ledger_manager_state.leader_sequence.len(), confirmed_txs, mempool_txs);
+        }
+        let sequences: Vec<&Vec<H256>> = self.nodes.iter().map(|node| &node.ledger.ledger_manager_state.leader_sequence).collect();
+        let consistent = sequences.iter().all(|sequence| {
+            let common = cmp::min(sequence.len(), sequences[0].len());
+            sequence[..common] == sequences[0][..common]
+        });
+        println!("leader sequences consistent: {}", consistent);
+    }
+
+    fn schedule(&mut self, delay: u64, action: Action) {
+        self.queue.insert((self.now + delay, self.next_seq), action);
+        self.next_seq += 1;
+    }
+
+    // Exponentially distributed delay with the given mean
+    fn sample_delay(&mut self, mean: u64) -> u64 {
+        self.rng.sample(Exp::new(1.0 / cmp::max(mean, 1) as f64)) as u64
+    }
+
+    fn schedule_mining(&mut self, node: usize) {
+        let delay = self.sample_delay(self.config.block_interval * self.nodes.len() as u64);
+        self.schedule(delay, Action::Mine(node));
+    }
+
+    fn schedule_tx(&mut self, node: usize) {
+        let delay = self.sample_delay(self.config.tx_interval);
+        self.schedule(delay, Action::GenerateTx(node));
+    }
+
+    fn process(&mut self, action: Action) {
+        match action {
+            Action::Deliver { from, to, msg } => {
+                let msg: Message = bincode::deserialize(&msg).unwrap();
+                let outgoing = self.nodes[to].handler.handle(msg, self.nodes[from].addr);
+                for outgoing in outgoing {
+                    match outgoing {
+                        Outgoing::Reply(msg) => self.send(to, from, bincode::serialize(&msg).unwrap()),
+                        Outgoing::Broadcast(msg) => self.broadcast(to, &msg),
+                        // simulated nodes are honest, this is a bug of the protocol
+                        Outgoing::Penalize(misbehavior) => warn!("Node {} penalized node {}: {}", to, from, misbehavior),
+                    }
+                }
+            }
+            Action::Mine(node) => {
+                self.mine(node);
+                self.schedule_mining(node);
+            }
+            Action::GenerateTx(node) => {
+                self.generate_tx(node);
+                self.schedule_tx(node);
+            }
+            Action::UpdateLedgers => {
+                for node in self.nodes.iter_mut() {
+                    node.ledger.update_ledger();
+                }
+                self.schedule(LEDGER_INTERVAL, Action::UpdateLedgers);
+            }
+        }
+    }
+
+    // Queue a message on the link, it arrives after the messages sent before it
+    fn send(&mut self, from: usize, to: usize, msg: Vec<u8>) {
+        if self.side[from] != self.side[to] {
+            self.held.push((from, to, msg));
+            return;
+        }
+        let transmit = match self.config.bandwidth {
+            0 => 0,
+            bandwidth => msg.len() as u64 * 1_000_000 / bandwidth,
+        };
+        let link = self.links.entry((from, to)).or_insert(0);
+        let start = cmp::max(*link, self.now);
+        *link = start + transmit;
+        let delay = start + transmit + self.config.latency - self.now;
+
+        self.stats.messages += 1;
+        self.stats.bytes += msg.len() as u64;
+        self.schedule(delay, Action::Deliver { from, to, msg });
+    }
+
+    fn broadcast(&mut self, from: usize, msg: &Message) {
+        let msg = bincode::serialize(msg).unwrap();
+        for to in 0..self.nodes.len() {
+            if to != from {
+                self.send(from, to, msg.clone());
+            }
+        }
+    }
+
+    // The node found a block: assemble a superblock and search its nonce. Unlike the miner
+    // of a node, blocks without transactions are mined too so that voting never stalls.
+    fn mine(&mut self, node: usize) {
+        let mut locked_blockchain = self.nodes[node].blockchain.lock().unwrap();
+        let locked_mempool = self.nodes[node].mempool.lock().unwrap();
+        let contents = assemble_contents(&locked_blockchain, &locked_mempool, node as u64);
+        drop(locked_mempool);
+
+        let parent = locked_blockchain.get_proposer_tip();
+        let difficulty = locked_blockchain.get_difficulty(&parent).unwrap();
+        let merkle_tree = MerkleTree::new(&contents);
+        let mut header = Header {
+            parent,
+            nonce: 0,
+            difficulty,
+            timestamp: self.now as u128,
+            merkle_root: merkle_tree.root(),
+            miner_id: node as i32,
+        };
+        // the time it takes is simulated, the nonce only makes the block valid
+        while header.hash() >= difficulty {
+            header.nonce += 1;
+        }
+        let block = sortition_block(header, &contents, &merkle_tree, locked_blockchain.num_voter_chains);
+        insert_mined_block(&mut locked_blockchain, &block);
+        drop(locked_blockchain);
+
+        self.stats.blocks_mined += 1;
+        self.broadcast(node, &Message::NewBlockHashes(vec![block.hash()]));
+    }
+
+    // Spend the largest coin of the node which is not pending yet to a random ICO address
+    fn generate_tx(&mut self, node: usize) {
+        let wallet = match &self.nodes[node].wallet {
+            Some(wallet) => wallet,
+            None => return,
+        };
+        let coins = wallet.spendable_coins(&self.nodes[node].utxo_state.lock().unwrap());
+        let locked_mempool = self.nodes[node].mempool.lock().unwrap();
+        let coin = coins.into_iter().find(|(input, _)| !locked_mempool.contains_utxoinput(&input.hash()));
+        drop(locked_mempool);
+        let (input, output) = match coin {
+            Some(coin) => coin,
+            None => return,
+        };
+
+        let addresses = ico_addresses();
+        let recipient = addresses[self.rng.gen_range(0, addresses.len())];
+        // outputs must keep some value
+        let fee = self.rng.gen_range(0, cmp::min(MAX_TX_FEE, output.value.saturating_sub(1)) + 1);
+        let new_output = UtxoOutput::new(recipient, output.value - fee);
+        let tx = match wallet.create_transaction(&[(input, output)], vec![new_output], fee as u64) {
+            Ok(tx) => tx,
+            Err(e) => {
+                debug!("Node {} failed to create a transaction: {}", node, e);
+                return;
+            }
+        };
+        match submit_transaction(tx, &self.nodes[node].mempool, &self.nodes[node].utxo_state) {
+            Ok((hash, _)) => {
+                self.stats.txs_generated += 1;
+                self.broadcast(node, &Message::NewTransactionHashes(vec![hash]));
+            }
+            Err(e) => debug!("Node {} generated a rejected transaction: {}", node, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tips(sim: &Simulator) -> Vec<(H256, u32)> {
+        sim.nodes().iter().map(|node| {
+            let blockchain = node.blockchain.lock().unwrap();
+            (blockchain.get_proposer_tip(), blockchain.proposer_depth)
+        }).collect()
+    }
+
+    fn leaders(sim: &Simulator) -> Vec<Vec<H256>> {
+        sim.nodes().iter().map(|node| node.ledger.ledger_manager_state.leader_sequence.clone()).collect()
+    }
+
+    #[test]
+    fn nodes_agree_on_confirmed_leaders() {
+        let mut sim = Simulator::new(Config::default());
+        sim.run_for(120_000_000);
+        // let the last blocks propagate
+        sim.run_for(2 * LEDGER_INTERVAL);
+
+        let leaders = leaders(&sim);
+        assert!(leaders[0].len() > 1, "no leader confirmed");
+        for other in &leaders[1..] {
+            let common = cmp::min(leaders[0].len(), other.len());
+            assert_eq!(leaders[0][..common], other[..common]);
+        }
+        let confirmed = sim.nodes()[0].utxo_state.lock().unwrap().confirmed_txs.len();
+        assert!(confirmed > 0, "no transaction confirmed");
+    }
+
+    #[test]
+    fn same_seed_same_run() {
+        let config = || Config { seed: 7, ..Config::default() };
+        let mut first = Simulator::new(config());
+        let mut second = Simulator::new(config());
+        first.run_for(30_000_000);
+        second.run_for(30_000_000);
+        assert_eq!(tips(&first), tips(&second));
+        assert_eq!(first.stats().messages, second.stats().messages);
+    }
+
+    #[test]
+    fn late_joiner_catches_up() {
+        let mut sim = Simulator::new(Config { seed: 5, ..Config::default() });
+        sim.run_for(60_000_000);
+        let late = sim.add_node();
+        sim.run_for(20_000_000);
+
+        // the ancestors of the blocks gossiped after it joined only come from syncing
+        let tips = tips(&sim);
+        assert!(tips[0].1 > 2);
+        assert_eq!(tips[late], tips[0]);
+        let voter_depths = |i: usize| sim.nodes()[i].blockchain.lock().unwrap().voter_depths.clone();
+        assert_eq!(voter_depths(late), voter_depths(0));
+    }
+
+    #[test]
+    fn partition_heals() {
+        let mut sim = Simulator::new(Config { seed: 3, ..Config::default() });
+        sim.run_for(10_000_000);
+        sim.partition(&[0]);
+        sim.run_for(30_000_000);
+        sim.heal();
+        sim.run_for(30_000_000);
+
+        let tips = tips(&sim);
+        assert!(tips.iter().all(|tip| *tip == tips[0]), "tips differ: {:?}", tips);
+    }
+}
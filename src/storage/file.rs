@@ -0,0 +1,214 @@
+use super::{BlockStore, ChainSnapshot};
+use crate::block::Block;
+use crate::crypto::hash::{H256, Hashable};
+
+use log::{info, warn};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const BLOCK_LOG: &str = "blocks.log";
+const SNAPSHOT_FILE: &str = "snapshot.bin";
+const LENGTH_SIZE: usize = std::mem::size_of::<u32>();
+
+// Append-only block store kept in a directory on disk.
+//
+// `blocks.log` holds every block as a length-prefixed (u32 big endian, same framing as
+// the p2p wire format) bincode record. A partially written record at the end of the log,
+// e.g. after a crash, is dropped when the store is opened.
+// `snapshot.bin` holds the latest `ChainSnapshot` and is replaced atomically.
+// All blocks are also cached in memory to serve lookups.
+pub struct FileStore {
+    dir: PathBuf,
+    log: BufWriter<File>,
+    blocks: HashMap<H256, Block>,
+    order: Vec<H256>,
+    snapshot: Option<ChainSnapshot>,
+}
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl FileStore {
+    // Open the store in `dir`, creating it if it does not exist yet
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(BLOCK_LOG))?;
+        let mut raw = Vec::new();
+        file.read_to_end(&mut raw)?;
+
+        let mut blocks = HashMap::new();
+        let mut order = Vec::new();
+        let mut offset = 0;
+        while offset + LENGTH_SIZE <= raw.len() {
+            let length = u32::from_be_bytes(raw[offset..offset + LENGTH_SIZE].try_into().unwrap()) as usize;
+            let start = offset + LENGTH_SIZE;
+            if start + length > raw.len() {
+                break;
+            }
+            let block: Block = match bincode::deserialize(&raw[start..start + length]) {
+                Ok(block) => block,
+                Err(e) => {
+                    warn!("Corrupted record at offset {} in block log: {}", offset, e);
+                    break;
+                }
+            };
+            let hash = block.hash();
+            if !blocks.contains_key(&hash) {
+                order.push(hash);
+                blocks.insert(hash, block);
+            }
+            offset = start + length;
+        }
+        if offset < raw.len() {
+            warn!("Dropping {} trailing bytes of incomplete block log", raw.len() - offset);
+            file.set_len(offset as u64)?;
+            file.seek(SeekFrom::End(0))?;
+        }
+
+        let snapshot = match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(raw) => {
+                let snapshot: ChainSnapshot = bincode::deserialize(&raw).map_err(invalid_data)?;
+                if snapshot.num_blocks > order.len() {
+                    warn!("Snapshot covers {} blocks but only {} are stored, ignoring it", snapshot.num_blocks, order.len());
+                    None
+                } else {
+                    Some(snapshot)
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        info!("Opened block store at {:?} with {} blocks", dir, order.len());
+        Ok(FileStore {
+            dir,
+            log: BufWriter::new(file),
+            blocks,
+            order,
+            snapshot,
+        })
+    }
+}
+
+impl BlockStore for FileStore {
+    fn insert_block(&mut self, hash: H256, block: &Block) -> io::Result<()> {
+        if self.blocks.contains_key(&hash) {
+            return Ok(());
+        }
+        let encoded = bincode::serialize(block).map_err(invalid_data)?;
+        self.log.write_all(&(encoded.len() as u32).to_be_bytes())?;
+        self.log.write_all(&encoded)?;
+        self.log.flush()?;
+
+        self.blocks.insert(hash, block.clone());
+        self.order.push(hash);
+        Ok(())
+    }
+
+    fn get_block(&self, hash: &H256) -> Option<&Block> {
+        self.blocks.get(hash)
+    }
+
+    fn contains_block(&self, hash: &H256) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    fn block_hashes(&self) -> &[H256] {
+        &self.order
+    }
+
+    fn save_snapshot(&mut self, snapshot: &ChainSnapshot) -> io::Result<()> {
+        // the snapshot must never reference blocks which are not durable yet
+        self.log.get_ref().sync_data()?;
+
+        let encoded = bincode::serialize(snapshot).map_err(invalid_data)?;
+        let tmp_path = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&encoded)?;
+        tmp.sync_data()?;
+        fs::rename(&tmp_path, self.dir.join(SNAPSHOT_FILE))?;
+
+        self.snapshot = Some(snapshot.clone());
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Option<&ChainSnapshot> {
+        self.snapshot.as_ref()
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{genesis_proposer, genesis_voter};
+
+    // genesis blocks all share one header, vary the nonce to get distinct hashes
+    fn distinct_voter(nonce: u32) -> Block {
+        let mut voter = genesis_voter(1);
+        voter.header.nonce = nonce;
+        voter
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("prism-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn reopen_keeps_blocks_and_snapshot() {
+        let dir = temp_dir("reopen");
+        let proposer = genesis_proposer();
+        let voter = distinct_voter(1);
+        {
+            let mut store = FileStore::open(&dir).unwrap();
+            store.insert_block(proposer.hash(), &proposer).unwrap();
+            store.insert_block(voter.hash(), &voter).unwrap();
+            store.insert_block(voter.hash(), &voter).unwrap();
+            let snapshot = ChainSnapshot { num_blocks: 2, num_voter_chains: 1, ..Default::default() };
+            store.save_snapshot(&snapshot).unwrap();
+        }
+        let store = FileStore::open(&dir).unwrap();
+        assert_eq!(store.block_hashes(), &[proposer.hash(), voter.hash()][..]);
+        assert!(store.contains_block(&voter.hash()));
+        assert_eq!(store.snapshot().unwrap().num_blocks, 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_record_is_dropped() {
+        let dir = temp_dir("truncated");
+        let proposer = genesis_proposer();
+        {
+            let mut store = FileStore::open(&dir).unwrap();
+            store.insert_block(proposer.hash(), &proposer).unwrap();
+        }
+        // simulate a crash in the middle of appending a record
+        let mut log = OpenOptions::new().append(true).open(dir.join(BLOCK_LOG)).unwrap();
+        log.write_all(&[0, 0, 1, 0, 42]).unwrap();
+        drop(log);
+
+        let mut store = FileStore::open(&dir).unwrap();
+        assert_eq!(store.block_hashes().len(), 1);
+        let voter = distinct_voter(1);
+        store.insert_block(voter.hash(), &voter).unwrap();
+        drop(store);
+        let store = FileStore::open(&dir).unwrap();
+        assert_eq!(store.block_hashes().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
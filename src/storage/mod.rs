@@ -0,0 +1,107 @@
+pub mod file;
+
+use serde::{Serialize, Deserialize};
+use crate::block::Block;
+use crate::crypto::hash::H256;
+
+use std::collections::HashMap;
+
+pub use self::file::FileStore;
+
+// Derived indices of the Prism DAG at the time the snapshot was taken.
+// Blocks themselves are not part of the snapshot, metablocks are rebuilt from
+// the block store using the levels recorded here.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChainSnapshot {
+    // number of blocks (in insertion order) covered by this snapshot,
+    // blocks after this position have to be replayed on startup
+    pub num_blocks: usize,
+    pub num_voter_chains: u32,
+
+    pub proposer_levels: HashMap<H256, u32>,
+    pub proposer_tip: H256,
+    pub proposer_depth: u32,
+
+    pub voter_levels: Vec<HashMap<H256, u32>>,
+    pub voter_tips: Vec<H256>,
+    pub voter_depths: Vec<u32>,
+
+    pub unref_proposers: Vec<H256>,
+    pub level2proposer: HashMap<u32, H256>,
+    pub level2allproposers: HashMap<u32, Vec<H256>>,
+    pub proposer2votecount: HashMap<H256, u32>,
+    pub proposer2voterinfo: HashMap<H256, Vec<(u32, H256)>>,
+    pub chain2level: HashMap<u32, u32>,
+}
+
+// Storage backend for blocks and the derived blockchain indices.
+// Blocks are kept in insertion order so that blocks received after the
+// latest snapshot can be replayed through `Blockchain::insert`.
+pub trait BlockStore: Send {
+    // Store a block, inserting a block that is already present is a no-op
+    fn insert_block(&mut self, hash: H256, block: &Block) -> std::io::Result<()>;
+
+    fn get_block(&self, hash: &H256) -> Option<&Block>;
+
+    fn contains_block(&self, hash: &H256) -> bool;
+
+    // Hashes of all stored blocks in the order they were inserted
+    fn block_hashes(&self) -> &[H256];
+
+    fn save_snapshot(&mut self, snapshot: &ChainSnapshot) -> std::io::Result<()>;
+
+    // Latest snapshot saved in this store, if any
+    fn snapshot(&self) -> Option<&ChainSnapshot>;
+
+    // Whether the blocks survive a restart, snapshots of a volatile store are never saved
+    fn is_persistent(&self) -> bool;
+}
+
+// Volatile store, everything is lost when the node exits
+#[derive(Default)]
+pub struct MemoryStore {
+    blocks: HashMap<H256, Block>,
+    order: Vec<H256>,
+    snapshot: Option<ChainSnapshot>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl BlockStore for MemoryStore {
+    fn insert_block(&mut self, hash: H256, block: &Block) -> std::io::Result<()> {
+        if !self.blocks.contains_key(&hash) {
+            self.blocks.insert(hash, block.clone());
+            self.order.push(hash);
+        }
+        Ok(())
+    }
+
+    fn get_block(&self, hash: &H256) -> Option<&Block> {
+        self.blocks.get(hash)
+    }
+
+    fn contains_block(&self, hash: &H256) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    fn block_hashes(&self) -> &[H256] {
+        &self.order
+    }
+
+    fn save_snapshot(&mut self, snapshot: &ChainSnapshot) -> std::io::Result<()> {
+        self.snapshot = Some(snapshot.clone());
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Option<&ChainSnapshot> {
+        self.snapshot.as_ref()
+    }
+
+    fn is_persistent(&self) -> bool {
+        false
+    }
+}
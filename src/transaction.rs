@@ -19,6 +19,26 @@ pub struct UtxoInput{
 pub struct UtxoOutput{
   pub receipient_addr: H160,
   pub value: u32,
+  // spending condition replacing the signature of `receipient_addr`,
+  // the address is then only used to attribute the coin
+  pub condition: Option<Condition>,
+}
+
+// Condition the witness of an input has to satisfy to spend an output
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+  // signed by the key of the address
+  Address(H160),
+  // signed by at least `threshold` of the public keys
+  Multisig { threshold: u8, public_keys: Vec<Vec<u8>> },
+  // the ledger confirmed a leader at this level
+  AfterLevel(u32),
+  // the ledger confirmed a leader mined at or after this timestamp
+  AfterTime(u128),
+  // the witness reveals a preimage of this SHA256 hash
+  HashLock(H256),
+  All(Vec<Condition>),
+  Any(Vec<Condition>),
 }
 
 #[derive(Serialize, Deserialize, Debug, Default,Clone)]
@@ -27,11 +47,34 @@ pub struct Transaction {
   pub tx_output: Vec<UtxoOutput>,
 }
 
+// Transaction minting the reward of a proposer block, it has no inputs and no signature
+#[derive(Serialize, Deserialize, Debug, Default,Clone)]
+pub struct CoinbaseTransaction {
+  // level and proposer parent of the block, they make the coinbase hash unique
+  pub level: u32,
+  pub parent: H256,
+  pub tx_output: Vec<UtxoOutput>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default,Clone)]
+pub struct KeySignature {
+  pub public_key: Vec<u8>,
+  pub signature: Vec<u8>,
+}
+
+// Proof that one input may be spent: signatures over the whole transaction and,
+// for hash locked outputs, the revealed preimage
+#[derive(Serialize, Deserialize, Debug, Default,Clone)]
+pub struct Witness {
+  pub signatures: Vec<KeySignature>,
+  pub preimage: Option<Vec<u8>>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Default,Clone)]
 pub struct SignedTransaction {
   pub tx: Transaction,
-  pub signature: Vec<u8>, 
-  pub public_key: Vec<u8>,
+  // one witness per input, in the order of `tx.tx_input`
+  pub witnesses: Vec<Witness>,
 }
 
 impl Hashable for Transaction {
@@ -49,6 +92,27 @@ impl Hashable for SignedTransaction {
     }
 }
 
+impl Hashable for CoinbaseTransaction {
+    fn hash(&self) -> H256 {
+        let encodedtrans: Vec<u8> = bincode::serialize(&self).unwrap();
+        ring::digest::digest(&ring::digest::SHA256, &encodedtrans[..]).into()
+    }
+}
+
+impl CoinbaseTransaction {
+    pub fn new(level: u32, parent: H256, receipient_addr: H160, value: u32) -> Self {
+        CoinbaseTransaction {
+            level,
+            parent,
+            tx_output: vec![UtxoOutput::new(receipient_addr, value)],
+        }
+    }
+
+    pub fn value(&self) -> u64 {
+        self.tx_output.iter().map(|output| output.value as u64).sum()
+    }
+}
+
 impl Hashable for UtxoInput {
     fn hash(&self) -> H256 {
         let encodedtrans: Vec<u8> = bincode::serialize(&self).unwrap();
@@ -57,6 +121,66 @@ impl Hashable for UtxoInput {
 }
 
 
+impl UtxoOutput {
+    pub fn new(receipient_addr: H160, value: u32) -> Self {
+        UtxoOutput { receipient_addr, value, condition: None }
+    }
+
+    pub fn with_condition(receipient_addr: H160, value: u32, condition: Condition) -> Self {
+        UtxoOutput { receipient_addr, value, condition: Some(condition) }
+    }
+
+    // Condition to spend this output, plain outputs need the signature of the recipient
+    pub fn spending_condition(&self) -> Condition {
+        match &self.condition {
+            Some(condition) => condition.clone(),
+            None => Condition::Address(self.receipient_addr),
+        }
+    }
+}
+
+impl Condition {
+    // Nesting depth, a plain condition has depth 1
+    pub fn depth(&self) -> usize {
+        match self {
+            Condition::All(conditions) | Condition::Any(conditions) => {
+                1 + conditions.iter().map(|c| c.depth()).max().unwrap_or(0)
+            }
+            _ => 1,
+        }
+    }
+}
+
+impl Witness {
+    // Witness of a single key, enough to spend plain outputs
+    pub fn new(t: &Transaction, key: &Ed25519KeyPair) -> Self {
+        Self::multisig(t, &[key])
+    }
+
+    pub fn multisig(t: &Transaction, keys: &[&Ed25519KeyPair]) -> Self {
+        let signatures = keys.iter()
+            .map(|key| KeySignature {
+                public_key: key.public_key().as_ref().to_vec(),
+                signature: sign(t, key).as_ref().to_vec(),
+            })
+            .collect();
+        Witness { signatures, preimage: None }
+    }
+
+    pub fn with_preimage(mut self, preimage: Vec<u8>) -> Self {
+        self.preimage = Some(preimage);
+        self
+    }
+}
+
+impl SignedTransaction {
+    // Sign a transaction with the key owning each of its inputs, `keys` is parallel to `tx_input`
+    pub fn new(t: Transaction, keys: &[&Ed25519KeyPair]) -> Self {
+        let witnesses = keys.iter().map(|key| Witness::new(&t, key)).collect();
+        SignedTransaction { tx: t, witnesses }
+    }
+}
+
 /// Create digital signature of a transaction
 pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
     //let mut mess = [&t.input[..],&t.output[..]].concat();
@@ -85,14 +209,14 @@ pub fn verify(t: &Transaction, signature_bytes: &Vec<u8>, public_key_bytes: &Vec
 
 pub fn generate_random_transaction() -> Transaction {
     let input = vec![UtxoInput{tx_hash: hash::generate_random_hash(), idx: 0}];
-    let output = vec![UtxoOutput{receipient_addr: address::generate_random_address(), value: 0}];
+    let output = vec![UtxoOutput::new(address::generate_random_address(), 0)];
     
     Transaction{tx_input: input, tx_output: output}
 }
 
 pub fn generate_genesis_transaction() -> Transaction {
     let input = vec![UtxoInput{tx_hash: H256::from([0;32]), idx: 0}];
-    let output = vec![UtxoOutput{receipient_addr: H160::from([0;20]), value: 0}];
+    let output = vec![UtxoOutput::new(H160::from([0;20]), 0)];
     
     Transaction{tx_input: input, tx_output: output}
 }
@@ -100,9 +224,7 @@ pub fn generate_genesis_transaction() -> Transaction {
 pub fn generate_random_signed_transaction() -> SignedTransaction {
     let t = generate_random_transaction();
     let key = key_pair::random();
-    let sig = sign(&t, &key);
-    let signed_tx = SignedTransaction{tx:t,signature:sig.as_ref().to_vec(),public_key:key.public_key().as_ref().to_vec()};
-    signed_tx
+    SignedTransaction::new(t, &[&key])
 }
 
 
@@ -111,9 +233,7 @@ pub fn generate_genesis_signed_transaction() -> SignedTransaction {
 
     let t = generate_genesis_transaction();
     let key = Ed25519KeyPair::from_pkcs8([48, 83, 2, 1, 1, 48, 5, 6, 3, 43, 101, 112, 4, 34, 4, 32, 187, 131, 74, 161, 134, 11, 240, 6, 188, 109, 18, 108, 124, 219, 167, 164, 215, 125, 168, 79, 204, 194, 232, 91, 58, 186, 181, 230, 212, 78, 163, 28, 161, 35, 3, 33, 0, 233, 72, 146, 218, 220, 235, 17, 123, 202, 112, 119, 63, 134, 105, 134, 71, 34, 185, 71, 193, 59, 66, 43, 137, 50, 194, 120, 234, 97, 132, 235, 159].as_ref().into()).unwrap();
-    let sig = sign(&t, &key);
-    let signed_tx = SignedTransaction{tx:t,signature:sig.as_ref().to_vec(),public_key:key.public_key().as_ref().to_vec()};
-    signed_tx
+    SignedTransaction::new(t, &[&key])
 }
 
 #[cfg(any(test, test_utilities))]
@@ -1,46 +1,59 @@
-use crate::transaction::{self, UtxoInput, UtxoOutput, SignedTransaction};
+use crate::transaction::{self, UtxoInput, UtxoOutput, SignedTransaction, CoinbaseTransaction};
 use crate::crypto::hash::{H256, Hashable};
 use crate::crypto::address::{self, H160};
+use crate::validation::transaction::check_tx;
+use crate::wallet::{Wallet, ICO_KEYS_PER_NODE};
 use ring::signature::{self,Ed25519KeyPair, Signature, KeyPair};
 
 use std::collections::HashMap;
+use std::sync::OnceLock;
 
-use log::debug;
+use log::{debug, warn};
+
+// Changes made to the state by one transaction, enough to revert it
+#[derive(Debug, Clone)]
+pub struct TxUndo {
+    pub tx_hash: H256,
+    // outputs consumed by the transaction
+    pub spent: Vec<(UtxoInput, UtxoOutput)>,
+    // outputs created by the transaction
+    pub created: Vec<UtxoInput>,
+}
+
+// Value minted by the coinbase of a proposer block
+pub const BLOCK_REWARD: u32 = 50;
+// Number of levels confirmed after the one of a coinbase before its outputs can be spent
+pub const COINBASE_MATURITY: u32 = 10;
 
 #[derive(Debug, Default, Clone)]
 pub struct UtxoState{
     pub state_map: HashMap<UtxoInput, UtxoOutput>,  
+    // coinbase tx hash -> level of the proposer block which minted it
+    pub coinbase_levels: HashMap<H256, u32>,
+    // level of the last confirmed leader
+    pub confirmed_level: u32,
+    // timestamp of the last confirmed leader
+    pub confirmed_timestamp: u128,
+    // confirmed tx hash -> level of the leader which confirmed it
+    pub confirmed_txs: HashMap<H256, u32>,
+}
+
+static ICO_ADDRESSES: OnceLock<Vec<H160>> = OnceLock::new();
+
+// Addresses of the ICO keys, derived once since every coinbase check needs them
+pub fn ico_addresses() -> &'static [H160] {
+    ICO_ADDRESSES.get_or_init(|| Wallet::ico().addresses().to_vec())
+}
+
+// Address the coinbase of a block mined by `miner_id` pays to,
+// the first ICO address owned by that node
+pub fn miner_address(miner_id: i32) -> H160 {
+    let addresses = ico_addresses();
+    addresses[(ICO_KEYS_PER_NODE * miner_id.unsigned_abs() as usize) % addresses.len()]
 }
 
 pub fn perform_ico() -> HashMap<UtxoInput, UtxoOutput> {
-    let vector1 = [48, 83, 2, 1, 1, 48, 5, 6, 3, 43, 101, 112, 4, 34, 4, 32, 187, 131, 74, 161, 134, 11, 240, 6, 188, 109, 18, 108, 124, 219, 167, 164, 215, 125, 168, 79, 204, 194, 232, 91, 58, 186, 181, 230, 212, 78, 163, 28, 161, 35, 3, 33, 0, 233, 72, 146, 218, 220, 235, 17, 123, 202, 112, 119, 63, 134, 105, 134, 71, 34, 185, 71, 193, 59, 66, 43, 137, 50, 194, 120, 234, 97, 132, 235, 159];
-    let vector2 = [48, 83, 2, 1, 1, 48, 5, 6, 3, 43, 101, 112, 4, 34, 4, 32, 154, 186, 73, 239, 105, 129, 142, 211, 156, 79, 213, 209, 229, 87, 22, 92, 113, 203, 244, 222, 244, 33, 199, 254, 130, 102, 178, 65, 198, 67, 20, 132, 161, 35, 3, 33, 0, 161, 153, 171, 27, 96, 146, 25, 237, 5, 189, 186, 116, 0, 24, 2, 8, 28, 143, 5, 119, 20, 47, 142, 186, 55, 234, 189, 167, 154, 15, 210, 97];
-    let vector3 = [48, 83, 2, 1, 1, 48, 5, 6, 3, 43, 101, 112, 4, 34, 4, 32, 147, 195, 231, 118, 135, 29, 32, 40, 23, 117, 107, 218, 6, 220, 198, 50, 81, 113, 167, 122, 175, 161, 118, 93, 191, 137, 50, 125, 203, 69, 70, 42, 161, 35, 3, 33, 0, 125, 80, 160, 138, 247, 46, 227, 162, 118, 51, 64, 42, 174, 60, 87, 134, 77, 60, 225, 11, 189, 222, 22, 185, 65, 10, 67, 78, 250, 41, 188, 60];
-    let vector4 = [48, 83, 2, 1, 1, 48, 5, 6, 3, 43, 101, 112, 4, 34, 4, 32, 11, 212, 170, 1, 126, 8, 32, 58, 40, 116, 165, 98, 48, 127, 67, 109, 86, 251, 249, 203, 244, 203, 1, 223, 248, 164, 176, 195, 23, 17, 146, 8, 161, 35, 3, 33, 0, 206, 15, 234, 106, 58, 45, 177, 81, 0, 193, 13, 113, 249, 55, 152, 151, 227, 224, 35, 185, 148, 49, 186, 234, 17, 106, 132, 216, 83, 196, 127, 99];
-    let vector5 = [48, 83, 2, 1, 1, 48, 5, 6, 3, 43, 101, 112, 4, 34, 4, 32, 40, 29, 27, 179, 25, 183, 68, 113, 252, 19, 20, 114, 160, 221, 228, 195, 253, 87, 245, 176, 226, 99, 249, 28, 87, 61, 101, 129, 207, 87, 90, 195, 161, 35, 3, 33, 0, 254, 57, 159, 24, 159, 141, 184, 159, 58, 86, 112, 217, 153, 215, 65, 7, 88, 14, 57, 80, 42, 33, 151, 211, 208, 52, 42, 208, 111, 174, 223, 27];
-    let vector6 = [48, 83, 2, 1, 1, 48, 5, 6, 3, 43, 101, 112, 4, 34, 4, 32, 224, 231, 169, 219, 160, 221, 218, 51, 189, 197, 202, 218, 24, 20, 166, 105, 31, 55, 241, 231, 5, 165, 51, 106, 174, 11, 110, 84, 17, 115, 230, 56, 161, 35, 3, 33, 0, 127, 130, 60, 237, 224, 179, 64, 241, 25, 174, 45, 64, 52, 179, 70, 249, 26, 49, 128, 103, 188, 201, 48, 55, 221, 154, 12, 83, 40, 123, 3, 157];
-
-    let key_pair1 = signature::Ed25519KeyPair::from_pkcs8(vector1.as_ref().into()).unwrap();
-    let key_pair2 = signature::Ed25519KeyPair::from_pkcs8(vector2.as_ref().into()).unwrap();
-    let key_pair3 = signature::Ed25519KeyPair::from_pkcs8(vector3.as_ref().into()).unwrap();
-    let key_pair4 = signature::Ed25519KeyPair::from_pkcs8(vector4.as_ref().into()).unwrap();
-    let key_pair5 = signature::Ed25519KeyPair::from_pkcs8(vector5.as_ref().into()).unwrap();
-    let key_pair6 = signature::Ed25519KeyPair::from_pkcs8(vector6.as_ref().into()).unwrap();
-
-    let address1 = address::address_from_public_key_vec_ref(&key_pair1.public_key().as_ref().to_vec());
-    let address2 = address::address_from_public_key_vec_ref(&key_pair2.public_key().as_ref().to_vec());
-    let address3 = address::address_from_public_key_vec_ref(&key_pair3.public_key().as_ref().to_vec());
-    let address4 = address::address_from_public_key_vec_ref(&key_pair4.public_key().as_ref().to_vec());
-    let address5 = address::address_from_public_key_vec_ref(&key_pair5.public_key().as_ref().to_vec());
-    let address6 = address::address_from_public_key_vec_ref(&key_pair6.public_key().as_ref().to_vec());
-
-    let mut address_vec: Vec<H160> = Vec::new();
-    address_vec.push(address1);
-    address_vec.push(address2);
-    address_vec.push(address3);
-    address_vec.push(address4);
-    address_vec.push(address5);
-    address_vec.push(address6);
+    let address_vec = ico_addresses();
 
     let mut state_map: HashMap<UtxoInput, UtxoOutput> = HashMap::new();
 
@@ -52,7 +65,7 @@ pub fn perform_ico() -> HashMap<UtxoInput, UtxoOutput> {
             sam[1] = j as u8;
             let mut initial_tx_hash: H256 = sam.into() ;
             let input = UtxoInput{tx_hash: initial_tx_hash, idx: 0};
-            let output = UtxoOutput{receipient_addr: *address, value: val};
+            let output = UtxoOutput::new(*address, val);
             state_map.insert(input, output);
         } 
     }
@@ -64,6 +77,10 @@ impl  UtxoState {
         UtxoState{
             // perform ICO 
             state_map: perform_ico(),
+            coinbase_levels: HashMap::new(),
+            confirmed_level: 0,
+            confirmed_timestamp: 0,
+            confirmed_txs: HashMap::new(),
         }
     }
 
@@ -82,59 +99,194 @@ impl  UtxoState {
     
     //TODO: Should take Vec<SignedTransaction> for more general purpose
     //As we will be giving only one tx at a time, for now it is fine
-    pub fn update_state(&mut self, signed_tx: &SignedTransaction) {
+    pub fn update_state(&mut self, signed_tx: &SignedTransaction) -> TxUndo {
+        let tx_hash = signed_tx.hash();
+        let mut undo = TxUndo { tx_hash, spent: Vec::new(), created: Vec::new() };
         for tx_input in &signed_tx.tx.tx_input {
-            self.state_map.remove(tx_input);
+            if let Some(output) = self.state_map.remove(tx_input) {
+                undo.spent.push((tx_input.clone(), output));
+            }
         }
         
         for (i, tx_output) in (&signed_tx.tx.tx_output).iter().enumerate() {
-            let tx_input = UtxoInput{tx_hash: signed_tx.hash(), idx: i as u8};
-            self.state_map.insert(tx_input, tx_output.clone());
+            let tx_input = UtxoInput{tx_hash, idx: i as u8};
+            self.state_map.insert(tx_input.clone(), tx_output.clone());
+            undo.created.push(tx_input);
         }
+        self.confirmed_txs.insert(tx_hash, self.confirmed_level);
+        undo
     }
 
-    //Should it be a "function" rather than "method" of UtxoState??
-    //1. Signature check
-    //2. Owner match
-    //3. Double Spend
-    //4. Input/Output total match
-    pub fn is_tx_valid(&self, signed_tx: &SignedTransaction) -> bool {
-        // println!("current signed_tx {:?}", signed_tx);
+    // Add the outputs minted by the coinbase of a proposer block. At most `max_value` is
+    // minted: outputs claiming more are cut down, the ones past the cap are not created.
+    pub fn apply_coinbase(&mut self, coinbase: &CoinbaseTransaction, max_value: u64) -> TxUndo {
+        let tx_hash = coinbase.hash();
+        let mut undo = TxUndo { tx_hash, spent: Vec::new(), created: Vec::new() };
+        let mut remaining = max_value;
+        for (i, tx_output) in coinbase.tx_output.iter().enumerate() {
+            if remaining == 0 {
+                break;
+            }
+            let mut tx_output = tx_output.clone();
+            if tx_output.value as u64 > remaining {
+                tx_output.value = remaining as u32;
+            }
+            remaining -= tx_output.value as u64;
+            let tx_input = UtxoInput{tx_hash, idx: i as u8};
+            self.state_map.insert(tx_input.clone(), tx_output);
+            undo.created.push(tx_input);
+        }
+        self.coinbase_levels.insert(tx_hash, coinbase.level);
+        undo
+    }
 
-        if !transaction::verify(&signed_tx.tx, &signed_tx.signature, &signed_tx.public_key){
-            println!("tx didn't pass signature check!");
+    // The coinbase of the proposer block at `level` can be applied once. The amount it
+    // mints is capped by `apply_coinbase`.
+    pub fn is_coinbase_valid(&self, coinbase: &CoinbaseTransaction, level: u32) -> bool {
+        if coinbase.level != level {
+            warn!("coinbase level {} doesn't match block level {}", coinbase.level, level);
             return false;
         }
-        
-        let owner_address = address::address_from_public_key_vec_ref(&signed_tx.public_key);
-        let mut total_input_value = 0;
+        if self.coinbase_levels.contains_key(&coinbase.hash()) {
+            warn!("coinbase already applied");
+            return false;
+        }
+        true
+    }
+
+    // Fee paid by a transaction, i.e. the value of its inputs not claimed by its outputs.
+    // None if an input is not in the state or the outputs are worth more than the inputs.
+    pub fn tx_fee(&self, signed_tx: &SignedTransaction) -> Option<u64> {
+        let mut total_input_value: u64 = 0;
         for input in &signed_tx.tx.tx_input {
-            if !self.state_map.contains_key(&input) {
-               println!("Input is {:?}",input);
-               println!("tx is double spend as input is not there in State!");
-               return false;  
-            }
+            total_input_value += self.state_map.get(input)?.value as u64;
+        }
+        let total_output_value: u64 = signed_tx.tx.tx_output.iter().map(|output| output.value as u64).sum();
+        total_input_value.checked_sub(total_output_value)
+    }
 
-            let output = &self.state_map[&input];
-            if output.receipient_addr != owner_address {
-               println!("owner of tx input doesn't match to previous tx output");
-               println!("input addreess {:?}", owner_address);
-               println!("output address {:?}", output.receipient_addr);
-               return false;
-            }
-            total_input_value = output.value;
+    // Outputs of a coinbase can only be spent COINBASE_MATURITY levels after it got confirmed
+    pub fn is_mature(&self, input: &UtxoInput) -> bool {
+        match self.coinbase_levels.get(&input.tx_hash) {
+            Some(level) => self.confirmed_level >= level + COINBASE_MATURITY,
+            None => true,
         }
-        
-        let mut total_output_value = 0;
-        for output in &signed_tx.tx.tx_output {
-             total_output_value += output.value;
+    }
+
+    // Revert a transaction applied by `update_state`, transactions have to be
+    // reverted in the reverse order in which they were applied
+    pub fn revert_state(&mut self, undo: &TxUndo) {
+        self.coinbase_levels.remove(&undo.tx_hash);
+        self.confirmed_txs.remove(&undo.tx_hash);
+        for tx_input in &undo.created {
+            self.state_map.remove(tx_input);
         }
-  
-        if total_input_value != total_output_value {
-           println!("Input sum didn't match to output sum for tx");
-           return false;
+        for (tx_input, output) in &undo.spent {
+            self.state_map.insert(tx_input.clone(), output.clone());
         }
+    }
 
-        true
+    // Full validity rules are in validation::transaction::check_tx
+    pub fn is_tx_valid(&self, signed_tx: &SignedTransaction) -> bool {
+        match check_tx(self, signed_tx) {
+            Ok(_) => true,
+            Err(e) => {
+                debug!("tx {:?} is invalid: {}", signed_tx.hash(), e);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{Transaction, sign};
+
+    #[test]
+    fn update_and_revert() {
+        let key = crate::crypto::key_pair::random();
+        let owner = address::address_from_public_key_vec_ref(&key.public_key().as_ref().to_vec());
+        let input = UtxoInput{tx_hash: hash_of(1), idx: 0};
+        let mut state = UtxoState::default();
+        state.state_map.insert(input.clone(), UtxoOutput::new(owner, 10));
+        let before = state.state_map.clone();
+
+        let tx = Transaction {
+            tx_input: vec![input.clone()],
+            tx_output: vec![UtxoOutput::new(owner, 4), UtxoOutput::new(owner, 6)],
+        };
+        let signed_tx = SignedTransaction::new(tx, &[&key]);
+        assert!(state.is_tx_valid(&signed_tx));
+        assert_eq!(state.tx_fee(&signed_tx), Some(0));
+        let undo = state.update_state(&signed_tx);
+        assert!(!state.state_map.contains_key(&input));
+        assert_eq!(state.state_map.len(), 2);
+
+        state.revert_state(&undo);
+        assert_eq!(state.state_map.len(), before.len());
+        assert_eq!(state.state_map[&input].value, 10);
+    }
+
+    #[test]
+    fn coinbase_reward_and_maturity() {
+        let key = crate::crypto::key_pair::random();
+        let owner = address::address_from_public_key_vec_ref(&key.public_key().as_ref().to_vec());
+        let mut state = UtxoState::default();
+        assert!(!state.is_coinbase_valid(&CoinbaseTransaction::new(2, hash_of(2), owner, BLOCK_REWARD), 3));
+
+        // a coinbase claiming more than it may mint is cut down to the cap
+        let greedy = CoinbaseTransaction::new(4, hash_of(4), owner, BLOCK_REWARD + 5);
+        let undo = state.apply_coinbase(&greedy, BLOCK_REWARD as u64 + 2);
+        assert_eq!(state.state_map[&undo.created[0]].value, BLOCK_REWARD + 2);
+        state.revert_state(&undo);
+
+        let coinbase = CoinbaseTransaction::new(3, hash_of(3), owner, BLOCK_REWARD);
+        assert!(state.is_coinbase_valid(&coinbase, 3));
+        state.confirmed_level = 3;
+        let undo = state.apply_coinbase(&coinbase, BLOCK_REWARD as u64);
+        assert!(!state.is_coinbase_valid(&coinbase, 3));
+        // the same reward mined on another parent at the same level
+        assert!(state.is_coinbase_valid(&CoinbaseTransaction::new(3, hash_of(4), owner, BLOCK_REWARD), 3));
+
+        let tx = Transaction {
+            tx_input: vec![UtxoInput{tx_hash: coinbase.hash(), idx: 0}],
+            tx_output: vec![UtxoOutput::new(owner, BLOCK_REWARD)],
+        };
+        let signed_tx = SignedTransaction::new(tx, &[&key]);
+        state.confirmed_level = 3 + COINBASE_MATURITY - 1;
+        assert!(!state.is_tx_valid(&signed_tx));
+        state.confirmed_level = 3 + COINBASE_MATURITY;
+        assert!(state.is_tx_valid(&signed_tx));
+
+        state.revert_state(&undo);
+        assert!(state.state_map.is_empty());
+        assert!(state.coinbase_levels.is_empty());
+    }
+
+    #[test]
+    fn fee_is_unclaimed_input_value() {
+        let key = crate::crypto::key_pair::random();
+        let owner = address::address_from_public_key_vec_ref(&key.public_key().as_ref().to_vec());
+        let mut state = UtxoState::default();
+        let inputs = vec![UtxoInput{tx_hash: hash_of(1), idx: 0}, UtxoInput{tx_hash: hash_of(2), idx: 0}];
+        for input in &inputs {
+            state.state_map.insert(input.clone(), UtxoOutput::new(owner, 10));
+        }
+        let signed = |value: u32| {
+            let tx = Transaction {
+                tx_input: inputs.clone(),
+                tx_output: vec![UtxoOutput::new(owner, value)],
+            };
+            SignedTransaction::new(tx, &[&key, &key])
+        };
+        assert!(state.is_tx_valid(&signed(17)));
+        assert_eq!(state.tx_fee(&signed(17)), Some(3));
+        assert!(!state.is_tx_valid(&signed(21)));
+        assert_eq!(state.tx_fee(&signed(21)), None);
+    }
+
+    fn hash_of(byte: u8) -> H256 {
+        [byte; 32].into()
     }
 }
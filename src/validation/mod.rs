@@ -1,3 +1,5 @@
+pub mod transaction;
+
 
 use crate::network::server::Handle as ServerHandle;
 use crate::block::{self, *};
@@ -5,8 +7,9 @@ use crate::crypto::hash::{H256, Hashable};
 use crate::crypto::merkle::{MerkleTree, verify};
 use crate::blockchain::{Blockchain, InsertStatus};
 use crate::miner::{sortition_hash, PROPOSER_INDEX, FIRST_VOTER_IDX};
+use crate::utxo::miner_address;
 
-use log::info;
+use log::warn;
 use bigint::uint::U256;
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
@@ -19,11 +22,14 @@ pub enum BlockResult {
     Fail,
 }
 
+// How far ahead of the local clock a proposer block may be timestamped, in microseconds
+pub const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 3600 * 1_000_000;
+
 //PoW and sortition id
 pub fn check_pow_sortition_id(block: &Block, num_voter_chains: u32) -> BlockResult {
     let sortition_id = sortition_hash(block.hash(), block.header.difficulty, num_voter_chains);
     if sortition_id.is_none() {
-        println!("New block does not satisy proof-of-work");
+        warn!("New block does not satisy proof-of-work");
         return BlockResult::Fail;
     }
 
@@ -32,7 +38,7 @@ pub fn check_pow_sortition_id(block: &Block, num_voter_chains: u32) -> BlockResu
         Content::Voter(content) => content.chain_num,
     };
     if sortition_id.unwrap() != correct_sortition_id {
-        println!("Sortition check failed: sortition hash {} content mapping {}", sortition_id.unwrap(), correct_sortition_id);
+        warn!("Sortition check failed: sortition hash {} content mapping {}", sortition_id.unwrap(), correct_sortition_id);
         return BlockResult::Fail;
     }
     return BlockResult::Pass;
@@ -55,3 +61,75 @@ pub fn check_sortition_proof(block: &Block, num_voter_chains: u32) -> BlockResul
     }
     return BlockResult::Pass;
 }
+
+// The difficulty must be the one required by the proposer chain on top of the
+// proposer block the block was mined on
+pub fn check_difficulty(block: &Block, blockchain: &Blockchain) -> BlockResult {
+    if let Content::Proposer(content) = &block.content {
+        if content.parent_hash != block.header.parent {
+            warn!("Proposer parent {:?} differs from header parent {:?}", content.parent_hash, block.header.parent);
+            return BlockResult::Fail;
+        }
+    }
+    match blockchain.get_difficulty(&block.header.parent) {
+        Some(difficulty) if difficulty == block.header.difficulty => BlockResult::Pass,
+        Some(_) => {
+            warn!("Difficulty check failed for block {:?}", block.hash());
+            BlockResult::Fail
+        }
+        None => {
+            warn!("Difficulty check failed: unknown proposer parent {:?}", block.header.parent);
+            BlockResult::Fail
+        }
+    }
+}
+
+// Proposer timestamps drive the difficulty retarget: a proposer block can't be older than
+// its parent nor more than MAX_FUTURE_BLOCK_TIME ahead of the local clock
+pub fn check_timestamp(block: &Block, blockchain: &Blockchain) -> BlockResult {
+    let content = match &block.content {
+        Content::Proposer(content) => content,
+        Content::Voter(_) => return BlockResult::Pass,
+    };
+    let parent_timestamp = match blockchain.proposer_chain.get(&content.parent_hash) {
+        Some(parent) => parent.block.header.timestamp,
+        None => return BlockResult::Fail,
+    };
+    if block.header.timestamp < parent_timestamp {
+        warn!("Block {:?} is older than its parent {:?}", block.hash(), content.parent_hash);
+        return BlockResult::Fail;
+    }
+    let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_micros();
+    if block.header.timestamp > now + MAX_FUTURE_BLOCK_TIME {
+        warn!("Block {:?} is timestamped {} ahead of the local clock", block.hash(), block.header.timestamp - now);
+        return BlockResult::Fail;
+    }
+    BlockResult::Pass
+}
+
+// The coinbase of a proposer block must be minted at the level and on the parent of the
+// block and pay the address of its miner. The amount it mints is capped when the block gets confirmed.
+pub fn check_coinbase(block: &Block, blockchain: &Blockchain) -> BlockResult {
+    let content = match &block.content {
+        Content::Proposer(content) => content,
+        Content::Voter(_) => return BlockResult::Pass,
+    };
+    let level = match blockchain.proposer_chain.get(&content.parent_hash) {
+        Some(parent) => parent.level + 1,
+        None => return BlockResult::Fail,
+    };
+    if content.coinbase.level != level {
+        warn!("Coinbase of block {:?} has level {} instead of {}", block.hash(), content.coinbase.level, level);
+        return BlockResult::Fail;
+    }
+    if content.coinbase.parent != content.parent_hash {
+        warn!("Coinbase of block {:?} has parent {:?} instead of {:?}", block.hash(), content.coinbase.parent, content.parent_hash);
+        return BlockResult::Fail;
+    }
+    let reward_address = miner_address(block.header.miner_id);
+    if content.coinbase.tx_output.iter().any(|output| output.receipient_addr != reward_address) {
+        warn!("Coinbase of block {:?} does not pay miner {}", block.hash(), block.header.miner_id);
+        return BlockResult::Fail;
+    }
+    BlockResult::Pass
+}
@@ -0,0 +1,379 @@
+use crate::transaction::{self, SignedTransaction, UtxoInput, Condition, Witness};
+use crate::crypto::address;
+use crate::crypto::hash::H256;
+use crate::utxo::UtxoState;
+
+use std::collections::HashSet;
+use std::fmt;
+
+// Output indices are stored in a u8
+const MAX_OUTPUTS: usize = u8::MAX as usize + 1;
+// Maximum nesting of All/Any in an output condition
+pub const MAX_CONDITION_DEPTH: usize = 4;
+
+// Reason a transaction is rejected by the UTXO state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxError {
+    NoInputs,
+    NoOutputs,
+    TooManyOutputs(usize),
+    // every input needs exactly one witness
+    WitnessCountMismatch { inputs: usize, witnesses: usize },
+    // index of the input whose witness has an invalid signature
+    BadSignature(usize),
+    // index of the output
+    ConditionTooDeep(usize),
+    DuplicateInput(UtxoInput),
+    // spent or never created
+    MissingInput(UtxoInput),
+    ImmatureCoinbase(UtxoInput),
+    // the key of the witness doesn't own the input
+    OwnerMismatch(UtxoInput),
+    // multisig condition of the input has too few valid signatures
+    MissingSignatures(UtxoInput),
+    // height or time lock of the input has not expired
+    Timelocked(UtxoInput),
+    // witness doesn't reveal the preimage of the hash lock of the input
+    BadPreimage(UtxoInput),
+    // index of the output
+    ZeroValueOutput(usize),
+    // input or output values don't fit in a u32
+    ValueOverflow,
+    // outputs are worth more than the inputs
+    Imbalance { input_value: u32, output_value: u32 },
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxError::NoInputs => write!(f, "tx has no inputs"),
+            TxError::NoOutputs => write!(f, "tx has no outputs"),
+            TxError::TooManyOutputs(n) => write!(f, "tx has {} outputs, at most {} are allowed", n, MAX_OUTPUTS),
+            TxError::WitnessCountMismatch { inputs, witnesses } => {
+                write!(f, "tx has {} inputs but {} witnesses", inputs, witnesses)
+            }
+            TxError::BadSignature(idx) => write!(f, "witness of input {} didn't pass signature check", idx),
+            TxError::ConditionTooDeep(idx) => {
+                write!(f, "condition of output {} nests deeper than {}", idx, MAX_CONDITION_DEPTH)
+            }
+            TxError::DuplicateInput(input) => write!(f, "input {:?} is spent twice by the tx", input),
+            TxError::MissingInput(input) => write!(f, "input {:?} is not in the state, double spend?", input),
+            TxError::ImmatureCoinbase(input) => write!(f, "input {:?} spends a coinbase before it matured", input),
+            TxError::OwnerMismatch(input) => write!(f, "witness key doesn't own input {:?}", input),
+            TxError::MissingSignatures(input) => write!(f, "not enough signatures to spend input {:?}", input),
+            TxError::Timelocked(input) => write!(f, "input {:?} is still time locked", input),
+            TxError::BadPreimage(input) => write!(f, "witness doesn't unlock hash lock of input {:?}", input),
+            TxError::ZeroValueOutput(idx) => write!(f, "output {} has no value", idx),
+            TxError::ValueOverflow => write!(f, "tx values overflow"),
+            TxError::Imbalance { input_value, output_value } => {
+                write!(f, "outputs are worth {} but inputs only {}", output_value, input_value)
+            }
+        }
+    }
+}
+
+impl TxError {
+    // Stable machine readable name of the error, used in API responses
+    pub fn code(&self) -> &'static str {
+        match self {
+            TxError::NoInputs => "no_inputs",
+            TxError::NoOutputs => "no_outputs",
+            TxError::TooManyOutputs(_) => "too_many_outputs",
+            TxError::WitnessCountMismatch { .. } => "witness_count_mismatch",
+            TxError::BadSignature(_) => "bad_signature",
+            TxError::ConditionTooDeep(_) => "condition_too_deep",
+            TxError::DuplicateInput(_) => "duplicate_input",
+            TxError::MissingInput(_) => "missing_input",
+            TxError::ImmatureCoinbase(_) => "immature_coinbase",
+            TxError::OwnerMismatch(_) => "owner_mismatch",
+            TxError::MissingSignatures(_) => "missing_signatures",
+            TxError::Timelocked(_) => "timelocked",
+            TxError::BadPreimage(_) => "bad_preimage",
+            TxError::ZeroValueOutput(_) => "zero_value_output",
+            TxError::ValueOverflow => "value_overflow",
+            TxError::Imbalance { .. } => "imbalance",
+        }
+    }
+
+    // Whether the transaction is invalid whatever the UTXO state, the sender could have known
+    pub fn is_malformed(&self) -> bool {
+        matches!(
+            self,
+            TxError::NoInputs
+                | TxError::NoOutputs
+                | TxError::TooManyOutputs(_)
+                | TxError::WitnessCountMismatch { .. }
+                | TxError::BadSignature(_)
+                | TxError::ConditionTooDeep(_)
+                | TxError::DuplicateInput(_)
+                | TxError::ZeroValueOutput(_)
+                | TxError::ValueOverflow
+        )
+    }
+}
+
+// Check a transaction against the UTXO state, returns the fee it pays
+pub fn check_tx(state: &UtxoState, signed_tx: &SignedTransaction) -> Result<u64, TxError> {
+    let tx = &signed_tx.tx;
+    if tx.tx_input.is_empty() {
+        return Err(TxError::NoInputs);
+    }
+    if tx.tx_output.is_empty() {
+        return Err(TxError::NoOutputs);
+    }
+    if tx.tx_output.len() > MAX_OUTPUTS {
+        return Err(TxError::TooManyOutputs(tx.tx_output.len()));
+    }
+
+    if signed_tx.witnesses.len() != tx.tx_input.len() {
+        return Err(TxError::WitnessCountMismatch { inputs: tx.tx_input.len(), witnesses: signed_tx.witnesses.len() });
+    }
+    for (idx, witness) in signed_tx.witnesses.iter().enumerate() {
+        let all_valid = witness.signatures.iter()
+            .all(|sig| transaction::verify(tx, &sig.signature, &sig.public_key));
+        if !all_valid {
+            return Err(TxError::BadSignature(idx));
+        }
+    }
+
+    let mut spent: HashSet<&UtxoInput> = HashSet::new();
+    let mut input_value: u32 = 0;
+    for (input, witness) in tx.tx_input.iter().zip(&signed_tx.witnesses) {
+        if !spent.insert(input) {
+            return Err(TxError::DuplicateInput(input.clone()));
+        }
+        let output = match state.state_map.get(input) {
+            Some(output) => output,
+            None => return Err(TxError::MissingInput(input.clone())),
+        };
+        if !state.is_mature(input) {
+            return Err(TxError::ImmatureCoinbase(input.clone()));
+        }
+        check_condition(&output.spending_condition(), witness, state, input)?;
+        input_value = input_value.checked_add(output.value).ok_or(TxError::ValueOverflow)?;
+    }
+
+    let mut output_value: u32 = 0;
+    for (idx, output) in tx.tx_output.iter().enumerate() {
+        if output.value == 0 {
+            return Err(TxError::ZeroValueOutput(idx));
+        }
+        if output.condition.as_ref().is_some_and(|c| c.depth() > MAX_CONDITION_DEPTH) {
+            return Err(TxError::ConditionTooDeep(idx));
+        }
+        output_value = output_value.checked_add(output.value).ok_or(TxError::ValueOverflow)?;
+    }
+
+    if input_value < output_value {
+        return Err(TxError::Imbalance { input_value, output_value });
+    }
+    Ok((input_value - output_value) as u64)
+}
+
+// Evaluate the spending condition of `input` against its witness, signatures of
+// the witness have already been verified
+fn check_condition(condition: &Condition, witness: &Witness, state: &UtxoState, input: &UtxoInput) -> Result<(), TxError> {
+    match condition {
+        Condition::Address(addr) => {
+            let signed = witness.signatures.iter()
+                .any(|sig| address::address_from_public_key_vec_ref(&sig.public_key) == *addr);
+            if !signed {
+                return Err(TxError::OwnerMismatch(input.clone()));
+            }
+        }
+        Condition::Multisig { threshold, public_keys } => {
+            let signers = public_keys.iter()
+                .filter(|key| witness.signatures.iter().any(|sig| sig.public_key == **key))
+                .count();
+            if signers < *threshold as usize {
+                return Err(TxError::MissingSignatures(input.clone()));
+            }
+        }
+        Condition::AfterLevel(level) => {
+            if state.confirmed_level < *level {
+                return Err(TxError::Timelocked(input.clone()));
+            }
+        }
+        Condition::AfterTime(timestamp) => {
+            if state.confirmed_timestamp < *timestamp {
+                return Err(TxError::Timelocked(input.clone()));
+            }
+        }
+        Condition::HashLock(hash) => {
+            let unlocked = witness.preimage.as_ref().is_some_and(|preimage| {
+                let digest: H256 = ring::digest::digest(&ring::digest::SHA256, preimage).into();
+                digest == *hash
+            });
+            if !unlocked {
+                return Err(TxError::BadPreimage(input.clone()));
+            }
+        }
+        Condition::All(conditions) => {
+            for condition in conditions {
+                check_condition(condition, witness, state, input)?;
+            }
+        }
+        Condition::Any(conditions) => {
+            let mut result = Err(TxError::OwnerMismatch(input.clone()));
+            for condition in conditions {
+                result = check_condition(condition, witness, state, input);
+                if result.is_ok() {
+                    break;
+                }
+            }
+            return result;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key_pair;
+    use crate::transaction::{Transaction, UtxoOutput};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn input(byte: u8) -> UtxoInput {
+        UtxoInput { tx_hash: H256::from([byte; 32]), idx: 0 }
+    }
+
+    fn signed(key: &Ed25519KeyPair, tx_input: Vec<UtxoInput>, values: &[u32]) -> SignedTransaction {
+        let keys = vec![key; tx_input.len()];
+        signed_by(key, &keys, tx_input, values)
+    }
+
+    // outputs pay the owner of `receiver`, input i is signed by keys[i]
+    fn signed_by(receiver: &Ed25519KeyPair, keys: &[&Ed25519KeyPair], tx_input: Vec<UtxoInput>, values: &[u32]) -> SignedTransaction {
+        let owner = address::address_from_public_key_vec_ref(&receiver.public_key().as_ref().to_vec());
+        let tx = Transaction {
+            tx_input,
+            tx_output: values.iter().map(|value| UtxoOutput::new(owner, *value)).collect(),
+        };
+        SignedTransaction::new(tx, keys)
+    }
+
+    #[test]
+    fn rejections_are_typed() {
+        let key = key_pair::random();
+        let owner = address::address_from_public_key_vec_ref(&key.public_key().as_ref().to_vec());
+        let mut state = UtxoState::default();
+        state.state_map.insert(input(1), UtxoOutput::new(owner, 10));
+        state.state_map.insert(input(2), UtxoOutput::new(owner, 5));
+        state.state_map.insert(input(3), UtxoOutput::new(owner, u32::MAX));
+        let other = key_pair::random();
+
+        assert_eq!(check_tx(&state, &signed(&key, vec![input(1), input(2)], &[12])), Ok(3));
+        assert_eq!(check_tx(&state, &signed(&key, vec![], &[1])), Err(TxError::NoInputs));
+        assert_eq!(check_tx(&state, &signed(&key, vec![input(1)], &[])), Err(TxError::NoOutputs));
+        assert_eq!(check_tx(&state, &signed(&key, vec![input(1), input(1)], &[20])), Err(TxError::DuplicateInput(input(1))));
+        assert_eq!(check_tx(&state, &signed(&key, vec![input(4)], &[1])), Err(TxError::MissingInput(input(4))));
+        assert_eq!(check_tx(&state, &signed(&other, vec![input(1)], &[1])), Err(TxError::OwnerMismatch(input(1))));
+        assert_eq!(check_tx(&state, &signed(&key, vec![input(1)], &[5, 0])), Err(TxError::ZeroValueOutput(1)));
+        assert_eq!(check_tx(&state, &signed(&key, vec![input(1), input(3)], &[1])), Err(TxError::ValueOverflow));
+        assert_eq!(
+            check_tx(&state, &signed(&key, vec![input(2)], &[4, 2])),
+            Err(TxError::Imbalance { input_value: 5, output_value: 6 })
+        );
+
+        let mut tampered = signed(&key, vec![input(1)], &[10]);
+        tampered.tx.tx_output[0].value = 9;
+        assert_eq!(check_tx(&state, &tampered), Err(TxError::BadSignature(0)));
+
+        let mut unwitnessed = signed(&key, vec![input(1), input(2)], &[15]);
+        unwitnessed.witnesses.pop();
+        assert_eq!(check_tx(&state, &unwitnessed), Err(TxError::WitnessCountMismatch { inputs: 2, witnesses: 1 }));
+    }
+
+    #[test]
+    fn inputs_of_different_owners() {
+        let alice = key_pair::random();
+        let bob = key_pair::random();
+        let mut state = UtxoState::default();
+        for (byte, key) in [(1, &alice), (2, &bob)].iter() {
+            let owner = address::address_from_public_key_vec_ref(&key.public_key().as_ref().to_vec());
+            state.state_map.insert(input(*byte), UtxoOutput::new(owner, 10));
+        }
+
+        assert_eq!(check_tx(&state, &signed_by(&alice, &[&alice, &bob], vec![input(1), input(2)], &[20])), Ok(0));
+        assert_eq!(
+            check_tx(&state, &signed_by(&alice, &[&bob, &alice], vec![input(1), input(2)], &[20])),
+            Err(TxError::OwnerMismatch(input(1)))
+        );
+    }
+
+    fn public_key(key: &Ed25519KeyPair) -> Vec<u8> {
+        key.public_key().as_ref().to_vec()
+    }
+
+    fn spend(witness: impl Fn(&Transaction) -> Witness, receiver: &Ed25519KeyPair) -> SignedTransaction {
+        let owner = address::address_from_public_key_vec_ref(&public_key(receiver));
+        let tx = Transaction { tx_input: vec![input(1)], tx_output: vec![UtxoOutput::new(owner, 10)] };
+        SignedTransaction { witnesses: vec![witness(&tx)], tx }
+    }
+
+    #[test]
+    fn escrow_condition() {
+        let (alice, bob, arbiter) = (key_pair::random(), key_pair::random(), key_pair::random());
+        let alice_addr = address::address_from_public_key_vec_ref(&public_key(&alice));
+        // 2 of 3 release the coin, alice gets it back after level 100
+        let escrow = Condition::Any(vec![
+            Condition::Multisig { threshold: 2, public_keys: vec![public_key(&alice), public_key(&bob), public_key(&arbiter)] },
+            Condition::All(vec![Condition::AfterLevel(100), Condition::Address(alice_addr)]),
+        ]);
+        let mut state = UtxoState::default();
+        state.state_map.insert(input(1), UtxoOutput::with_condition(alice_addr, 10, escrow));
+
+        assert_eq!(check_tx(&state, &spend(|tx| Witness::multisig(tx, &[&bob, &arbiter]), &bob)), Ok(0));
+        // a key signing twice counts once
+        assert_eq!(
+            check_tx(&state, &spend(|tx| Witness::multisig(tx, &[&bob, &bob]), &bob)),
+            Err(TxError::Timelocked(input(1)))
+        );
+        assert_eq!(check_tx(&state, &spend(|tx| Witness::new(tx, &alice), &alice)), Err(TxError::Timelocked(input(1))));
+        state.confirmed_level = 100;
+        assert_eq!(check_tx(&state, &spend(|tx| Witness::new(tx, &alice), &alice)), Ok(0));
+        assert_eq!(check_tx(&state, &spend(|tx| Witness::new(tx, &bob), &bob)), Err(TxError::OwnerMismatch(input(1))));
+    }
+
+    #[test]
+    fn hash_and_time_lock() {
+        let bob = key_pair::random();
+        let bob_addr = address::address_from_public_key_vec_ref(&public_key(&bob));
+        let secret = b"payment secret".to_vec();
+        let lock: H256 = ring::digest::digest(&ring::digest::SHA256, &secret).into();
+        let htlc = Condition::All(vec![Condition::HashLock(lock), Condition::AfterTime(1_000), Condition::Address(bob_addr)]);
+        let mut state = UtxoState::default();
+        state.state_map.insert(input(1), UtxoOutput::with_condition(bob_addr, 10, htlc));
+        state.confirmed_timestamp = 1_000;
+
+        assert_eq!(check_tx(&state, &spend(|tx| Witness::new(tx, &bob), &bob)), Err(TxError::BadPreimage(input(1))));
+        assert_eq!(
+            check_tx(&state, &spend(|tx| Witness::new(tx, &bob).with_preimage(b"guess".to_vec()), &bob)),
+            Err(TxError::BadPreimage(input(1)))
+        );
+        assert_eq!(check_tx(&state, &spend(|tx| Witness::new(tx, &bob).with_preimage(secret.clone()), &bob)), Ok(0));
+        state.confirmed_timestamp = 999;
+        assert_eq!(
+            check_tx(&state, &spend(|tx| Witness::new(tx, &bob).with_preimage(secret.clone()), &bob)),
+            Err(TxError::Timelocked(input(1)))
+        );
+    }
+
+    #[test]
+    fn conditions_nest_boundedly() {
+        let key = key_pair::random();
+        let owner = address::address_from_public_key_vec_ref(&public_key(&key));
+        let mut condition = Condition::Address(owner);
+        for _ in 0..MAX_CONDITION_DEPTH {
+            condition = Condition::All(vec![condition]);
+        }
+        let mut state = UtxoState::default();
+        state.state_map.insert(input(1), UtxoOutput::new(owner, 10));
+        let tx = Transaction {
+            tx_input: vec![input(1)],
+            tx_output: vec![UtxoOutput::with_condition(owner, 10, condition)],
+        };
+        assert_eq!(check_tx(&state, &SignedTransaction::new(tx, &[&key])), Err(TxError::ConditionTooDeep(0)));
+    }
+}
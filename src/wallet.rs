@@ -0,0 +1,347 @@
+use crate::crypto::address::{self, H160};
+use crate::crypto::key_pair;
+use crate::transaction::{SignedTransaction, Transaction, UtxoInput, UtxoOutput};
+use crate::utxo::UtxoState;
+
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{Serialize, Deserialize};
+
+use log::info;
+use std::cmp::Reverse;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+
+// PKCS#8 documents of the keys funded by the ICO (see utxo::perform_ico)
+const ICO_KEYS: [&[u8]; 6] = [
+    &[48, 83, 2, 1, 1, 48, 5, 6, 3, 43, 101, 112, 4, 34, 4, 32, 187, 131, 74, 161, 134, 11, 240, 6, 188, 109, 18, 108, 124, 219, 167, 164, 215, 125, 168, 79, 204, 194, 232, 91, 58, 186, 181, 230, 212, 78, 163, 28, 161, 35, 3, 33, 0, 233, 72, 146, 218, 220, 235, 17, 123, 202, 112, 119, 63, 134, 105, 134, 71, 34, 185, 71, 193, 59, 66, 43, 137, 50, 194, 120, 234, 97, 132, 235, 159],
+    &[48, 83, 2, 1, 1, 48, 5, 6, 3, 43, 101, 112, 4, 34, 4, 32, 154, 186, 73, 239, 105, 129, 142, 211, 156, 79, 213, 209, 229, 87, 22, 92, 113, 203, 244, 222, 244, 33, 199, 254, 130, 102, 178, 65, 198, 67, 20, 132, 161, 35, 3, 33, 0, 161, 153, 171, 27, 96, 146, 25, 237, 5, 189, 186, 116, 0, 24, 2, 8, 28, 143, 5, 119, 20, 47, 142, 186, 55, 234, 189, 167, 154, 15, 210, 97],
+    &[48, 83, 2, 1, 1, 48, 5, 6, 3, 43, 101, 112, 4, 34, 4, 32, 147, 195, 231, 118, 135, 29, 32, 40, 23, 117, 107, 218, 6, 220, 198, 50, 81, 113, 167, 122, 175, 161, 118, 93, 191, 137, 50, 125, 203, 69, 70, 42, 161, 35, 3, 33, 0, 125, 80, 160, 138, 247, 46, 227, 162, 118, 51, 64, 42, 174, 60, 87, 134, 77, 60, 225, 11, 189, 222, 22, 185, 65, 10, 67, 78, 250, 41, 188, 60],
+    &[48, 83, 2, 1, 1, 48, 5, 6, 3, 43, 101, 112, 4, 34, 4, 32, 11, 212, 170, 1, 126, 8, 32, 58, 40, 116, 165, 98, 48, 127, 67, 109, 86, 251, 249, 203, 244, 203, 1, 223, 248, 164, 176, 195, 23, 17, 146, 8, 161, 35, 3, 33, 0, 206, 15, 234, 106, 58, 45, 177, 81, 0, 193, 13, 113, 249, 55, 152, 151, 227, 224, 35, 185, 148, 49, 186, 234, 17, 106, 132, 216, 83, 196, 127, 99],
+    &[48, 83, 2, 1, 1, 48, 5, 6, 3, 43, 101, 112, 4, 34, 4, 32, 40, 29, 27, 179, 25, 183, 68, 113, 252, 19, 20, 114, 160, 221, 228, 195, 253, 87, 245, 176, 226, 99, 249, 28, 87, 61, 101, 129, 207, 87, 90, 195, 161, 35, 3, 33, 0, 254, 57, 159, 24, 159, 141, 184, 159, 58, 86, 112, 217, 153, 215, 65, 7, 88, 14, 57, 80, 42, 33, 151, 211, 208, 52, 42, 208, 111, 174, 223, 27],
+    &[48, 83, 2, 1, 1, 48, 5, 6, 3, 43, 101, 112, 4, 34, 4, 32, 224, 231, 169, 219, 160, 221, 218, 51, 189, 197, 202, 218, 24, 20, 166, 105, 31, 55, 241, 231, 5, 165, 51, 106, 174, 11, 110, 84, 17, 115, 230, 56, 161, 35, 3, 33, 0, 127, 130, 60, 237, 224, 179, 64, 241, 25, 174, 45, 64, 52, 179, 70, 249, 26, 49, 128, 103, 188, 201, 48, 55, 221, 154, 12, 83, 40, 123, 3, 157],
+];
+
+// Number of ICO keys owned by every test node, node i owns keys 2i and 2i+1
+pub const ICO_KEYS_PER_NODE: usize = 2;
+// Number of test nodes the ICO keys are split between
+pub const ICO_NODES: usize = ICO_KEYS.len() / ICO_KEYS_PER_NODE;
+
+#[derive(Debug)]
+pub enum WalletError {
+    Io(io::Error),
+    // the keystore can't be decrypted with the passphrase or was tampered with
+    BadPassphrase,
+    Corrupted(String),
+    InvalidKey,
+    InsufficientFunds { available: u64, required: u64 },
+    UnknownAddress(H160),
+    // the change doesn't fit in the value of one output
+    ChangeOverflow(u64),
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WalletError::Io(e) => write!(f, "keystore io error: {}", e),
+            WalletError::BadPassphrase => write!(f, "wrong passphrase or corrupted keystore"),
+            WalletError::Corrupted(e) => write!(f, "corrupted keystore: {}", e),
+            WalletError::InvalidKey => write!(f, "invalid PKCS#8 key"),
+            WalletError::InsufficientFunds { available, required } => {
+                write!(f, "insufficient funds: {} available, {} required", available, required)
+            }
+            WalletError::UnknownAddress(addr) => write!(f, "no key for address {:?}", addr),
+            WalletError::ChangeOverflow(change) => write!(f, "change of {} does not fit in an output", change),
+        }
+    }
+}
+
+impl From<io::Error> for WalletError {
+    fn from(e: io::Error) -> Self {
+        WalletError::Io(e)
+    }
+}
+
+// On-disk format of the keystore, the ciphertext is the bincode encoded list of
+// PKCS#8 documents sealed with ChaCha20-Poly1305 under a PBKDF2 derived key
+#[derive(Serialize, Deserialize)]
+struct KeystoreFile {
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> LessSafeKey {
+    let mut key = [0u8; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    LessSafeKey::new(UnboundKey::new(&aead::CHACHA20_POLY1305, &key).unwrap())
+}
+
+// Ed25519 keys of a node, optionally backed by an encrypted keystore file
+pub struct Wallet {
+    // PKCS#8 documents in the order the keys were added
+    pkcs8: Vec<Vec<u8>>,
+    keys: Vec<Ed25519KeyPair>,
+    addresses: Vec<H160>,
+    // keystore file and its passphrase, every new key is saved to it
+    keystore: Option<(PathBuf, String)>,
+}
+
+impl Default for Wallet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Wallet {
+    // Empty wallet kept in memory only
+    pub fn new() -> Self {
+        Wallet {
+            pkcs8: Vec::new(),
+            keys: Vec::new(),
+            addresses: Vec::new(),
+            keystore: None,
+        }
+    }
+
+    // All keys funded by the ICO
+    pub fn ico() -> Self {
+        let mut wallet = Self::new();
+        for pkcs8 in ICO_KEYS.iter() {
+            wallet.add_pkcs8(pkcs8.to_vec()).unwrap();
+        }
+        wallet
+    }
+
+    // ICO keys owned by test node `index`
+    pub fn ico_node(index: usize) -> Self {
+        let mut wallet = Self::new();
+        for pkcs8 in ICO_KEYS.iter().skip(index * ICO_KEYS_PER_NODE).take(ICO_KEYS_PER_NODE) {
+            wallet.add_pkcs8(pkcs8.to_vec()).unwrap();
+        }
+        wallet
+    }
+
+    // Open the keystore at `path`, an empty one is created if it doesn't exist
+    pub fn open<P: AsRef<Path>>(path: P, passphrase: &str) -> Result<Self, WalletError> {
+        let path = path.as_ref().to_path_buf();
+        let mut wallet = Self::new();
+        match fs::read(&path) {
+            Ok(raw) => {
+                let file: KeystoreFile = bincode::deserialize(&raw).map_err(|e| WalletError::Corrupted(e.to_string()))?;
+                let nonce = Nonce::try_assume_unique_for_key(&file.nonce).map_err(|_| WalletError::Corrupted("bad nonce".to_string()))?;
+                let mut in_out = file.ciphertext;
+                let plaintext = derive_key(passphrase, &file.salt)
+                    .open_in_place(nonce, Aad::empty(), &mut in_out)
+                    .map_err(|_| WalletError::BadPassphrase)?;
+                let documents: Vec<Vec<u8>> = bincode::deserialize(plaintext).map_err(|e| WalletError::Corrupted(e.to_string()))?;
+                for pkcs8 in documents {
+                    wallet.add_pkcs8(pkcs8)?;
+                }
+                wallet.keystore = Some((path, passphrase.to_string()));
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                wallet.keystore = Some((path, passphrase.to_string()));
+                wallet.save()?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        info!("Opened wallet with {} keys", wallet.keys.len());
+        Ok(wallet)
+    }
+
+    // Write all keys to the keystore, replacing it atomically
+    pub fn save(&self) -> Result<(), WalletError> {
+        let (path, passphrase) = match &self.keystore {
+            Some(keystore) => keystore,
+            None => return Ok(()),
+        };
+        let rng = SystemRandom::new();
+        let mut salt = vec![0u8; SALT_LEN];
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        rng.fill(&mut salt).unwrap();
+        rng.fill(&mut nonce).unwrap();
+
+        let mut in_out = bincode::serialize(&self.pkcs8).unwrap();
+        derive_key(passphrase, &salt)
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut in_out)
+            .unwrap();
+        let file = KeystoreFile { salt, nonce: nonce.to_vec(), ciphertext: in_out };
+
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&bincode::serialize(&file).unwrap())?;
+        tmp.sync_data()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    fn add_pkcs8(&mut self, pkcs8: Vec<u8>) -> Result<H160, WalletError> {
+        let key = Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|_| WalletError::InvalidKey)?;
+        let addr = address::address_from_public_key_vec_ref(&key.public_key().as_ref().to_vec());
+        if !self.owns(&addr) {
+            self.pkcs8.push(pkcs8);
+            self.keys.push(key);
+            self.addresses.push(addr);
+        }
+        Ok(addr)
+    }
+
+    // Generate a new key and persist it, returns its address
+    pub fn generate_key(&mut self) -> Result<H160, WalletError> {
+        let addr = self.add_pkcs8(key_pair::random_pkcs8())?;
+        self.save()?;
+        Ok(addr)
+    }
+
+    // Import a PKCS#8 encoded key and persist it
+    pub fn import_key(&mut self, pkcs8: &[u8]) -> Result<H160, WalletError> {
+        let addr = self.add_pkcs8(pkcs8.to_vec())?;
+        self.save()?;
+        Ok(addr)
+    }
+
+    pub fn addresses(&self) -> &[H160] {
+        &self.addresses
+    }
+
+    pub fn owns(&self, addr: &H160) -> bool {
+        self.addresses.contains(addr)
+    }
+
+    pub fn key(&self, addr: &H160) -> Option<&Ed25519KeyPair> {
+        let idx = self.addresses.iter().position(|a| a == addr)?;
+        Some(&self.keys[idx])
+    }
+
+    // Plain, mature outputs paying one of our addresses, largest first
+    pub fn spendable_coins(&self, state: &UtxoState) -> Vec<(UtxoInput, UtxoOutput)> {
+        let mut coins: Vec<(UtxoInput, UtxoOutput)> = state.state_map.iter()
+            .filter(|(input, output)| {
+                output.condition.is_none() && self.owns(&output.receipient_addr) && state.is_mature(input)
+            })
+            .map(|(input, output)| (input.clone(), output.clone()))
+            .collect();
+        coins.sort_by(|(a_input, a), (b_input, b)| {
+            b.value.cmp(&a.value)
+                .then(a_input.tx_hash.cmp(&b_input.tx_hash))
+                .then(a_input.idx.cmp(&b_input.idx))
+        });
+        coins
+    }
+
+    pub fn balance(&self, state: &UtxoState) -> u64 {
+        self.spendable_coins(state).iter().map(|(_, output)| output.value as u64).sum()
+    }
+
+    // Spend `coins`, taken largest first until they cover the outputs and the fee.
+    // The rest goes back to our first address as a change output.
+    pub fn create_transaction(
+        &self,
+        coins: &[(UtxoInput, UtxoOutput)],
+        outputs: Vec<UtxoOutput>,
+        fee: u64,
+    ) -> Result<SignedTransaction, WalletError> {
+        let required = outputs.iter().map(|output| output.value as u64).sum::<u64>() + fee;
+        let mut selected: Vec<&(UtxoInput, UtxoOutput)> = coins.iter().collect();
+        selected.sort_by_key(|(_, output)| Reverse(output.value));
+
+        let mut total: u64 = 0;
+        let mut count = 0;
+        while total < required && count < selected.len() {
+            total += selected[count].1.value as u64;
+            count += 1;
+        }
+        if total < required {
+            return Err(WalletError::InsufficientFunds { available: total, required });
+        }
+        selected.truncate(count);
+
+        let mut keys: Vec<&Ed25519KeyPair> = Vec::new();
+        for (_, output) in &selected {
+            let key = self.key(&output.receipient_addr).ok_or(WalletError::UnknownAddress(output.receipient_addr))?;
+            keys.push(key);
+        }
+
+        let mut tx_output = outputs;
+        let change = total - required;
+        if change > 0 {
+            let change = u32::try_from(change).map_err(|_| WalletError::ChangeOverflow(change))?;
+            tx_output.push(UtxoOutput::new(self.addresses[0], change));
+        }
+        let tx = Transaction {
+            tx_input: selected.iter().map(|(input, _)| input.clone()).collect(),
+            tx_output,
+        };
+        Ok(SignedTransaction::new(tx, &keys))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::H256;
+    use crate::validation::transaction::check_tx;
+
+    fn keystore_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("prism-wallet-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn keystore_roundtrip() {
+        let path = keystore_path("roundtrip");
+        let addr = {
+            let mut wallet = Wallet::open(&path, "secret").unwrap();
+            wallet.generate_key().unwrap()
+        };
+        let wallet = Wallet::open(&path, "secret").unwrap();
+        assert_eq!(wallet.addresses(), &[addr][..]);
+        assert!(matches!(Wallet::open(&path, "wrong"), Err(WalletError::BadPassphrase)));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn coin_selection_with_change() {
+        let mut wallet = Wallet::new();
+        let first = wallet.generate_key().unwrap();
+        let second = wallet.generate_key().unwrap();
+        let mut state = UtxoState::default();
+        for (byte, addr, value) in [(1u8, first, 10), (2, second, 30), (3, first, 5)].iter() {
+            state.state_map.insert(UtxoInput { tx_hash: H256::from([*byte; 32]), idx: 0 }, UtxoOutput::new(*addr, *value));
+        }
+        let coins = wallet.spendable_coins(&state);
+        assert_eq!(wallet.balance(&state), 45);
+
+        let receiver = address::generate_random_address();
+        let tx = wallet.create_transaction(&coins, vec![UtxoOutput::new(receiver, 33)], 2).unwrap();
+        // the 30 and 10 coins are needed, 5 goes back as change
+        assert_eq!(tx.tx.tx_input.len(), 2);
+        assert_eq!(tx.tx.tx_output.len(), 2);
+        assert_eq!(tx.tx.tx_output[1].value, 5);
+        assert_eq!(tx.tx.tx_output[1].receipient_addr, first);
+        assert_eq!(check_tx(&state, &tx), Ok(2));
+
+        assert!(matches!(
+            wallet.create_transaction(&coins, vec![UtxoOutput::new(receiver, 45)], 1),
+            Err(WalletError::InsufficientFunds { available: 45, required: 46 })
+        ));
+    }
+}